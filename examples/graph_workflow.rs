@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use rigs::agent::Agent;
-use rigs::graph_workflow::{DAGWorkflow, Flow};
+use rigs::graph_workflow::{Condition, DAGWorkflow, Flow, Transform};
 use rigs::llm_provider::LLMProvider;
 use rigs::rig_agent::RigAgent;
 
@@ -245,14 +245,16 @@ async fn main() -> Result<()> {
 
     // Add a conditional flow with transformation
     let conditional_flow = Flow {
-        // Add a custom transformation function, this will change the output of the previous agent
-        // to a new format that will be used as the input of the next agent.
-        transform: Some(Arc::new(|output| format!("Summary request: {output}"))),
+        // Add a transformation, this will change the output of the previous agent to a new format
+        // that will be used as the input of the next agent.
+        transform: Some(Transform::template("Summary request: {output}")),
         // Add a condition, this will only trigger the next agent if the output of the previous agent
         // is longer than 100 characters. If the condition is not met, the workflow will continue
         // to the next agent in the graph. This is useful to avoid expensive computations if the
-        // input is too short.
-        condition: Some(Arc::new(|output| output.len() > 100)),
+        // input is too short. The length is counted in characters, not in bytes like `str::len`,
+        // so a non-ASCII output needs more bytes to pass.
+        condition: Some(Condition::min_length(101)),
+        ..Default::default()
     };
     let _edge_idx2 = workflow
         .connect_agents(
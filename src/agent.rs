@@ -1,18 +1,42 @@
 use std::collections::HashSet;
 use std::fmt::Debug;
+use std::num::NonZeroUsize;
 
-use futures::future::BoxFuture;
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+};
 use rig::{completion::PromptError, vector_store::VectorStoreError};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::persistence::PersistenceError;
+use crate::{persistence::PersistenceError, retry::RetryPolicy};
 
 /// An autonomous agent that can complete tasks.
-pub trait Agent {
+pub trait Agent: Send + Sync {
     /// Runs the autonomous agent loop to complete the given task.
     fn run(&self, task: String) -> BoxFuture<'_, Result<String, AgentError>>;
 
+    /// Runs the autonomous agent loop and yields the response incrementally as it is generated.
+    ///
+    /// Concatenating all chunks gives the same output as [`Agent::run`]. The default implementation
+    /// yields the whole response of [`Agent::run`] as a single chunk.
+    fn run_stream(&self, task: String) -> BoxStream<'_, Result<String, AgentError>> {
+        Box::pin(stream::once(self.run(task)))
+    }
+
+    /// Runs the autonomous agent loop like [`Agent::run`], but fails when the agent gives up.
+    ///
+    /// [`Agent::run`] may succeed with the output produced so far when the agent gives up, e.g.
+    /// once the retries of a loop are exhausted, this returns the error instead. The default
+    /// implementation is [`Agent::run`], for agents which always report their failures.
+    ///
+    /// Workflows and the [`FallbackAgent`](crate::fallback_agent::FallbackAgent) run agents with
+    /// it, so that a failed agent is handled as a failure.
+    fn run_checked(&self, task: String) -> BoxFuture<'_, Result<String, AgentError>> {
+        self.run(task)
+    }
+
     /// Run multiple tasks concurrently
     fn run_multiple_tasks(
         &mut self,
@@ -59,12 +83,33 @@ pub enum AgentError {
         #[source]
         source: PersistenceError,
     },
+    /// Conversation error, e.g. a saved conversation couldn't be read.
+    #[error("Conversation error: {0}")]
+    ConversationError(#[from] crate::conversation::ConversationError),
+    /// Memory error, e.g. the memory store couldn't be written.
+    #[error("Memory error: {0}")]
+    MemoryError(#[from] crate::memory::MemoryError),
     /// Build agent error.
     #[error("Failed to build agent: {0}")]
     BuildError(String),
     /// LLM provider error.
     #[error("LLM provider error: {0}")]
     LLMProviderError(#[from] crate::llm_provider::LLMProviderError),
+    /// Workflow error, e.g. a nested workflow failed.
+    #[error("Workflow error: {0}")]
+    WorkflowError(String),
+    /// The agent has no state directory to save to or load from.
+    #[error("No state directory, maybe you forgot to call `save_state_dir(..)`?")]
+    MissingStateDir,
+    /// The summarizer of the memory gave an empty summary.
+    #[error("The summarizer gave an empty summary")]
+    EmptySummary,
+    /// The agent is configured with no attempts, see `retry_attempts`.
+    #[error("No attempt was made, `retry_attempts` is 0")]
+    NoAttempts,
+    /// No state was saved for the task.
+    #[error("No saved state of task: {0}")]
+    MissingTaskState(String),
     /// Agent builder not initialized.
     #[error("Agent builder not initialized, maybe you forgot to call `provider(..)`?")]
     AgentBuilderNotInitialized,
@@ -95,6 +140,11 @@ impl AgentConfigBuilder {
         self
     }
 
+    pub fn system_prompt(mut self, system_prompt: impl Into<String>) -> Self {
+        self.config.system_prompt = system_prompt.into();
+        self
+    }
+
     pub fn temperature(mut self, temperature: f64) -> Self {
         self.config.temperature = temperature;
         self
@@ -110,6 +160,11 @@ impl AgentConfigBuilder {
         self
     }
 
+    pub fn context_window(mut self, context_window: usize) -> Self {
+        self.config.context_window = Some(context_window);
+        self
+    }
+
     pub fn enable_plan(mut self, planning_prompt: impl Into<Option<String>>) -> Self {
         self.config.plan_enabled = true;
         self.config.planning_prompt = planning_prompt.into();
@@ -126,11 +181,26 @@ impl AgentConfigBuilder {
         self
     }
 
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.config.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn enable_stream(mut self) -> Self {
+        self.config.stream = true;
+        self
+    }
+
     pub fn enable_rag_every_loop(mut self) -> Self {
         self.config.rag_every_loop = true;
         self
     }
 
+    pub fn max_concurrent_tasks(mut self, max_concurrent_tasks: NonZeroUsize) -> Self {
+        self.config.max_concurrent_tasks = Some(max_concurrent_tasks);
+        self
+    }
+
     pub fn save_sate_path(mut self, path: impl Into<String>) -> Self {
         self.config.save_state_dir = Some(path.into());
         self
@@ -152,6 +222,9 @@ impl AgentConfigBuilder {
     }
 }
 
+/// The number of tasks [`Agent::run_multiple_tasks`] runs at once by default
+pub const DEFAULT_MAX_CONCURRENT_TASKS: usize = 4;
+
 /// Agent configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AgentConfig {
@@ -164,13 +237,25 @@ pub struct AgentConfig {
     pub temperature: f64,
     pub max_loops: u32,
     pub max_tokens: u64,
+    /// The context window of the model in tokens, the chat history is truncated to fit in it,
+    /// see [`ContextWindow`](crate::context_window::ContextWindow)
+    #[serde(default)]
+    pub context_window: Option<usize>,
     pub plan_enabled: bool,
     pub planning_prompt: Option<String>,
     pub autosave: bool,
     pub retry_attempts: u32,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
     pub rag_every_loop: bool,
     pub save_state_dir: Option<String>,
     pub stop_words: HashSet<String>,
+    #[serde(default)]
+    pub stream: bool,
+    /// The number of tasks [`Agent::run_multiple_tasks`] runs at once,
+    /// [`DEFAULT_MAX_CONCURRENT_TASKS`] when not set
+    #[serde(default)]
+    pub max_concurrent_tasks: Option<NonZeroUsize>,
 }
 
 impl AgentConfig {
@@ -193,13 +278,57 @@ impl Default for AgentConfig {
             temperature: 0.7,
             max_loops: 1,
             max_tokens: 8192,
+            context_window: None,
             plan_enabled: false,
             planning_prompt: None,
             autosave: false,
             retry_attempts: 3,
+            retry_policy: RetryPolicy::default(),
             rag_every_loop: false,
             save_state_dir: None,
             stop_words: HashSet::new(),
+            stream: false,
+            max_concurrent_tasks: None,
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use futures::future;
+    use mockall::mock;
+    use rig::completion::CompletionError;
+
+    use super::*;
+
+    mock! {
+        #[derive(Debug)]
+        pub(crate) Agent {}
+
+        impl Agent for Agent {
+            fn run(&self, task: String) -> BoxFuture<'static, Result<String, AgentError>>;
+            fn run_multiple_tasks(&mut self, tasks: Vec<String>) -> BoxFuture<'static, Result<Vec<String>, AgentError>>;
+            fn id(&self) -> String;
+            fn name(&self) -> String;
+            fn description(&self) -> String;
+        }
+    }
+
+    /// An error of the LLM provider, as a [`RigAgent`](crate::rig_agent::RigAgent) returns it
+    pub(crate) fn provider_error(message: &str) -> AgentError {
+        PromptError::CompletionError(CompletionError::ProviderError(message.to_owned())).into()
+    }
+
+    /// An agent which is run `times` times, answering each task with `answer`
+    pub(crate) fn mock_agent(
+        times: usize,
+        answer: impl Fn(String) -> Result<String, AgentError> + Send + Sync + 'static,
+    ) -> MockAgent {
+        let mut agent = MockAgent::new();
+        agent
+            .expect_run()
+            .times(times)
+            .returning(move |task| Box::pin(future::ready(answer(task))));
+        agent
+    }
+}
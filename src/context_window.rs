@@ -0,0 +1,238 @@
+//! Fit the chat history into the context window of a model
+//!
+#![deny(missing_docs)]
+
+use std::{fmt::Debug, sync::Arc};
+
+use crate::conversation::{Message, Role};
+
+/// Counts the tokens of a text, implement it with the tokenizer of a model for exact counts
+///
+/// Closures are counters too, e.g. with [tiktoken-rs](https://docs.rs/tiktoken-rs):
+///
+/// ```ignore
+/// let bpe = tiktoken_rs::cl100k_base()?;
+/// let counter = move |text: &str| bpe.encode_with_special_tokens(text).len();
+/// ```
+pub trait TokenCounter: Send + Sync {
+    /// The number of tokens of the text
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+impl<F> TokenCounter for F
+where
+    F: Fn(&str) -> usize + Send + Sync,
+{
+    fn count_tokens(&self, text: &str) -> usize {
+        self(text)
+    }
+}
+
+/// Estimates tokens from characters, about four characters per token
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CharacterCounter;
+
+impl TokenCounter for CharacterCounter {
+    fn count_tokens(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+}
+
+/// The token budget of the chat history sent to a model
+///
+/// When the history doesn't fit, the oldest messages are dropped first. The original task, i.e.
+/// the first message when it comes from the user, and the [`Role::System`] messages are always
+/// kept.
+#[derive(Clone)]
+pub struct ContextWindow {
+    tokens: usize,
+    counter: Arc<dyn TokenCounter>,
+}
+
+impl ContextWindow {
+    /// A context window of `tokens` tokens, counted with the [`CharacterCounter`]
+    pub fn new(tokens: usize) -> Self {
+        Self {
+            tokens,
+            counter: Arc::new(CharacterCounter),
+        }
+    }
+
+    /// Count tokens with another counter, e.g. a tokenizer
+    pub fn with_counter(mut self, counter: Arc<dyn TokenCounter>) -> Self {
+        self.counter = counter;
+        self
+    }
+
+    /// The size of the context window, in tokens
+    pub fn tokens(&self) -> usize {
+        self.tokens
+    }
+
+    /// Count the tokens of a text with the counter of the window
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.counter.count_tokens(text)
+    }
+
+    /// Count the tokens of a message, as it is displayed
+    pub fn count_message_tokens(&self, message: &Message) -> usize {
+        self.count_tokens(&format!("{}: {}", message.role, message.content))
+    }
+
+    /// Select the messages of the history which fit in the window
+    ///
+    /// `reserved` tokens are kept free for the rest of the request, e.g. the system prompt, the
+    /// prompt and the response. The selected messages keep their order.
+    pub fn fit<'a>(&self, history: &'a [Message], reserved: usize) -> FittedHistory<'a> {
+        let budget = self.tokens.saturating_sub(reserved);
+        let is_pinned = |index: usize, message: &Message| {
+            matches!(message.role, Role::System)
+                || (index == 0 && matches!(message.role, Role::User(_)))
+        };
+
+        let tokens = history
+            .iter()
+            .map(|message| self.count_message_tokens(message))
+            .collect::<Vec<_>>();
+        let mut used = history
+            .iter()
+            .enumerate()
+            .filter(|(index, message)| is_pinned(*index, message))
+            .map(|(index, _)| tokens[index])
+            .sum::<usize>();
+
+        // Keep the most recent messages which fit, without gaps
+        let mut oldest_kept = history.len();
+        for (index, message) in history.iter().enumerate().rev() {
+            if is_pinned(index, message) {
+                continue;
+            }
+            if used + tokens[index] > budget {
+                break;
+            }
+            used += tokens[index];
+            oldest_kept = index;
+        }
+        // Skip the results of tool calls which were dropped
+        while oldest_kept < history.len()
+            && history[oldest_kept].is_tool_result()
+            && !is_pinned(oldest_kept, &history[oldest_kept])
+        {
+            used -= tokens[oldest_kept];
+            oldest_kept += 1;
+        }
+
+        let messages = history
+            .iter()
+            .enumerate()
+            .filter(|(index, message)| *index >= oldest_kept || is_pinned(*index, message))
+            .map(|(_, message)| message)
+            .collect::<Vec<_>>();
+        FittedHistory {
+            dropped: history.len() - messages.len(),
+            messages,
+            tokens: used,
+        }
+    }
+}
+
+impl Debug for ContextWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContextWindow")
+            .field("tokens", &self.tokens)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The messages of a history which fit in a [`ContextWindow`]
+pub struct FittedHistory<'a> {
+    /// The kept messages, in order
+    pub messages: Vec<&'a Message>,
+    /// The number of dropped messages
+    pub dropped: usize,
+    /// The tokens of the kept messages
+    pub tokens: usize,
+}
+
+impl FittedHistory<'_> {
+    /// Whether messages were dropped
+    pub fn is_truncated(&self) -> bool {
+        self.dropped > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::Content;
+
+    fn message(role: Role, text: &str) -> Message {
+        Message::new(role, Content::Text(text.to_owned()))
+    }
+
+    fn history() -> Vec<Message> {
+        let user = || Role::User("u".to_owned());
+        let assistant = || Role::Assistant("a".to_owned());
+        vec![
+            message(user(), "task"),
+            message(Role::System, "plan"),
+            message(assistant(), "first answer"),
+            message(
+                Role::Tool {
+                    name: "search".to_owned(),
+                    call_id: "call_1".to_owned(),
+                },
+                "result",
+            ),
+            message(assistant(), "second answer"),
+            message(user(), "follow-up"),
+        ]
+    }
+
+    fn texts(fitted: &FittedHistory<'_>) -> Vec<String> {
+        fitted
+            .messages
+            .iter()
+            .map(|message| message.content.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_fit() {
+        // Count one token per character, to make the budgets readable
+        let window =
+            ContextWindow::new(1000).with_counter(Arc::new(|text: &str| text.chars().count()));
+        let history = history();
+
+        let fitted = window.fit(&history, 0);
+        assert!(!fitted.is_truncated());
+        assert_eq!(fitted.messages.len(), history.len());
+
+        // "u(User): task" and "System: plan" are pinned, 13 + 12 tokens
+        let fitted = window.fit(&history, 1000 - 25 - 25);
+        assert_eq!(texts(&fitted), ["task", "plan", "follow-up"]);
+        assert_eq!(fitted.dropped, 3);
+        assert_eq!(fitted.tokens, 25 + 18);
+
+        // The tool result is dropped with the answer which called the tool
+        let fitted = window.fit(&history, 1000 - 25 - 18 - 27 - 20);
+        assert_eq!(
+            texts(&fitted),
+            ["task", "plan", "second answer", "follow-up"]
+        );
+        assert_eq!(fitted.tokens, 25 + 18 + 27);
+
+        // The pinned messages are kept even if they don't fit
+        let fitted = window.fit(&history, 1000);
+        assert_eq!(texts(&fitted), ["task", "plan"]);
+        assert_eq!(fitted.dropped, 4);
+    }
+
+    #[test]
+    fn test_character_counter() {
+        let window = ContextWindow::new(10);
+        assert_eq!(window.count_tokens("12345678"), 2);
+        assert_eq!(window.count_tokens("123456789"), 3);
+        assert_eq!(window.count_tokens(""), 0);
+    }
+}
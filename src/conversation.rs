@@ -1,24 +1,50 @@
 use std::{
     collections::HashMap,
     fmt::Display,
+    ops::RangeBounds,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use chrono::Local;
-use dashmap::DashMap;
-use serde::{Deserialize, Serialize};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::{DateTime, Local};
+use serde::{
+    Deserialize, Serialize, Serializer,
+    ser::{Error as _, SerializeMap},
+};
 use thiserror::Error;
 
-use crate::persistence::{self, PersistenceError};
+use crate::{
+    memory::{InMemoryStore, MemoryError, MemoryStore},
+    persistence::{self, PersistenceError},
+};
+
+mod format;
+
+pub use format::{CONVERSATION_VERSION, ConversationFormat};
 
 /// A [AgentShortMemory] is a struct that stores multiple conversations.
 /// It is a map from `Task` to [Conversation]. `Task` is a string, usually the first message from the user.
-#[derive(Clone, Serialize)]
-pub struct AgentShortMemory(pub DashMap<String, Conversation>);
+///
+/// The conversations are kept by a [MemoryStore], in memory by default. Clones share the store.
+#[derive(Clone)]
+pub struct AgentShortMemory {
+    store: Arc<dyn MemoryStore>,
+}
 
 impl AgentShortMemory {
     pub fn new() -> Self {
-        Self(DashMap::new())
+        Self::with_store(Arc::new(InMemoryStore::new()))
+    }
+
+    /// Keep the conversations in a [MemoryStore], e.g. a file or a database.
+    pub fn with_store(store: Arc<dyn MemoryStore>) -> Self {
+        Self { store }
+    }
+
+    pub fn store(&self) -> &Arc<dyn MemoryStore> {
+        &self.store
     }
 
     /// Add a [Conversation] to the agent short memory.
@@ -30,18 +56,112 @@ impl AgentShortMemory {
     /// * `conversation_owner` - The owner of the conversation.
     /// * `role` - The role of the message, which will be added to the conversation.
     /// * `message` - The message to add.
-    pub fn add(
+    pub async fn add(
         &self,
         task: impl Into<String>,
         conversation_owner: impl Into<String>,
         role: Role,
         message: impl Into<String>,
-    ) {
-        let mut conversation = self
-            .0
-            .entry(task.into())
-            .or_insert(Conversation::new(conversation_owner.into()));
-        conversation.add(role, message.into())
+    ) -> Result<(), MemoryError> {
+        self.add_content(
+            task,
+            conversation_owner,
+            role,
+            Content::Text(message.into()),
+        )
+        .await
+    }
+
+    /// Add a message with any [Content] to the agent short memory, like [AgentShortMemory::add].
+    pub async fn add_content(
+        &self,
+        task: impl Into<String>,
+        conversation_owner: impl Into<String>,
+        role: Role,
+        content: Content,
+    ) -> Result<(), MemoryError> {
+        self.add_message(task, conversation_owner, Message::new(role, content))
+            .await
+    }
+
+    /// Add a [Message], e.g. with [MessageMetadata], to the agent short memory, like [AgentShortMemory::add].
+    pub async fn add_message(
+        &self,
+        task: impl Into<String>,
+        conversation_owner: impl Into<String>,
+        message: Message,
+    ) -> Result<(), MemoryError> {
+        let task = task.into();
+        let conversation_owner = conversation_owner.into();
+        self.run(move |store| store.append(&task, &conversation_owner, message))
+            .await
+    }
+
+    /// The conversation of a task.
+    pub async fn get(&self, task: &str) -> Result<Option<Conversation>, MemoryError> {
+        let task = task.to_owned();
+        self.run(move |store| store.get(&task)).await
+    }
+
+    /// Replace the conversation of a task.
+    pub async fn put(&self, task: &str, conversation: Conversation) -> Result<(), MemoryError> {
+        let task = task.to_owned();
+        self.run(move |store| store.put(&task, conversation)).await
+    }
+
+    /// Replace the conversation of a task in one operation, see [MemoryStore::update].
+    pub async fn update(
+        &self,
+        task: &str,
+        mut update: impl FnMut(Conversation) -> Option<Conversation> + Send + 'static,
+    ) -> Result<bool, MemoryError> {
+        let task = task.to_owned();
+        self.run(move |store| store.update(&task, &mut update))
+            .await
+    }
+
+    /// The tasks which have a conversation.
+    pub async fn tasks(&self) -> Result<Vec<String>, MemoryError> {
+        self.run(|store| store.list()).await
+    }
+
+    /// Forget the conversation of a task, returns whether it existed.
+    pub async fn evict(&self, task: &str) -> Result<bool, MemoryError> {
+        let task = task.to_owned();
+        self.run(move |store| store.evict(&task)).await
+    }
+
+    /// Forget all conversations.
+    pub async fn clear(&self) -> Result<(), MemoryError> {
+        self.run(|store| store.clear()).await
+    }
+
+    /// Run an operation of the store, on a blocking thread if the store blocks.
+    async fn run<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&dyn MemoryStore) -> Result<T, MemoryError> + Send + 'static,
+    ) -> Result<T, MemoryError> {
+        if !self.store.blocks() {
+            return operation(self.store.as_ref());
+        }
+        let store = Arc::clone(&self.store);
+        tokio::task::spawn_blocking(move || operation(store.as_ref()))
+            .await
+            .map_err(std::io::Error::from)?
+    }
+}
+
+/// Serialized as a map from `Task` to [Conversation], reading the store on the current thread.
+impl Serialize for AgentShortMemory {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tasks = self.store.list().map_err(S::Error::custom)?;
+        let mut map = serializer.serialize_map(Some(tasks.len()))?;
+        for task in tasks {
+            if let Some(conversation) = self.store.get(&task).map_err(S::Error::custom)? {
+                map.serialize_entry(&task, &conversation)?;
+            }
+        }
+        map.end()
     }
 }
 
@@ -71,13 +191,23 @@ impl Conversation {
         }
     }
 
+    /// The name of the agent the conversation belongs to.
+    pub fn agent_name(&self) -> &str {
+        &self.agent_name
+    }
+
     /// Add a message to the conversation history.
     pub fn add(&mut self, role: Role, message: String) {
-        let timestamp = Local::now().timestamp();
-        let message = Message {
-            role,
-            content: Content::Text(format!("Time: {timestamp} \n{message}")),
-        };
+        self.add_content(role, Content::Text(message));
+    }
+
+    /// Add a message with any [Content], e.g. an image, to the conversation history.
+    pub fn add_content(&mut self, role: Role, content: Content) {
+        self.add_message(Message::new(role, content));
+    }
+
+    /// Add a [Message] to the conversation history, e.g. with [MessageMetadata].
+    pub fn add_message(&mut self, message: Message) {
         self.history.push(message);
 
         if let Some(filepath) = &self.save_filepath {
@@ -97,7 +227,7 @@ impl Conversation {
 
     /// Update a message in the conversation history.
     pub fn update(&mut self, index: usize, role: Role, content: Content) {
-        self.history[index] = Message { role, content };
+        self.history[index] = Message::new(role, content);
     }
 
     /// Query a message in the conversation history.
@@ -113,12 +243,20 @@ impl Conversation {
             .collect()
     }
 
+    /// The messages added in a time range, e.g. `conversation.messages_in(since..)`.
+    pub fn messages_in(&self, range: impl RangeBounds<DateTime<Local>>) -> Vec<&Message> {
+        self.history
+            .iter()
+            .filter(|message| range.contains(&message.timestamp))
+            .collect()
+    }
+
     // Clear the conversation history.
     pub fn clear(&mut self) {
         self.history.clear();
     }
 
-    /// Convert the conversation history to a JSON string.
+    /// Convert the conversation history to a JSON string, which [ConversationFormat::Json] imports.
     pub fn to_json(&self) -> Result<String, ConversationError> {
         Ok(serde_json::to_string(&self.history)?)
     }
@@ -130,35 +268,50 @@ impl Conversation {
         Ok(())
     }
 
+    /// Export the conversation in a [ConversationFormat], which [Conversation::import] reads back.
+    pub fn export(&self, format: ConversationFormat) -> Result<String, ConversationError> {
+        format::export(format, &self.agent_name, &self.history)
+    }
+
+    /// Replace the conversation history with the one exported in a [ConversationFormat].
+    ///
+    /// The agent name is replaced too if the format records it.
+    pub fn import(
+        &mut self,
+        data: &str,
+        format: ConversationFormat,
+    ) -> Result<(), ConversationError> {
+        let imported = format::import(format, data)?;
+        if let Some(agent_name) = imported.agent_name {
+            self.agent_name = agent_name;
+        }
+        self.history = imported.history;
+        Ok(())
+    }
+
     /// Export the conversation history to a file, the content of the file can be imported by `import_from_file`
+    ///
+    /// The format is chosen from the extension, see [ConversationFormat::from_path].
     pub async fn export_to_file(&self, filepath: &Path) -> Result<(), ConversationError> {
-        let data = self.to_string();
+        let data = self.export(ConversationFormat::from_path(filepath))?;
         persistence::save_to_file(data.as_bytes(), filepath).await?;
         Ok(())
     }
 
     /// Import the conversation history from a file, the content of the file should be exported by `export_to_file`
+    ///
+    /// Files exported before the formats existed are text, whatever their extension, they are
+    /// imported as [ConversationFormat::Text] if they can't be read in the format of the extension.
     pub async fn import_from_file(&mut self, filepath: &Path) -> Result<(), ConversationError> {
         let data = persistence::load_from_file(filepath).await?;
-        let history = data
-            .split(|s| *s == b'\n')
-            .map(|line| {
-                let line = String::from_utf8_lossy(line);
-                // M4n5ter(User): hello
-                let (role, content) = line.split_once(": ").unwrap();
-                if role.contains("(User)") {
-                    let role = Role::User(role.replace("(User)", "").to_string());
-                    let content = Content::Text(content.to_owned());
-                    Message { role, content }
-                } else {
-                    let role = Role::Assistant(role.replace("(Assistant)", "").to_string());
-                    let content = Content::Text(content.to_owned());
-                    Message { role, content }
-                }
-            })
-            .collect();
-        self.history = history;
-        Ok(())
+        let data = String::from_utf8_lossy(&data);
+        match ConversationFormat::from_path(filepath) {
+            ConversationFormat::Text => self.import(&data, ConversationFormat::Text),
+            format => self.import(&data, format).or_else(|error| {
+                self.import(&data, ConversationFormat::Text)
+                    .map_err(|_| error)
+            }),
+        }
     }
 
     /// Count the number of messages by role
@@ -177,25 +330,201 @@ pub enum ConversationError {
     JsonError(#[from] serde_json::Error),
     #[error("FilePersistence error: {0}")]
     FilePersistenceError(#[from] PersistenceError),
+    #[error("Invalid conversation: {0}")]
+    InvalidFormat(String),
+    #[error("Unsupported conversation version: {0}")]
+    UnsupportedVersion(u32),
 }
 
-/// A [Message] consists of a [Role] and a [Content].
+/// A [Message] consists of a [Role] and a [Content], with the time it was added.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: Role,
     pub content: Content,
+    /// Histories saved before messages had a timestamp load with the Unix epoch.
+    #[serde(default)]
+    pub timestamp: DateTime<Local>,
+    #[serde(default, skip_serializing_if = "MessageMetadata::is_empty")]
+    pub metadata: MessageMetadata,
+}
+
+impl Message {
+    /// Create a message added now, without metadata.
+    pub fn new(role: Role, content: Content) -> Self {
+        Self {
+            role,
+            content,
+            timestamp: Local::now(),
+            metadata: MessageMetadata::default(),
+        }
+    }
+
+    pub fn with_metadata(mut self, metadata: MessageMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Whether the message is the result of a tool call, which must follow the call
+    pub(crate) fn is_tool_result(&self) -> bool {
+        matches!(self.role, Role::Tool { .. }) || matches!(self.content, Content::ToolResult { .. })
+    }
+}
+
+/// Optional information about a [Message], e.g. which model answered and how fast.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessageMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The id of the message this message answers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_tokens: Option<u64>,
+    /// How long the model took to answer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<Duration>,
+    /// The number of older messages dropped from the chat history to fit the context window,
+    /// when the model answered without them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropped_messages: Option<usize>,
 }
 
-/// A [Role] is a string that identifies the sender of a message.
+impl MessageMetadata {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A [Role] identifies the sender of a message.
 #[derive(Clone, Serialize, Deserialize)]
 pub enum Role {
     User(String),
     Assistant(String),
+    /// Instructions or context from the application, e.g. a plan.
+    System,
+    /// The output of a tool, `call_id` is the id of the tool call.
+    Tool {
+        name: String,
+        call_id: String,
+    },
+    /// Context retrieved from a source, e.g. documents of a vector store.
+    Retrieval {
+        source: String,
+    },
 }
 
+/// The content of a [Message].
 #[derive(Clone, Serialize, Deserialize)]
 pub enum Content {
     Text(String),
+    /// An image, e.g. a screenshot.
+    Image {
+        source: MediaSource,
+        /// The MIME type, e.g. `image/png`.
+        mime_type: String,
+    },
+    /// A document, e.g. a PDF.
+    Document {
+        source: MediaSource,
+        /// The MIME type, e.g. `application/pdf`.
+        mime_type: String,
+    },
+    /// A tool call requested by the assistant.
+    ToolCall {
+        id: String,
+        name: String,
+        arguments: serde_json::Value,
+    },
+    /// The result of a tool call, `id` is the id of the call.
+    ToolResult {
+        id: String,
+        result: String,
+    },
+}
+
+impl Content {
+    pub fn image(source: MediaSource, mime_type: impl Into<String>) -> Self {
+        Content::Image {
+            source,
+            mime_type: mime_type.into(),
+        }
+    }
+
+    pub fn document(source: MediaSource, mime_type: impl Into<String>) -> Self {
+        Content::Document {
+            source,
+            mime_type: mime_type.into(),
+        }
+    }
+
+    pub fn tool_call(
+        id: impl Into<String>,
+        name: impl Into<String>,
+        arguments: serde_json::Value,
+    ) -> Self {
+        Content::ToolCall {
+            id: id.into(),
+            name: name.into(),
+            arguments,
+        }
+    }
+
+    pub fn tool_result(id: impl Into<String>, result: impl Into<String>) -> Self {
+        Content::ToolResult {
+            id: id.into(),
+            result: result.into(),
+        }
+    }
+}
+
+/// Where the data of an image or a document comes from.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum MediaSource {
+    /// The data itself, serialized as base64.
+    Bytes(#[serde(with = "base64_bytes")] Vec<u8>),
+    /// A URL, which is passed to the model as is.
+    Url(String),
+}
+
+impl MediaSource {
+    /// The data as base64, or the URL
+    pub(crate) fn to_data(&self) -> String {
+        match self {
+            MediaSource::Bytes(bytes) => BASE64_STANDARD.encode(bytes),
+            MediaSource::Url(url) => url.clone(),
+        }
+    }
+}
+
+impl Display for MediaSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaSource::Bytes(bytes) => write!(f, "{} bytes", bytes.len()),
+            MediaSource::Url(url) => f.write_str(url),
+        }
+    }
+}
+
+/// Serialize bytes as base64
+mod base64_bytes {
+    use base64::{Engine, prelude::BASE64_STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub(super) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64_STANDARD.encode(bytes))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let data = String::deserialize(deserializer)?;
+        BASE64_STANDARD.decode(data).map_err(D::Error::custom)
+    }
 }
 
 impl Display for Conversation {
@@ -212,6 +541,9 @@ impl Display for Role {
         match self {
             Role::User(name) => write!(f, "{name}(User)"),
             Role::Assistant(name) => write!(f, "{name}(Assistant)"),
+            Role::System => f.write_str("System"),
+            Role::Tool { name, .. } => write!(f, "{name}(Tool)"),
+            Role::Retrieval { source } => write!(f, "{source}(Retrieval)"),
         }
     }
 }
@@ -220,6 +552,301 @@ impl Display for Content {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Content::Text(text) => f.pad(text),
+            Content::Image { source, mime_type } => write!(f, "[Image: {mime_type}, {source}]"),
+            Content::Document { source, mime_type } => {
+                write!(f, "[Document: {mime_type}, {source}]")
+            }
+            Content::ToolCall {
+                id,
+                name,
+                arguments,
+            } => write!(f, "[Tool call {id}: {name}({arguments})]"),
+            Content::ToolResult { id, result } => write!(f, "[Tool result {id}]: {result}"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rig::{
+        OneOrMany,
+        message::{
+            self as rig_message, AssistantContent, ContentFormat, ImageMediaType, UserContent,
+        },
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new("reviewer".to_owned());
+        let user = Role::User("user".to_owned());
+        let assistant = Role::Assistant("reviewer".to_owned());
+        conversation.add_content(user.clone(), Content::Text("Review this".to_owned()));
+        conversation.add_content(
+            user.clone(),
+            Content::image(MediaSource::Bytes(vec![1, 2, 3]), "image/png"),
+        );
+        conversation.add_content(
+            user,
+            Content::document(
+                MediaSource::Url("https://example.com/spec.pdf".to_owned()),
+                "application/pdf",
+            ),
+        );
+        conversation.add_content(
+            assistant.clone(),
+            Content::tool_call("call_1", "ocr", json!({ "page": 1 })),
+        );
+        conversation.add_content(
+            Role::User("ocr".to_owned()),
+            Content::tool_result("call_1", "Page 1"),
+        );
+        conversation.add_content(assistant, Content::Text("Looks good".to_owned()));
+        conversation
+    }
+
+    #[test]
+    fn test_content_display() {
+        assert_eq!(
+            conversation().to_string(),
+            "user(User): Review this\n\
+             user(User): [Image: image/png, 3 bytes]\n\
+             user(User): [Document: application/pdf, https://example.com/spec.pdf]\n\
+             reviewer(Assistant): [Tool call call_1: ocr({\"page\":1})]\n\
+             ocr(User): [Tool result call_1]: Page 1\n\
+             reviewer(Assistant): Looks good\n"
+        );
+    }
+
+    #[test]
+    fn test_content_json() {
+        let conversation = conversation();
+        let json = conversation.to_json().unwrap();
+        assert!(json.contains(r#""Bytes":"AQID""#));
+
+        let history: Vec<Message> = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&history).unwrap(), json);
+
+        // Histories saved before other contents existed still load
+        let history: Vec<Message> =
+            serde_json::from_str(r#"[{"role":{"User":"user"},"content":{"Text":"hello"}}]"#)
+                .unwrap();
+        assert_eq!(history[0].content.to_string(), "hello");
+    }
+
+    #[test]
+    fn test_content_to_rig_messages() {
+        let messages: Vec<rig_message::Message> = (&conversation()).into();
+        assert_eq!(messages.len(), 6);
+        assert_eq!(messages[0], rig_message::Message::user("user: Review this"));
+
+        let mut image = OneOrMany::one(UserContent::text("user:"));
+        image.push(UserContent::image(
+            "AQID",
+            Some(ContentFormat::Base64),
+            Some(ImageMediaType::PNG),
+            None,
+        ));
+        assert_eq!(messages[1], rig_message::Message::User { content: image });
+        let rig_message::Message::User { content } = &messages[2] else {
+            panic!("documents are user content");
+        };
+        assert!(matches!(
+            content.rest()[0],
+            UserContent::Document(rig_message::Document {
+                format: Some(ContentFormat::String),
+                ..
+            })
+        ));
+
+        assert_eq!(
+            messages[3],
+            rig_message::Message::Assistant {
+                content: OneOrMany::one(AssistantContent::tool_call(
+                    "call_1",
+                    "ocr",
+                    json!({ "page": 1 })
+                ))
+            }
+        );
+        assert!(matches!(
+            &messages[4],
+            rig_message::Message::User { content }
+                if matches!(content.first(), UserContent::ToolResult(result) if result.id == "call_1")
+        ));
+        assert_eq!(
+            messages[5],
+            rig_message::Message::assistant("reviewer: Looks good")
+        );
+    }
+
+    #[test]
+    fn test_roles() {
+        let mut conversation = Conversation::new("researcher".to_owned());
+        conversation.add_content(Role::System, Content::Text("Plan: search".to_owned()));
+        conversation.add_content(
+            Role::Retrieval {
+                source: "docs".to_owned(),
+            },
+            Content::Text("Rust is a language".to_owned()),
+        );
+        conversation.add_content(
+            Role::Tool {
+                name: "search".to_owned(),
+                call_id: "call_1".to_owned(),
+            },
+            Content::Text("3 results".to_owned()),
+        );
+        assert_eq!(
+            conversation.to_string(),
+            "System: Plan: search\n\
+             docs(Retrieval): Rust is a language\n\
+             search(Tool): 3 results\n"
+        );
+
+        let messages: Vec<rig_message::Message> = (&conversation).into();
+        assert_eq!(
+            messages[0],
+            rig_message::Message::user("System: Plan: search")
+        );
+        assert_eq!(
+            messages[1],
+            rig_message::Message::user("Retrieved from docs: Rust is a language")
+        );
+        // Without its call, the output of the tool is sent as text
+        assert_eq!(
+            messages[2],
+            rig_message::Message::user("search(Tool): 3 results")
+        );
+
+        // After its call, as the result of the call
+        conversation.history.insert(
+            2,
+            Message::new(
+                Role::Assistant("researcher".to_owned()),
+                Content::ToolCall {
+                    id: "call_1".to_owned(),
+                    name: "search".to_owned(),
+                    arguments: json!({ "query": "rust" }),
+                },
+            ),
+        );
+        let messages: Vec<rig_message::Message> = (&conversation).into();
+        assert!(matches!(
+            &messages[3],
+            rig_message::Message::User { content }
+                if matches!(content.first(), UserContent::ToolResult(result) if result.id == "call_1")
+        ));
+    }
+
+    #[test]
+    fn test_timestamps_and_metadata() {
+        let mut conversation = Conversation::new("writer".to_owned());
+        let mut message = Message::new(
+            Role::User("user".to_owned()),
+            Content::Text("Write a poem".to_owned()),
+        );
+        message.timestamp = Local::now() - chrono::Duration::hours(1);
+        conversation.add_message(message);
+        let since = Local::now();
+        conversation.add_message(
+            Message::new(
+                Role::Assistant("writer".to_owned()),
+                Content::Text("Roses are red".to_owned()),
+            )
+            .with_metadata(MessageMetadata {
+                model: Some("deepseek-chat".to_owned()),
+                completion_tokens: Some(4),
+                latency: Some(Duration::from_millis(1500)),
+                ..Default::default()
+            }),
+        );
+
+        // The content is kept clean
+        assert_eq!(
+            conversation.search("poem")[0].content.to_string(),
+            "Write a poem"
+        );
+        let recent = conversation.messages_in(since..);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].metadata.model.as_deref(), Some("deepseek-chat"));
+        assert_eq!(conversation.messages_in(..since).len(), 1);
+
+        let json = conversation.to_json().unwrap();
+        let history: Vec<Message> = serde_json::from_str(&json).unwrap();
+        assert_eq!(history[0].timestamp, conversation.history[0].timestamp);
+        assert!(history[0].metadata.is_empty());
+        assert_eq!(history[1].metadata, conversation.history[1].metadata);
+        assert!(!json.contains("parent_id"));
+    }
+
+    #[tokio::test]
+    async fn test_import_baseline_files() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        // Files were exported as displayed, with the timestamps in the text
+        let baseline = "user(User): Time: 1700000000 \nWrite a poem\n\
+            writer(Assistant): Time: 1700000005 \nRoses are red\n";
+        for name in ["chat.json", "chat.md", "chat.txt"] {
+            let path = dir.join(name);
+            std::fs::write(&path, baseline).unwrap();
+            let mut conversation = Conversation::new("writer".to_owned());
+            conversation.import_from_file(&path).await.unwrap();
+            let history = &conversation.history;
+            assert_eq!(history.len(), 2, "{name}");
+            assert_eq!(history[1].content.to_string(), "Roses are red");
+            assert_eq!(history[1].timestamp.timestamp(), 1_700_000_005);
+        }
+
+        // Errors of the format are kept
+        let path = dir.join("future.json");
+        std::fs::write(
+            &path,
+            r#"{"version":2,"agent_name":"writer","messages":[]}"#,
+        )
+        .unwrap();
+        let mut conversation = Conversation::new("writer".to_owned());
+        assert!(matches!(
+            conversation.import_from_file(&path).await,
+            Err(ConversationError::UnsupportedVersion(2))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_short_memory_with_blocking_store() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let short_memory =
+            AgentShortMemory::with_store(Arc::new(crate::memory::FileStore::new(&dir).unwrap()));
+        short_memory
+            .add(
+                "task",
+                "writer",
+                Role::User("user".to_owned()),
+                "Write a poem",
+            )
+            .await
+            .unwrap();
+        short_memory
+            .add_content(
+                "task",
+                "writer",
+                Role::Assistant("writer".to_owned()),
+                Content::Text("Roses are red".to_owned()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(short_memory.tasks().await.unwrap(), ["task"]);
+        let conversation = short_memory.get("task").await.unwrap().unwrap();
+        assert_eq!(conversation.history[1].content.to_string(), "Roses are red");
+        assert_eq!(
+            serde_json::to_value(&short_memory).unwrap()["task"]["history"][0]["content"],
+            serde_json::to_value(&conversation.history[0].content).unwrap()
+        );
+
+        assert!(short_memory.evict("task").await.unwrap());
+        assert!(short_memory.get("task").await.unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
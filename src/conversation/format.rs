@@ -0,0 +1,532 @@
+//! Export and import formats of conversations
+//!
+
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use super::{Content, ConversationError, Message, MessageMetadata, Role};
+
+/// The version of the JSON, JSONL and Markdown schemas written by this crate
+///
+/// Version 0 is the bare JSON array of messages written by [`Conversation::to_json`](super::Conversation::to_json).
+pub const CONVERSATION_VERSION: u32 = 1;
+
+/// A format to export a [`Conversation`](super::Conversation) to, and import it from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConversationFormat {
+    /// A JSON document with the version, the agent name and the messages
+    Json,
+    /// A header line with the version and the agent name, then one message per line
+    JsonLines,
+    /// Readable Markdown, with the role, timestamp and metadata of each message in an HTML comment
+    Markdown,
+    /// Lines of `name(Role): text`, as displayed. Timestamps, metadata and tool call ids are lost.
+    Text,
+}
+
+impl ConversationFormat {
+    /// The format of a file from its extension, [`ConversationFormat::Text`] if the extension is unknown
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Self::Json,
+            Some("jsonl") => Self::JsonLines,
+            Some("md" | "markdown") => Self::Markdown,
+            _ => Self::Text,
+        }
+    }
+}
+
+/// The conversation found in imported data
+pub(super) struct Imported {
+    /// `None` if the format doesn't record it
+    pub(super) agent_name: Option<String>,
+    pub(super) history: Vec<Message>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    agent_name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Document {
+    #[serde(flatten)]
+    header: Header,
+    messages: Vec<Message>,
+}
+
+/// A message in Markdown, without its text, which follows the comment
+#[derive(Serialize, Deserialize)]
+struct MarkdownMessage {
+    role: Role,
+    timestamp: DateTime<Local>,
+    #[serde(default, skip_serializing_if = "MessageMetadata::is_empty")]
+    metadata: MessageMetadata,
+    /// Contents other than text, which are displayed but not parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content: Option<Content>,
+}
+
+const MARKDOWN_CONVERSATION: &str = "<!-- conversation ";
+const MARKDOWN_MESSAGE: &str = "<!-- message ";
+const MARKDOWN_COMMENT_END: &str = " -->";
+
+pub(super) fn export(
+    format: ConversationFormat,
+    agent_name: &str,
+    history: &[Message],
+) -> Result<String, ConversationError> {
+    let header = Header {
+        version: CONVERSATION_VERSION,
+        agent_name: agent_name.to_owned(),
+    };
+    match format {
+        ConversationFormat::Json => Ok(serde_json::to_string_pretty(&Document {
+            header,
+            messages: history.to_vec(),
+        })?),
+        ConversationFormat::JsonLines => {
+            let mut data = serde_json::to_string(&header)?;
+            for message in history {
+                data.push('\n');
+                data.push_str(&serde_json::to_string(message)?);
+            }
+            data.push('\n');
+            Ok(data)
+        }
+        ConversationFormat::Markdown => export_markdown(&header, history),
+        ConversationFormat::Text => Ok(history
+            .iter()
+            .map(|message| format!("{}: {}\n", message.role, message.content))
+            .collect()),
+    }
+}
+
+pub(super) fn import(
+    format: ConversationFormat,
+    data: &str,
+) -> Result<Imported, ConversationError> {
+    match format {
+        ConversationFormat::Json => import_json(data),
+        ConversationFormat::JsonLines => {
+            let mut lines = data.lines().filter(|line| !line.trim().is_empty());
+            let header = lines
+                .next()
+                .ok_or_else(|| ConversationError::InvalidFormat("missing header".to_owned()))?;
+            let header: Header = serde_json::from_str(header)?;
+            check_version(header.version)?;
+            let history = lines.map(serde_json::from_str).collect::<Result<_, _>>()?;
+            Ok(Imported {
+                agent_name: Some(header.agent_name),
+                history,
+            })
+        }
+        ConversationFormat::Markdown => import_markdown(data),
+        ConversationFormat::Text => import_text(data),
+    }
+}
+
+fn check_version(version: u32) -> Result<(), ConversationError> {
+    if version > CONVERSATION_VERSION {
+        return Err(ConversationError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+fn import_json(data: &str) -> Result<Imported, ConversationError> {
+    let mut value: serde_json::Value = serde_json::from_str(data)?;
+    // Version 0, the messages written by `to_json`
+    if value.is_array() {
+        return Ok(Imported {
+            agent_name: None,
+            history: upgrade_legacy_history(serde_json::from_value(value)?),
+        });
+    }
+    // Version 0, a serialized conversation, e.g. the state saved by an agent
+    if value.get("version").is_none()
+        && let Some(history) = value.get_mut("history")
+    {
+        let history = serde_json::from_value(history.take())?;
+        return Ok(Imported {
+            agent_name: value
+                .get("agent_name")
+                .and_then(serde_json::Value::as_str)
+                .map(ToOwned::to_owned),
+            history: upgrade_legacy_history(history),
+        });
+    }
+
+    let version = value
+        .get("version")
+        .and_then(serde_json::Value::as_u64)
+        .ok_or_else(|| ConversationError::InvalidFormat("missing version".to_owned()))?;
+    check_version(u32::try_from(version).unwrap_or(u32::MAX))?;
+    let document: Document = serde_json::from_value(value)?;
+    Ok(Imported {
+        agent_name: Some(document.header.agent_name),
+        history: document.messages,
+    })
+}
+
+/// Move the timestamps which were prepended to the text, before messages had one
+fn upgrade_legacy_history(history: Vec<Message>) -> Vec<Message> {
+    history
+        .into_iter()
+        .map(|mut message| {
+            if message.timestamp == DateTime::<Local>::default() {
+                upgrade_legacy_text(&mut message);
+            }
+            message
+        })
+        .collect()
+}
+
+/// Move the timestamp prepended to the text of a message, e.g. `Time: 1700000000 \nhello`
+fn upgrade_legacy_text(message: &mut Message) {
+    if let Content::Text(text) = &message.content
+        && let Some((seconds, text)) = text
+            .strip_prefix("Time: ")
+            .and_then(|text| text.split_once(" \n"))
+        && let Some(timestamp) = seconds
+            .parse()
+            .ok()
+            .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+    {
+        message.content = Content::Text(text.to_owned());
+        message.timestamp = timestamp.with_timezone(&Local);
+    }
+}
+
+/// Escape the lines of a text which would start a message, by adding a backslash
+fn escape_markdown(text: &str) -> String {
+    text.split('\n')
+        .map(|line| {
+            if line.trim_start_matches('\\').starts_with(MARKDOWN_MESSAGE) {
+                format!("\\{line}")
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn unescape_markdown(text: &str) -> String {
+    text.split('\n')
+        .map(|line| match line.strip_prefix('\\') {
+            Some(escaped)
+                if escaped
+                    .trim_start_matches('\\')
+                    .starts_with(MARKDOWN_MESSAGE) =>
+            {
+                escaped
+            }
+            _ => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn export_markdown(header: &Header, history: &[Message]) -> Result<String, ConversationError> {
+    let mut data = format!(
+        "{MARKDOWN_CONVERSATION}{}{MARKDOWN_COMMENT_END}\n# {}\n",
+        serde_json::to_string(header)?,
+        header.agent_name
+    );
+    for message in history {
+        let (content, text) = match &message.content {
+            Content::Text(text) => (None, escape_markdown(text)),
+            content => (Some(content.clone()), content.to_string()),
+        };
+        let comment = serde_json::to_string(&MarkdownMessage {
+            role: message.role.clone(),
+            timestamp: message.timestamp,
+            metadata: message.metadata.clone(),
+            content,
+        })?;
+        data.push_str(&format!(
+            "\n{MARKDOWN_MESSAGE}{comment}{MARKDOWN_COMMENT_END}\n**{}** {}\n\n{text}\n",
+            message.role,
+            message.timestamp.format("%Y-%m-%d %H:%M:%S"),
+        ));
+    }
+    Ok(data)
+}
+
+fn import_markdown(data: &str) -> Result<Imported, ConversationError> {
+    let invalid = |reason: &str| ConversationError::InvalidFormat(reason.to_owned());
+
+    let (header, mut rest) = data.split_once('\n').unwrap_or((data, ""));
+    let header = header
+        .strip_prefix(MARKDOWN_CONVERSATION)
+        .and_then(|header| header.strip_suffix(MARKDOWN_COMMENT_END))
+        .ok_or_else(|| invalid("missing conversation header"))?;
+    let header: Header = serde_json::from_str(header)?;
+    check_version(header.version)?;
+
+    let mut history = Vec::new();
+    let start = format!("\n{MARKDOWN_MESSAGE}");
+    // Skip the title
+    let Some(index) = rest.find(&start) else {
+        return Ok(Imported {
+            agent_name: Some(header.agent_name),
+            history,
+        });
+    };
+    rest = &rest[index + 1..];
+    while !rest.is_empty() {
+        let (comment, body) = rest.split_once('\n').unwrap_or((rest, ""));
+        let (body, next) = match body.find(&start) {
+            // The blank line before the next message is not part of the text
+            Some(index) => (&body[..index], &body[index + 1..]),
+            None => (body, ""),
+        };
+        rest = next;
+
+        let comment = comment
+            .strip_prefix(MARKDOWN_MESSAGE)
+            .and_then(|comment| comment.strip_suffix(MARKDOWN_COMMENT_END))
+            .ok_or_else(|| invalid("invalid message comment"))?;
+        let message: MarkdownMessage = serde_json::from_str(comment)?;
+        let content = match message.content {
+            Some(content) => content,
+            None => {
+                // **role** timestamp, a blank line, the text and a newline
+                let text = body
+                    .split_once("\n\n")
+                    .and_then(|(_, text)| text.strip_suffix('\n'))
+                    .ok_or_else(|| invalid("invalid message text"))?;
+                Content::Text(unescape_markdown(text))
+            }
+        };
+        history.push(Message {
+            role: message.role,
+            content,
+            timestamp: message.timestamp,
+            metadata: message.metadata,
+        });
+    }
+    Ok(Imported {
+        agent_name: Some(header.agent_name),
+        history,
+    })
+}
+
+/// The role of a displayed message, e.g. `M4n5ter(User)`
+fn parse_role(role: &str) -> Option<Role> {
+    if let Some(name) = role.strip_suffix("(User)") {
+        Some(Role::User(name.to_owned()))
+    } else if let Some(name) = role.strip_suffix("(Assistant)") {
+        Some(Role::Assistant(name.to_owned()))
+    } else if let Some(name) = role.strip_suffix("(Tool)") {
+        // The call id is not displayed
+        Some(Role::Tool {
+            name: name.to_owned(),
+            call_id: String::new(),
+        })
+    } else if let Some(source) = role.strip_suffix("(Retrieval)") {
+        Some(Role::Retrieval {
+            source: source.to_owned(),
+        })
+    } else if role == "System" {
+        Some(Role::System)
+    } else {
+        None
+    }
+}
+
+fn import_text(data: &str) -> Result<Imported, ConversationError> {
+    let mut history: Vec<Message> = Vec::new();
+    let data = data.strip_suffix('\n').unwrap_or(data);
+    if data.is_empty() {
+        return Ok(Imported {
+            agent_name: None,
+            history,
+        });
+    }
+
+    for (index, line) in data.split('\n').enumerate() {
+        // M4n5ter(User): hello
+        let message = line
+            .split_once(": ")
+            .and_then(|(role, text)| Some((parse_role(role)?, text)));
+        match (message, history.last_mut()) {
+            (Some((role, text)), _) => {
+                history.push(Message::new(role, Content::Text(text.to_owned())));
+            }
+            // A line of a multi-line message
+            (
+                None,
+                Some(Message {
+                    content: Content::Text(text),
+                    ..
+                }),
+            ) => {
+                text.push('\n');
+                text.push_str(line);
+            }
+            (None, _) => {
+                return Err(ConversationError::InvalidFormat(format!(
+                    "line {} is not a message: {line}",
+                    index + 1
+                )));
+            }
+        }
+    }
+    // Exported before messages had a timestamp
+    for message in &mut history {
+        upgrade_legacy_text(message);
+    }
+    Ok(Imported {
+        agent_name: None,
+        history,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::conversation::{Conversation, MediaSource};
+
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new("reviewer".to_owned());
+        conversation.add(
+            Role::User("user".to_owned()),
+            "Review this:\n\n<!-- message {} -->\n\\<!-- message\n".to_owned(),
+        );
+        conversation.add_content(
+            Role::User("user".to_owned()),
+            Content::image(MediaSource::Bytes(vec![1, 2, 3]), "image/png"),
+        );
+        conversation.add_content(
+            Role::Assistant("reviewer".to_owned()),
+            Content::tool_call("call_1", "ocr", json!({ "page": 1 })),
+        );
+        conversation.add(
+            Role::Tool {
+                name: "ocr".to_owned(),
+                call_id: "call_1".to_owned(),
+            },
+            "Page 1".to_owned(),
+        );
+        conversation.add(
+            Role::Retrieval {
+                source: "docs".to_owned(),
+            },
+            String::new(),
+        );
+        conversation.add(Role::System, "Plan: review".to_owned());
+        conversation.add_message(
+            Message::new(
+                Role::Assistant("reviewer".to_owned()),
+                Content::Text("Looks good".to_owned()),
+            )
+            .with_metadata(MessageMetadata {
+                model: Some("deepseek-chat".to_owned()),
+                latency: Some(Duration::from_millis(1200)),
+                ..Default::default()
+            }),
+        );
+        conversation
+    }
+
+    #[test]
+    fn test_lossless_round_trip() {
+        let conversation = conversation();
+        let expected = serde_json::to_value(&conversation.history).unwrap();
+        for format in [
+            ConversationFormat::Json,
+            ConversationFormat::JsonLines,
+            ConversationFormat::Markdown,
+        ] {
+            let data = conversation.export(format).unwrap();
+            let mut imported = Conversation::new("other".to_owned());
+            imported.import(&data, format).unwrap();
+            assert_eq!(imported.agent_name, "reviewer", "{format:?}");
+            assert_eq!(
+                serde_json::to_value(&imported.history).unwrap(),
+                expected,
+                "{format:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_import_text() {
+        let mut conversation = Conversation::new("reviewer".to_owned());
+        conversation
+            .import(
+                "user(User): Review this:\n- a\n- b\nreviewer(Assistant): Done\nSystem: Plan\n",
+                ConversationFormat::Text,
+            )
+            .unwrap();
+        assert_eq!(conversation.history.len(), 3);
+        assert_eq!(
+            conversation.history[0].content.to_string(),
+            "Review this:\n- a\n- b"
+        );
+        assert!(matches!(conversation.history[2].role, Role::System));
+
+        let error = conversation
+            .import("no role\n", ConversationFormat::Text)
+            .unwrap_err();
+        assert!(matches!(error, ConversationError::InvalidFormat(_)));
+
+        // The timestamps were in the text before messages had one
+        conversation
+            .import(
+                "user(User): Time: 1700000000 \nReview this\n",
+                ConversationFormat::Text,
+            )
+            .unwrap();
+        assert_eq!(conversation.history[0].content.to_string(), "Review this");
+        assert_eq!(conversation.history[0].timestamp.timestamp(), 1_700_000_000);
+    }
+
+    #[test]
+    fn test_versions() {
+        // Version 0, written by `to_json`
+        let conversation = conversation();
+        let mut imported = Conversation::new("other".to_owned());
+        imported
+            .import(&conversation.to_json().unwrap(), ConversationFormat::Json)
+            .unwrap();
+        assert_eq!(imported.agent_name, "other");
+        assert_eq!(imported.history.len(), conversation.history.len());
+
+        // Version 0, a serialized conversation with the timestamps in the text
+        let legacy = r#"{"agent_name":"reviewer","save_filepath":null,"history":[
+            {"role":{"User":"user"},"content":{"Text":"Time: 1700000000 \nReview this"}}
+        ]}"#;
+        imported.import(legacy, ConversationFormat::Json).unwrap();
+        assert_eq!(imported.agent_name, "reviewer");
+        assert_eq!(imported.history[0].content.to_string(), "Review this");
+        assert_eq!(imported.history[0].timestamp.timestamp(), 1_700_000_000);
+
+        let future = r#"{"version":2,"agent_name":"reviewer","messages":[]}"#;
+        for (data, format) in [
+            (future.to_owned(), ConversationFormat::Json),
+            (future.to_owned(), ConversationFormat::JsonLines),
+            (
+                format!("<!-- conversation {future} -->\n"),
+                ConversationFormat::Markdown,
+            ),
+        ] {
+            assert!(matches!(
+                imported.import(&data, format),
+                Err(ConversationError::UnsupportedVersion(2))
+            ));
+        }
+        assert!(matches!(
+            imported.import("# reviewer\n", ConversationFormat::Markdown),
+            Err(ConversationError::InvalidFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,254 @@
+//! Fail over between LLM providers
+//!
+#![deny(missing_docs)]
+
+use std::sync::Arc;
+
+use futures::future::{self, BoxFuture};
+use rig::completion::PromptError;
+
+use crate::{
+    agent::{Agent, AgentConfig, AgentError},
+    conversation::{AgentShortMemory, Role},
+    llm_provider::LLMProvider,
+    retry::ErrorKind,
+    rig_agent::RigAgent,
+};
+
+/// An agent backed by an ordered list of providers, which fails over to the next provider when
+/// a provider keeps failing
+///
+/// Each provider runs the task with its own retries, see
+/// [`RetryPolicy`](crate::retry::RetryPolicy). When the retries are exhausted with a provider
+/// error, e.g. a rate limit, an outage or rejected credentials, the task is run again with the
+/// next provider. Invalid requests and errors unrelated to the provider, e.g. a failing tool,
+/// are returned right away, as they would fail with any provider.
+///
+/// The conversation of the fallback agent records which provider produced each answer, as
+/// answers of the assistant `{agent}@{provider}`, e.g. `Agent@deepseek/deepseek-chat`.
+pub struct FallbackAgent {
+    config: AgentConfig,
+    /// The providers in order, with the agents built from them
+    agents: Vec<(String, Arc<dyn Agent>)>,
+    short_memory: AgentShortMemory,
+}
+
+impl FallbackAgent {
+    /// Create an agent without providers, add them with [`FallbackAgent::with_provider`]
+    pub fn new(config: AgentConfig) -> Self {
+        Self {
+            config,
+            agents: Vec::new(),
+            short_memory: AgentShortMemory::new(),
+        }
+    }
+
+    /// Create an agent failing over between the providers, in order
+    pub fn from_providers(
+        config: AgentConfig,
+        providers: impl IntoIterator<Item = LLMProvider>,
+    ) -> Result<Self, AgentError> {
+        providers
+            .into_iter()
+            .try_fold(Self::new(config), Self::with_provider)
+    }
+
+    /// Add a provider, after the providers already added
+    ///
+    /// The agent of the provider is built from the configuration of the fallback agent, with
+    /// the model of the provider.
+    pub fn with_provider(self, provider: LLMProvider) -> Result<Self, AgentError> {
+        let label = provider.to_string();
+        let agent = RigAgent::from_provider(provider, self.config.clone())?;
+        Ok(self.with_agent(label, agent))
+    }
+
+    /// Add an agent, after the agents already added
+    ///
+    /// `provider` names the agent's provider in the conversation.
+    pub fn with_agent(mut self, provider: impl Into<String>, agent: Arc<dyn Agent>) -> Self {
+        self.agents.push((provider.into(), agent));
+        self
+    }
+
+    /// The conversations of the agent, with the provider of each answer
+    pub fn short_memory(&self) -> &AgentShortMemory {
+        &self.short_memory
+    }
+
+    /// Whether another provider may succeed where this one failed
+    fn is_provider_failure(error: &AgentError) -> bool {
+        matches!(
+            error,
+            AgentError::PromptError(PromptError::CompletionError(_))
+        ) && ErrorKind::of(error) != ErrorKind::InvalidRequest
+    }
+}
+
+impl Agent for FallbackAgent {
+    fn run(&self, task: String) -> BoxFuture<'_, Result<String, AgentError>> {
+        Box::pin(async move {
+            self.short_memory
+                .add(
+                    &task,
+                    &self.config.name,
+                    Role::User(self.config.user_name.clone()),
+                    &task,
+                )
+                .await?;
+
+            let mut last_error = None;
+            for (provider, agent) in &self.agents {
+                match agent.run_checked(task.clone()).await {
+                    Ok(response) => {
+                        self.short_memory
+                            .add(
+                                &task,
+                                &self.config.name,
+                                Role::Assistant(format!("{}@{}", self.config.name, provider)),
+                                &response,
+                            )
+                            .await?;
+                        return Ok(response);
+                    }
+                    Err(e) if Self::is_provider_failure(&e) => {
+                        tracing::warn!(
+                            "Agent<{}>, provider {} failed, failing over: {}",
+                            self.config.name,
+                            provider,
+                            e
+                        );
+                        last_error = Some(e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            Err(last_error.unwrap_or_else(|| {
+                AgentError::BuildError(format!(
+                    "Fallback agent '{}' has no providers",
+                    self.config.name
+                ))
+            }))
+        })
+    }
+
+    fn run_multiple_tasks(
+        &mut self,
+        tasks: Vec<String>,
+    ) -> BoxFuture<'_, Result<Vec<String>, AgentError>> {
+        let agent = &*self;
+        Box::pin(future::try_join_all(
+            tasks.into_iter().map(move |task| agent.run(task)),
+        ))
+    }
+
+    fn id(&self) -> String {
+        self.config.id.clone()
+    }
+
+    fn name(&self) -> String {
+        self.config.name.clone()
+    }
+
+    fn description(&self) -> String {
+        self.config.description.clone().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::tests::{MockAgent, mock_agent, provider_error};
+
+    fn fallback_agent(agents: Vec<(&str, MockAgent)>) -> FallbackAgent {
+        let config = AgentConfig::builder().agent_name("writer").build();
+        agents
+            .into_iter()
+            .fold(FallbackAgent::new(config), |fallback, (provider, agent)| {
+                fallback.with_agent(provider, Arc::new(agent))
+            })
+    }
+
+    async fn answers(agent: &FallbackAgent, task: &str) -> Vec<(String, String)> {
+        let conversation = agent.short_memory().get(task).await.unwrap().unwrap();
+        conversation
+            .history
+            .iter()
+            .filter_map(|message| match &message.role {
+                Role::Assistant(name) => Some((name.clone(), message.content.to_string())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_fail_over() {
+        let agent = fallback_agent(vec![
+            (
+                "deepseek",
+                mock_agent(1, |_| Err(provider_error("Service Unavailable"))),
+            ),
+            (
+                "openrouter",
+                mock_agent(1, |_| Err(provider_error("Unauthorized"))),
+            ),
+            ("anthropic", mock_agent(1, |_| Ok("answer".to_owned()))),
+            ("unused", mock_agent(0, |_| Ok(String::new()))),
+        ]);
+        assert_eq!(agent.run("task".to_owned()).await.unwrap(), "answer");
+
+        let answers = answers(&agent, "task").await;
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].0, "writer@anthropic");
+        assert!(answers[0].1.ends_with("answer"));
+    }
+
+    #[tokio::test]
+    async fn test_no_fail_over() {
+        // The request would be invalid with any provider
+        let agent = fallback_agent(vec![
+            (
+                "deepseek",
+                mock_agent(1, |_| Err(provider_error("invalid_request_error"))),
+            ),
+            ("openrouter", mock_agent(0, |_| Ok(String::new()))),
+        ]);
+        let error = agent.run("task".to_owned()).await.unwrap_err();
+        assert!(error.to_string().contains("invalid_request_error"));
+
+        // Errors unrelated to the provider
+        let agent = fallback_agent(vec![
+            (
+                "deepseek",
+                mock_agent(1, |_| Err(AgentError::TestError("tool failed".to_owned()))),
+            ),
+            ("openrouter", mock_agent(0, |_| Ok(String::new()))),
+        ]);
+        let error = agent.run("task".to_owned()).await.unwrap_err();
+        assert_eq!(error.to_string(), "Test error: tool failed");
+        assert!(answers(&agent, "task").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_all_providers_fail() {
+        let agent = fallback_agent(vec![
+            (
+                "deepseek",
+                mock_agent(1, |_| Err(provider_error("timed out"))),
+            ),
+            (
+                "openrouter",
+                mock_agent(1, |_| Err(provider_error("rate limit"))),
+            ),
+        ]);
+        let error = agent.run("task".to_owned()).await.unwrap_err();
+        assert!(error.to_string().contains("rate limit"));
+
+        let agent = fallback_agent(vec![]);
+        assert!(matches!(
+            agent.run("task".to_owned()).await,
+            Err(AgentError::BuildError(_))
+        ));
+    }
+}
@@ -3,24 +3,53 @@
 #![deny(missing_docs)]
 
 use std::{
-    collections::{HashMap, hash_map},
+    collections::{HashMap, HashSet, hash_map},
     fmt::Debug,
+    num::NonZeroUsize,
+    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
 
+use chrono::Local;
+
 use dashmap::DashMap;
+use futures::{StreamExt, stream::FuturesUnordered};
 use petgraph::{
     Direction,
     graph::{EdgeIndex, NodeIndex},
     prelude::StableGraph,
-    visit::EdgeRef,
+    visit::{EdgeRef, NodeIndexable},
 };
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::sync::Mutex;
+use tokio::{
+    sync::{Mutex, Semaphore, mpsc},
+    time::Instant,
+};
+pub use tokio_util::sync::CancellationToken;
 
 use crate::agent::Agent;
 
+mod aggregator;
+mod checkpoint;
+mod event;
+mod expression;
+mod spec;
+mod workflow_agent;
+
+pub use aggregator::{Aggregator, NodeInput};
+pub use checkpoint::WorkflowCheckpoint;
+pub use event::{WorkflowEvent, WorkflowObserver};
+pub use expression::{Condition, Transform};
+pub use spec::{
+    AgentSpec, ConnectionSpec, ProviderKind, ToolRegistry, WorkflowSpec, WorkflowSpecError,
+};
+pub use workflow_agent::WorkflowAgent;
+
+/// The timeout of agents without their own timeout, unless the workflow sets another one
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3600);
+
 /// The main orchestration structure
 pub struct DAGWorkflow {
     /// The workflow name
@@ -33,6 +62,28 @@ pub struct DAGWorkflow {
     workflow: StableGraph<AgentNode, Flow>,
     /// Map from agent name to node index for quick lookup
     name_to_node: HashMap<String, NodeIndex>,
+    /// Whether flows are allowed to form feedback loops
+    allow_cycles: bool,
+    /// Flows which close a feedback loop
+    back_edges: HashSet<EdgeIndex>,
+    /// What to do when an agent fails, unless overridden by its flows
+    failure_policy: FailurePolicy,
+    /// Timeout of agents without their own timeout
+    default_timeout: Duration,
+    /// The maximum number of agents running at the same time
+    max_concurrency: Option<NonZeroUsize>,
+    /// Limits the number of agents running at the same time
+    concurrency: Option<Arc<Semaphore>>,
+    /// Where to save the checkpoint of the execution
+    checkpoint_path: Option<PathBuf>,
+    /// Whether the checkpoint is compressed
+    compress_checkpoint: bool,
+    /// Specs of the agents built from a [`WorkflowSpec`]
+    agent_specs: HashMap<String, AgentSpec>,
+    /// Subscribers of the incremental output of agents
+    output_subscribers: Vec<mpsc::UnboundedSender<NodeOutputChunk>>,
+    /// Observers of the workflow execution
+    observers: Vec<Arc<dyn WorkflowObserver>>,
 }
 
 impl DAGWorkflow {
@@ -44,24 +95,135 @@ impl DAGWorkflow {
             agents: DashMap::new(),
             workflow: StableGraph::new(),
             name_to_node: HashMap::new(),
+            allow_cycles: false,
+            back_edges: HashSet::new(),
+            failure_policy: FailurePolicy::default(),
+            default_timeout: DEFAULT_TIMEOUT,
+            max_concurrency: None,
+            concurrency: None,
+            checkpoint_path: None,
+            compress_checkpoint: false,
+            agent_specs: HashMap::new(),
+            output_subscribers: Vec::new(),
+            observers: Vec::new(),
+        }
+    }
+
+    /// Allow flows to form feedback loops, e.g. a writer and a reviewer refining a draft
+    ///
+    /// A flow which closes a loop becomes a back edge, it must have a `condition` and
+    /// `max_iterations`, otherwise [`DAGWorkflow::connect_agents`] fails with
+    /// [`GraphWorkflowError::UnboundedCycle`]. Each time a back edge is taken, its target and the
+    /// agents following it in the loop run again.
+    pub fn allow_cycles(&mut self) {
+        self.allow_cycles = true;
+    }
+
+    /// Set what to do when an agent fails, the default is [`FailurePolicy::ContinueWithSkip`]
+    ///
+    /// A flow can override the policy with [`Flow::failure_policy`].
+    pub fn set_failure_policy(&mut self, policy: FailurePolicy) {
+        self.failure_policy = policy;
+    }
+
+    /// Set the timeout of agents registered without their own timeout, the default is 60 minutes
+    pub fn set_default_timeout(&mut self, timeout: Duration) {
+        self.default_timeout = timeout;
+    }
+
+    /// Limit the number of agents running at the same time, agents wait for their turn before
+    /// their timeout starts
+    ///
+    /// To limit the requests sent to a provider, e.g. across workflows, attach a
+    /// [`RateLimiter`](crate::rate_limit::RateLimiter) to the provider instead.
+    pub fn set_max_concurrency(&mut self, max_concurrency: NonZeroUsize) {
+        self.max_concurrency = Some(max_concurrency);
+        self.concurrency = Some(Arc::new(Semaphore::new(max_concurrency.get())));
+    }
+
+    /// Save a checkpoint to the file after each agent completes, optionally compressed with zstd
+    ///
+    /// If the execution is interrupted, [`DAGWorkflow::resume_workflow`] continues from the
+    /// checkpoint without running the agents which already succeeded again.
+    pub fn enable_checkpoint(&mut self, path: impl Into<PathBuf>, compress: bool) {
+        self.checkpoint_path = Some(path.into());
+        self.compress_checkpoint = compress;
+    }
+
+    /// Add an observer which will be notified of every [`WorkflowEvent`] during the execution
+    pub fn add_observer(&mut self, observer: Arc<dyn WorkflowObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Subscribe to the [`WorkflowEvent`]s of the workflow execution through a channel
+    pub fn subscribe_events(&mut self) -> mpsc::UnboundedReceiver<WorkflowEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.add_observer(Arc::new(tx));
+        rx
+    }
+
+    /// Notify all observers of an event
+    fn emit(&self, event: WorkflowEvent) {
+        for observer in &self.observers {
+            observer.on_event(&event);
         }
     }
 
+    /// Subscribe to the incremental output of all agents in the workflow
+    ///
+    /// Once there is a subscriber, agents are run with [`Agent::run_stream`] and every chunk is
+    /// sent to the returned receiver as soon as it is produced, so the receiver should be consumed
+    /// concurrently with the workflow execution.
+    pub fn subscribe_output(&mut self) -> mpsc::UnboundedReceiver<NodeOutputChunk> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.output_subscribers.push(tx);
+        rx
+    }
+
     /// Register an agent with the orchestrator
+    ///
+    /// An agent registered under the name of another one replaces it, along with its spec.
     pub fn register_agent(&mut self, agent: Arc<dyn Agent>) {
         let agent_name = agent.name();
         self.agents.insert(agent_name.clone(), agent);
+        self.agent_specs.remove(&agent_name);
 
         // If agent isn't already in the graph, add it
         if let hash_map::Entry::Vacant(e) = self.name_to_node.entry(agent_name.clone()) {
-            let node_idx = self.workflow.add_node(AgentNode {
-                name: agent_name.clone(),
-                last_result: Mutex::new(None),
-            });
+            let node_idx = self.workflow.add_node(AgentNode::new(agent_name.clone()));
             e.insert(node_idx);
         }
     }
 
+    /// Register an agent with its own timeout, which overrides the workflow's default timeout
+    pub fn register_agent_with_timeout(&mut self, agent: Arc<dyn Agent>, timeout: Duration) {
+        let agent_name = agent.name();
+        self.register_agent(agent);
+
+        if let Some(node) = self
+            .name_to_node
+            .get(&agent_name)
+            .and_then(|node_idx| self.workflow.node_weight_mut(*node_idx))
+        {
+            node.timeout = Some(timeout);
+        }
+    }
+
+    /// Set how an agent combines the inputs of its incoming flows, see [`Aggregator`]
+    pub fn set_aggregator(
+        &mut self,
+        agent_name: &str,
+        aggregator: Aggregator,
+    ) -> Result<(), GraphWorkflowError> {
+        let node = self
+            .name_to_node
+            .get(agent_name)
+            .and_then(|node_idx| self.workflow.node_weight_mut(*node_idx))
+            .ok_or_else(|| GraphWorkflowError::AgentNotFound(agent_name.to_owned()))?;
+        node.aggregator = Some(aggregator);
+        Ok(())
+    }
+
     /// Add a flow connection between two agents
     pub fn connect_agents(
         &mut self,
@@ -83,39 +245,44 @@ impl DAGWorkflow {
 
         // Get node indices, creating nodes if necessary
         let from_entry = self.name_to_node.entry(from.to_owned());
-        let from_idx = *from_entry.or_insert_with(|| {
-            self.workflow.add_node(AgentNode {
-                name: from.to_owned(),
-                last_result: Mutex::new(None),
-            })
-        });
+        let from_idx =
+            *from_entry.or_insert_with(|| self.workflow.add_node(AgentNode::new(from.to_owned())));
 
         let to_entry = self.name_to_node.entry(to.to_owned());
-        let to_idx = *to_entry.or_insert_with(|| {
-            self.workflow.add_node(AgentNode {
-                name: to.to_owned(),
-                last_result: Mutex::new(None),
-            })
-        });
+        let to_idx =
+            *to_entry.or_insert_with(|| self.workflow.add_node(AgentNode::new(to.to_owned())));
+
+        let bounded = flow.condition.is_some() && flow.max_iterations.is_some();
 
         // Add the edge
         let edge_idx = self.workflow.add_edge(from_idx, to_idx, flow);
 
         // Check for cycles
         if self.has_cycle() {
+            if self.allow_cycles && bounded {
+                // The flow closes a feedback loop, keep it as a back edge
+                self.back_edges.insert(edge_idx);
+                return Ok(edge_idx);
+            }
+
             // Remove the edge we just added
             self.workflow.remove_edge(edge_idx);
+            if self.allow_cycles {
+                return Err(GraphWorkflowError::UnboundedCycle(format!(
+                    "flow from '{from}' to '{to}' closes a loop, it requires a condition and max_iterations"
+                )));
+            }
             return Err(GraphWorkflowError::CycleDetected);
         }
 
         Ok(edge_idx)
     }
 
-    // Check if the workflow has a cycle
+    // Check if the workflow has a cycle, back edges of feedback loops are ignored
     fn has_cycle(&self) -> bool {
         // Implementation using DFS to detect cycles
-        let mut visited = vec![false; self.workflow.node_count()];
-        let mut rec_stack = vec![false; self.workflow.node_count()];
+        let mut visited = vec![false; self.workflow.node_bound()];
+        let mut rec_stack = vec![false; self.workflow.node_bound()];
 
         for node in self.workflow.node_indices() {
             if !visited[node.index()] && self.is_cyclic_util(node, &mut visited, &mut rec_stack) {
@@ -134,7 +301,12 @@ impl DAGWorkflow {
         visited[node.index()] = true;
         rec_stack[node.index()] = true;
 
-        for neighbor in self.workflow.neighbors_directed(node, Direction::Outgoing) {
+        for neighbor in self
+            .workflow
+            .edges_directed(node, Direction::Outgoing)
+            .filter(|edge| !self.back_edges.contains(&edge.id()))
+            .map(|edge| edge.target())
+        {
             if !visited[neighbor.index()] {
                 if self.is_cyclic_util(neighbor, visited, rec_stack) {
                     return true;
@@ -160,6 +332,7 @@ impl DAGWorkflow {
         // Find and remove the edge
         if let Some(edge) = self.workflow.find_edge(*from_idx, *to_idx) {
             self.workflow.remove_edge(edge);
+            self.back_edges.remove(&edge);
             Ok(())
         } else {
             Err(GraphWorkflowError::AgentNotFound(format!(
@@ -173,6 +346,10 @@ impl DAGWorkflow {
         if let Some(node_idx) = self.name_to_node.remove(name) {
             self.workflow.remove_node(node_idx);
             self.agents.remove(name);
+            self.agent_specs.remove(name);
+            // Edge indices of removed flows may be reused
+            self.back_edges
+                .retain(|edge| self.workflow.edge_endpoints(*edge).is_some());
             Ok(())
         } else {
             Err(GraphWorkflowError::AgentNotFound(format!(
@@ -188,10 +365,22 @@ impl DAGWorkflow {
         input: String,
     ) -> Result<String, GraphWorkflowError> {
         if let Some(agent) = self.agents.get(name) {
-            agent
-                .run(input)
-                .await
-                .map_err(|e| GraphWorkflowError::AgentError(e.to_string()))
+            if self.output_subscribers.is_empty() {
+                // The node fails if the agent gives up, even with a partial output
+                return agent
+                    .run_checked(input)
+                    .await
+                    .map_err(|e| GraphWorkflowError::AgentError(e.to_string()));
+            }
+
+            let mut stream = agent.run_stream(input);
+            let mut output = String::new();
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(|e| GraphWorkflowError::AgentError(e.to_string()))?;
+                output.push_str(&chunk);
+                self.publish_output(name, chunk);
+            }
+            Ok(output)
         } else {
             Err(GraphWorkflowError::AgentNotFound(format!(
                 "Agent '{name}' not found"
@@ -199,6 +388,17 @@ impl DAGWorkflow {
         }
     }
 
+    /// Send a chunk of an agent's output to all subscribers
+    fn publish_output(&self, agent: &str, chunk: String) {
+        for subscriber in &self.output_subscribers {
+            // A dropped receiver only means the subscriber is no longer interested
+            let _ = subscriber.send(NodeOutputChunk {
+                agent: agent.to_owned(),
+                chunk: chunk.clone(),
+            });
+        }
+    }
+
     /// Execute the entire workflow starting from a specific agent
     ///
     /// # Arguments
@@ -208,20 +408,90 @@ impl DAGWorkflow {
     ///
     /// # Returns
     ///
-    /// * `Result<DashMap<String, Result<String, GraphWorkflowError>>, GraphWorkflowError>`: A map of agent names to their results
+    /// * `Result<DashMap<String, NodeOutcome>, GraphWorkflowError>`: A map of agent names to their outcomes,
+    ///   agents which were never reached (e.g. their flows' conditions were not met) are absent.
+    ///   An error is returned if an agent failed under [`FailurePolicy::FailFast`].
     ///
     pub async fn execute_workflow(
         &mut self,
         start_agents: &[&str],
         input: impl Into<String>,
-    ) -> Result<DashMap<String, Result<String, GraphWorkflowError>>, GraphWorkflowError> {
-        let input = input.into();
+    ) -> Result<DashMap<String, NodeOutcome>, GraphWorkflowError> {
+        self.execute_workflow_with_cancel(start_agents, input, CancellationToken::new())
+            .await
+    }
 
-        let start_indices = start_agents
+    /// Execute the entire workflow, which can be aborted through the cancellation token
+    ///
+    /// Once the token is canceled, the running agents are dropped, their last result is set to
+    /// [`GraphWorkflowError::Canceled`], and [`GraphWorkflowError::Canceled`] is returned.
+    pub async fn execute_workflow_with_cancel(
+        &mut self,
+        start_agents: &[&str],
+        input: impl Into<String>,
+        cancel: CancellationToken,
+    ) -> Result<DashMap<String, NodeOutcome>, GraphWorkflowError> {
+        let checkpoint = WorkflowCheckpoint::new(
+            self.name.clone(),
+            start_agents
+                .iter()
+                .map(|agent| (*agent).to_owned())
+                .collect(),
+            input.into(),
+        );
+        self.run_workflow(checkpoint, cancel).await
+    }
+
+    /// Resume the workflow from its checkpoint, see [`DAGWorkflow::enable_checkpoint`]
+    ///
+    /// The workflow is executed again with the start agents and input of the checkpoint, agents
+    /// which already succeeded are not run again, their saved outputs are used instead.
+    pub async fn resume_workflow(
+        &mut self,
+    ) -> Result<DashMap<String, NodeOutcome>, GraphWorkflowError> {
+        self.resume_workflow_with_cancel(CancellationToken::new())
+            .await
+    }
+
+    /// Resume the workflow from its checkpoint, which can be aborted through the cancellation token
+    pub async fn resume_workflow_with_cancel(
+        &mut self,
+        cancel: CancellationToken,
+    ) -> Result<DashMap<String, NodeOutcome>, GraphWorkflowError> {
+        let Some(path) = &self.checkpoint_path else {
+            return Err(GraphWorkflowError::Checkpoint(
+                "Checkpoint is not enabled".to_owned(),
+            ));
+        };
+
+        let checkpoint = WorkflowCheckpoint::load(path, self.compress_checkpoint)
+            .await
+            .map_err(|e| GraphWorkflowError::Checkpoint(e.to_string()))?;
+        if checkpoint.workflow != self.name {
+            return Err(GraphWorkflowError::Checkpoint(format!(
+                "Checkpoint belongs to workflow '{}'",
+                checkpoint.workflow
+            )));
+        }
+
+        self.run_workflow(checkpoint, cancel).await
+    }
+
+    /// Execute the workflow, replaying the outputs saved in the checkpoint
+    async fn run_workflow(
+        &mut self,
+        checkpoint: WorkflowCheckpoint,
+        cancel: CancellationToken,
+    ) -> Result<DashMap<String, NodeOutcome>, GraphWorkflowError> {
+        let input = checkpoint.input.clone();
+        let started_at = Instant::now();
+
+        let start_indices = checkpoint
+            .start_agents
             .iter()
             .map(|agent| {
                 self.name_to_node
-                    .get(*agent)
+                    .get(agent)
                     .ok_or_else(|| {
                         GraphWorkflowError::AgentNotFound(format!(
                             "Start agent '{agent}' not found"
@@ -235,275 +505,476 @@ impl DAGWorkflow {
         let node_idxs = self.workflow.node_indices().collect::<Vec<_>>();
         for idx in node_idxs {
             if let Some(node_weight) = self.workflow.node_weight_mut(idx) {
-                let mut last_result = node_weight.last_result.lock().await;
-                *last_result = None;
+                *node_weight.last_result.get_mut() = None;
+                node_weight.iteration_results.get_mut().clear();
             }
         }
 
-        // Create a shared results map for all agents to write to
-        let results = Arc::new(DashMap::new());
-        // Create a shared tracking state for the entire workflow
-        let edge_tracker = Arc::new(DashMap::new());
-        let processed_nodes = Arc::new(DashMap::new());
-        // Execute the workflow
-        let mut tasks = Vec::new();
+        let mut state = ExecutionState::new(self.feedback_loops(), checkpoint);
+        let mut running = FuturesUnordered::new();
+        let mut in_flight = Vec::new();
         for &start_idx in &start_indices {
-            let task = self.execute_node(
-                start_idx,
-                input.clone(),
-                Arc::clone(&results),
-                Arc::clone(&edge_tracker),
-                Arc::clone(&processed_nodes),
-            );
-            tasks.push(task);
+            state
+                .inputs
+                .entry(start_idx)
+                .or_default()
+                .push((None, input.clone()));
+            state.runs.insert(start_idx, 0);
+            in_flight.push(start_idx);
+            let saved = self.saved_output(start_idx, &state);
+            running.push(self.execute_node(start_idx, input.clone(), saved));
+        }
+
+        let mut error = None;
+        loop {
+            let next = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => {
+                    error = Some(GraphWorkflowError::Canceled);
+                    break;
+                }
+                next = running.next() => next,
+            };
+            let Some((node_idx, result)) = next else {
+                break;
+            };
+
+            let run = state.runs.entry(node_idx).or_default();
+            *run += 1;
+            let run = *run as usize;
+            if let Some(pos) = in_flight.iter().position(|idx| *idx == node_idx) {
+                in_flight.swap_remove(pos);
+            }
+
+            // Save the progress, outputs replayed from the checkpoint are already saved
+            if let Ok(output) = &result {
+                let outputs = state
+                    .checkpoint
+                    .outputs
+                    .entry(self.node_name(node_idx))
+                    .or_default();
+                if outputs.len() < run {
+                    outputs.push(output.clone());
+                    self.save_checkpoint(&mut state.checkpoint).await;
+                }
+            }
+
+            if let Some(node) = self.workflow.node_weight(node_idx) {
+                state
+                    .results
+                    .insert(node.name.clone(), NodeOutcome::from(result.clone()));
+                *node.last_result.lock().await = Some(result.clone());
+                node.iteration_results.lock().await.push(result.clone());
+            }
+
+            if let Err(e) = &result
+                && self
+                    .failure_policies(node_idx)
+                    .contains(&FailurePolicy::FailFast)
+            {
+                error = Some(GraphWorkflowError::ExecutionError(e.to_string()));
+                break;
+            }
+
+            for (next_idx, next_input) in self.propagate(node_idx, &result, &mut state) {
+                in_flight.push(next_idx);
+                let saved = self.saved_output(next_idx, &state);
+                running.push(self.execute_node(next_idx, next_input, saved));
+            }
+        }
+
+        // Dropping the futures cancels the agents which are still running
+        drop(running);
+        for node_idx in in_flight {
+            let agent = self.node_name(node_idx);
+            state.results.insert(agent.clone(), NodeOutcome::Canceled);
+            if let Some(node) = self.workflow.node_weight(node_idx) {
+                *node.last_result.lock().await = Some(Err(GraphWorkflowError::Canceled));
+            }
+            self.emit(WorkflowEvent::NodeCanceled {
+                agent,
+                timestamp: Local::now(),
+            });
+        }
+
+        self.emit(WorkflowEvent::WorkflowFinished {
+            workflow: self.name.clone(),
+            timestamp: Local::now(),
+            duration: started_at.elapsed(),
+        });
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(state.results),
+        }
+    }
+
+    /// The output saved in the checkpoint for the next run of a node, if any
+    fn saved_output(&self, node_idx: NodeIndex, state: &ExecutionState) -> Option<String> {
+        let run = state.runs.get(&node_idx).copied().unwrap_or_default() as usize;
+        state
+            .checkpoint
+            .outputs
+            .get(&self.node_name(node_idx))
+            .and_then(|outputs| outputs.get(run))
+            .cloned()
+    }
+
+    /// Save the checkpoint if enabled, a failure to save doesn't stop the execution
+    async fn save_checkpoint(&self, checkpoint: &mut WorkflowCheckpoint) {
+        if let Some(path) = &self.checkpoint_path {
+            checkpoint.timestamp = Local::now();
+            if let Err(e) = checkpoint.save(path, self.compress_checkpoint).await {
+                tracing::error!("Failed to save workflow checkpoint: {}", e);
+            }
         }
-        futures::future::join_all(tasks)
-            .await
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| GraphWorkflowError::ExecutionError(e.to_string()))?;
-        Ok(Arc::into_inner(results).expect("Results should not be poisoned"))
     }
 
+    /// Run the agent of a node with timeout protection, or restore its output saved in the checkpoint
     async fn execute_node(
         &self,
         node_idx: NodeIndex,
         input: String,
-        results: Arc<DashMap<String, Result<String, GraphWorkflowError>>>,
-        edge_tracker: Arc<DashMap<(NodeIndex, NodeIndex), bool>>,
-        processed_nodes: Arc<DashMap<NodeIndex, Vec<(NodeIndex, String)>>>,
-    ) -> Result<String, GraphWorkflowError> {
-        // Get the agent name from the node
-        let agent_name = &self
+        saved: Option<String>,
+    ) -> (NodeIndex, Result<String, GraphWorkflowError>) {
+        let agent_name = self.node_name(node_idx);
+
+        if let Some(output) = saved {
+            self.emit(WorkflowEvent::NodeRestored {
+                agent: agent_name,
+                timestamp: Local::now(),
+            });
+            return (node_idx, Ok(output));
+        }
+        let timeout = self
             .workflow
             .node_weight(node_idx)
-            .ok_or_else(|| GraphWorkflowError::AgentNotFound("Node not found in graph".to_owned()))?
-            .name;
+            .and_then(|node| node.timeout)
+            .unwrap_or(self.default_timeout);
+
+        let _permit = match &self.concurrency {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire()
+                    .await
+                    .expect("the semaphore is never closed"),
+            ),
+            None => None,
+        };
+        self.emit(WorkflowEvent::NodeStarted {
+            agent: agent_name.clone(),
+            timestamp: Local::now(),
+        });
+        let started_at = Instant::now();
+
+        let result = tokio::time::timeout(timeout, self.execute_agent(&agent_name, input))
+            .await
+            .unwrap_or_else(|_| Err(GraphWorkflowError::Timeout(agent_name.clone())));
 
-        // Check if we already have a result for this node (avoid duplicate work)
-        if let Some(entry) = results.get(agent_name) {
-            return entry.value().clone();
+        match &result {
+            Ok(_) => self.emit(WorkflowEvent::NodeFinished {
+                agent: agent_name,
+                timestamp: Local::now(),
+                duration: started_at.elapsed(),
+            }),
+            Err(e) => {
+                tracing::error!("Agent '{}' execution failed: {:?}", agent_name, e);
+                self.emit(WorkflowEvent::NodeFailed {
+                    agent: agent_name,
+                    error: e.to_string(),
+                    timestamp: Local::now(),
+                    duration: started_at.elapsed(),
+                });
+            }
         }
 
-        // Execute the agent with timeout protection
-        let result = tokio::time::timeout(
-            Duration::from_secs(3600), // 60-minute timeout
-            self.execute_agent(agent_name, input),
-        )
-        .await
-        .map_err(|_| GraphWorkflowError::Timeout(agent_name.clone()))?;
+        (node_idx, result)
+    }
 
-        // Store the result
-        results.insert(agent_name.clone(), result.clone());
+    /// The failure policies which apply when an agent fails, one per outgoing flow, or the
+    /// workflow's policy if the agent has no outgoing flows. Error flows are not considered.
+    fn failure_policies(&self, node_idx: NodeIndex) -> Vec<FailurePolicy> {
+        let policies = self
+            .workflow
+            .edges_directed(node_idx, Direction::Outgoing)
+            .filter(|edge| !edge.weight().on_error)
+            .map(|edge| edge.weight().failure_policy.unwrap_or(self.failure_policy))
+            .collect::<Vec<_>>();
 
-        // Update the node's last result
-        if let Some(node_weight) = self.workflow.node_weight(node_idx) {
-            let mut last_result = node_weight.last_result.lock().await;
-            *last_result = Some(result.clone());
+        if policies.is_empty() {
+            vec![self.failure_policy]
+        } else {
+            policies
         }
+    }
 
-        // If successful, propagate to connected agents
-        match &result {
-            Ok(output) => {
-                // Find all outgoing edges that pass the condition (if any)
-                let valid_edges = self
-                    .workflow
-                    .edges_directed(node_idx, Direction::Outgoing)
-                    .filter(|edge| {
-                        // Evaluate condition with the current output
-                        let condition_result = edge
-                            .weight()
-                            .condition
-                            .as_ref()
-                            .map(|cond| {
-                                // Apply condition to the current output
-                                let result = cond(output);
-                                tracing::debug!(
-                                    "Condition for edge {:?} -> {:?}: {}",
-                                    node_idx,
-                                    edge.target(),
-                                    result
-                                );
-                                result
-                            })
-                            .unwrap_or(true); // if no condition, always execute
-
-                        condition_result
-                    })
-                    .collect::<Vec<_>>();
+    /// Pass the result of a node along its outgoing flows, agents downstream of a failed or
+    /// skipped agent are skipped.
+    ///
+    /// Returns the nodes which became ready to run, with their aggregated inputs.
+    fn propagate(
+        &self,
+        node_idx: NodeIndex,
+        result: &Result<String, GraphWorkflowError>,
+        state: &mut ExecutionState,
+    ) -> Vec<(NodeIndex, String)> {
+        let mut ready = Vec::new();
+        let mut pending = vec![(node_idx, result.clone().map_err(|e| (true, e.to_string())))];
+
+        // `Err((true, error))` for a failed agent, `Err((false, reason))` for a skipped agent
+        while let Some((node_idx, result)) = pending.pop() {
+            let from = self.node_name(node_idx);
+            let route_to_fallback = matches!(result, Err((true, _)))
+                && self
+                    .failure_policies(node_idx)
+                    .contains(&FailurePolicy::RouteToFallback);
+            let skip_reason = match &result {
+                Ok(_) => None,
+                Err((true, error)) => Some(format!("Agent '{from}' failed: {error}")),
+                Err((false, reason)) => Some(reason.clone()),
+            };
+
+            for edge in self.workflow.edges_directed(node_idx, Direction::Outgoing) {
+                let flow = edge.weight();
+                let target_idx = edge.target();
+                let to = self.node_name(target_idx);
+                let is_back_edge = self.back_edges.contains(&edge.id());
+
+                // Regular flows carry the output of a successful agent, error flows carry the
+                // error of a failed agent routed to its fallback
+                let payload = match &result {
+                    Ok(output) if !flow.on_error => Some(output.clone()),
+                    Err((true, error)) if flow.on_error && route_to_fallback => Some(error.clone()),
+                    _ => None,
+                };
+
+                let mut taken = false;
+                if let Some(payload) = &payload {
+                    // Evaluate condition with the current output, if no condition, always take the flow
+                    taken = flow
+                        .condition
+                        .as_ref()
+                        .is_none_or(|cond| cond.evaluate(payload));
+                    tracing::debug!(
+                        "Condition for edge {:?} -> {:?}: {}",
+                        node_idx,
+                        target_idx,
+                        taken
+                    );
+
+                    if taken && is_back_edge {
+                        let iterations = state.iterations.entry(edge.id()).or_default();
+                        if *iterations < flow.max_iterations.unwrap_or_default() {
+                            *iterations += 1;
+                        } else {
+                            taken = false;
+                            self.emit(WorkflowEvent::IterationLimitReached {
+                                from: from.clone(),
+                                to: to.clone(),
+                                iterations: *iterations,
+                                timestamp: Local::now(),
+                            });
+                        }
+                        if taken {
+                            self.emit(WorkflowEvent::EdgeTaken {
+                                from: from.clone(),
+                                to: to.clone(),
+                                timestamp: Local::now(),
+                            });
+                        }
+                    } else {
+                        self.emit(if taken {
+                            WorkflowEvent::EdgeTaken {
+                                from: from.clone(),
+                                to: to.clone(),
+                                timestamp: Local::now(),
+                            }
+                        } else {
+                            WorkflowEvent::EdgeSkippedByCondition {
+                                from: from.clone(),
+                                to: to.clone(),
+                                timestamp: Local::now(),
+                            }
+                        });
+                    }
+                }
 
-                let mut futures = Vec::new();
+                if let Some(payload) = payload
+                    && taken
+                {
+                    // Apply transformation if any
+                    let next_input = flow
+                        .transform
+                        .as_ref()
+                        .map_or(payload.clone(), |transform| transform.apply(payload));
+
+                    // A flow replaces the input it delivered in a previous iteration
+                    let inputs = state.inputs.entry(target_idx).or_default();
+                    inputs.retain(|(input_edge, _)| *input_edge != Some(edge.id()));
+                    inputs.push((Some(edge.id()), next_input));
+                    state.fresh.insert(target_idx);
+                } else if !flow.on_error
+                    && let Some(reason) = &skip_reason
+                {
+                    state
+                        .skip_reasons
+                        .entry(target_idx)
+                        .or_insert_with(|| reason.clone());
+                }
 
-                for edge in valid_edges {
-                    let source_node = node_idx;
-                    let target_node = edge.target();
-                    let flow = edge.weight().clone();
-                    let results_clone = Arc::clone(&results);
-                    let processed_nodes_clone = Arc::clone(&processed_nodes);
-                    let edge_tracker_clone = Arc::clone(&edge_tracker);
+                if !is_back_edge {
+                    state.edge_tracker.insert(edge.id(), taken);
+                }
 
-                    let future = async move {
-                        // Apply transformation if any
-                        let next_input = flow
-                            .transform
-                            .as_ref()
-                            .map_or_else(|| output.clone(), |transform| transform(output.clone()));
-
-                        // mark this edge as processed
-                        edge_tracker_clone.insert((source_node, target_node), true);
-
-                        // record the input for this node with proper synchronization
-                        // Use a scope to ensure the lock is released after the operation
-                        {
-                            processed_nodes_clone
-                                .entry(target_node)
-                                .and_modify(|v| v.push((source_node, next_input.clone())))
-                                .or_insert_with(|| vec![(source_node, next_input.clone())]);
-                        }
+                let resolved = self
+                    .workflow
+                    .edges_directed(target_idx, Direction::Incoming)
+                    .filter(|incoming| !self.back_edges.contains(&incoming.id()))
+                    .all(|incoming| state.edge_tracker.contains_key(&incoming.id()));
+                let schedulable =
+                    state.loops.contains_key(&target_idx) || !state.runs.contains_key(&target_idx);
+
+                // A target downstream of a failure is skipped once all its incoming flows have
+                // been resolved, the skip is then passed along its own flows
+                if resolved
+                    && !state.runs.contains_key(&target_idx)
+                    && let Some(reason) = state.skip_reasons.get(&target_idx).cloned()
+                {
+                    state.runs.insert(target_idx, 0);
+                    state.fresh.remove(&target_idx);
+                    state
+                        .results
+                        .insert(to.clone(), NodeOutcome::Skipped(reason.clone()));
+                    self.emit(WorkflowEvent::NodeSkipped {
+                        agent: to,
+                        reason: reason.clone(),
+                        timestamp: Local::now(),
+                    });
+                    pending.push((target_idx, Err((false, reason))));
+                    continue;
+                }
 
-                        // Get all input edges (including those from different starting nodes)
-                        let all_incoming_edges = self
-                            .workflow
-                            .edges_directed(target_node, Direction::Incoming)
-                            .map(|e| (e.source(), target_node))
-                            .collect::<Vec<_>>();
-
-                        // Check that all input edges have completed processing (from different paths).
-                        // For conditional flows, we need to check if the edge has a condition and if it evaluates to false
-                        let all_processed = all_incoming_edges.iter().all(|edge| {
-                            // Check if this edge is already processed
-                            let processed = edge_tracker_clone.contains_key(edge);
-
-                            // If not processed, check if it has a condition that evaluates to false
-                            // In that case, we should consider it as "processed" (skipped)
-                            let conditionally_skipped = if !processed {
-                                if let Some(edge_idx) = self.workflow.find_edge(edge.0, edge.1) {
-                                    let edge_weight = self.workflow.edge_weight(edge_idx).unwrap();
-                                    if let Some(cond) = &edge_weight.condition {
-                                        // If we can find the source node's result, check the condition
-                                        if let Some(source_name) =
-                                            self.workflow.node_weight(edge.0).map(|n| &n.name)
-                                        {
-                                            if let Some(source_result) =
-                                                results_clone.get(source_name)
-                                            {
-                                                if let Ok(output) = source_result.as_ref() {
-                                                    // If condition is false, this edge is conditionally skipped
-                                                    let condition_result = !cond(output);
-                                                    if condition_result {
-                                                        // Mark this edge as processed (skipped due to condition)
-                                                        edge_tracker_clone
-                                                            .insert((edge.0, edge.1), true);
-                                                    }
-                                                    condition_result
-                                                } else {
-                                                    // Source node execution failed, consider edge as processed
-                                                    edge_tracker_clone
-                                                        .insert((edge.0, edge.1), true);
-                                                    true
-                                                }
-                                            } else {
-                                                false
-                                            }
-                                        } else {
-                                            false
-                                        }
-                                    } else {
-                                        false
-                                    }
-                                } else {
-                                    false
-                                }
-                            } else {
-                                false
-                            };
-
-                            tracing::debug!(
-                                "Edge {:?} processed: {}, conditionally skipped: {}",
-                                edge,
-                                processed,
-                                conditionally_skipped
-                            );
-                            processed || conditionally_skipped
-                        });
+                // A taken back edge starts a new iteration of the target right away, as does the
+                // first input of a target which only needs one, otherwise the target waits until
+                // all incoming flows have been resolved
+                let first_arrived = self
+                    .workflow
+                    .node_weight(target_idx)
+                    .is_some_and(|node| matches!(node.aggregator, Some(Aggregator::FirstArrived)));
+                let can_run = state.fresh.contains(&target_idx)
+                    && schedulable
+                    && ((taken && (is_back_edge || first_arrived)) || resolved);
+
+                if can_run {
+                    ready.push((target_idx, self.take_inputs(target_idx, state)));
+                }
+            }
+        }
 
-                        // only execute if all incoming edges have been processed
-                        if all_processed {
-                            // Aggregate all inputs from different paths
-                            let aggregated_input = processed_nodes_clone
-                                .get(&target_node)
-                                .map(|inputs| {
-                                    // Sort inputs by source node to ensure consistent ordering
-                                    let mut sorted_inputs = inputs.value().clone();
-                                    sorted_inputs.sort_by_key(|(source_idx, _)| *source_idx);
-
-                                    // Log the number of inputs for debugging
-                                    tracing::debug!(
-                                        "Node {:?} has {} inputs",
-                                        target_node,
-                                        sorted_inputs.len()
-                                    );
-
-                                    // Format each input with its source agent name
-                                    let formatted_inputs = sorted_inputs
-                                        .iter()
-                                        .map(|(source_idx, input)| {
-                                            let source_name = &self
-                                                .workflow
-                                                .node_weight(*source_idx)
-                                                .unwrap()
-                                                .name;
-                                            format!("[From {source_name}] {input}")
-                                        })
-                                        .collect::<Vec<_>>();
-
-                                    // Join all inputs with a clear separator
-                                    let result = formatted_inputs.join("\n\n---\n\n");
-                                    tracing::debug!(
-                                        "Aggregated input for node {:?}: {}",
-                                        target_node,
-                                        result
-                                    );
-                                    result
-                                })
-                                .unwrap_or_default();
-
-                            tracing::debug!(
-                                "Executing node {:?} with aggregated input",
-                                target_node
-                            );
-
-                            // execute the target node with the aggregated input
-                            if let Err(e) = self
-                                .execute_node(
-                                    target_node,
-                                    aggregated_input,
-                                    results_clone,
-                                    edge_tracker_clone,
-                                    processed_nodes_clone,
-                                )
-                                .await
-                            {
-                                tracing::error!("Failed to execute node: {:?}", e);
-                            }
-                        }
-                    };
+        ready
+    }
 
-                    futures.push(future);
+    /// Aggregate the inputs of a node which is about to run, and reset the state of the flows
+    /// which will be resolved again in the next iteration.
+    fn take_inputs(&self, node_idx: NodeIndex, state: &mut ExecutionState) -> String {
+        state.fresh.remove(&node_idx);
+        // Mark the node as scheduled, so that nodes outside of feedback loops only run once
+        state.runs.entry(node_idx).or_default();
+
+        let mut inputs = state.inputs.get(&node_idx).cloned().unwrap_or_default();
+
+        // Inputs from the same feedback loop belong to the current iteration only, inputs from
+        // outside of the loop are kept for the following iterations
+        if state.loops.contains_key(&node_idx) {
+            let scc = state.loops.get(&node_idx).copied();
+            let mut reset_edges = Vec::new();
+            for incoming in self.workflow.edges_directed(node_idx, Direction::Incoming) {
+                if self.back_edges.contains(&incoming.id())
+                    || state.loops.get(&incoming.source()).copied() == scc
+                {
+                    reset_edges.push(incoming.id());
                 }
-
-                // Execute connected agents concurrently
-                futures::future::join_all(futures).await; // TODO: may use another way which can handle errors
             }
-            Err(e) => {
-                tracing::error!("Agent '{}' execution failed: {:?}", agent_name, e);
-                // TODO: maybe we need to propagate the error to the caller?
+            for edge in &reset_edges {
+                state.edge_tracker.remove(edge);
+            }
+            if let Some(node_inputs) = state.inputs.get_mut(&node_idx) {
+                node_inputs
+                    .retain(|(edge, _)| edge.is_none_or(|edge| !reset_edges.contains(&edge)));
             }
         }
 
+        let default_aggregator = Aggregator::default();
+        let aggregator = self
+            .workflow
+            .node_weight(node_idx)
+            .and_then(|node| node.aggregator.as_ref())
+            .unwrap_or(&default_aggregator);
+
+        // Sort inputs by source node to ensure consistent ordering, the workflow input goes first,
+        // unless the first arriving input wins
+        if !matches!(aggregator, Aggregator::FirstArrived) {
+            inputs.sort_by_key(|(edge, _)| {
+                edge.and_then(|edge| self.workflow.edge_endpoints(edge))
+                    .map(|(source, _)| source)
+            });
+        }
+
+        // Log the number of inputs for debugging
+        tracing::debug!("Node {:?} has {} inputs", node_idx, inputs.len());
+
+        let inputs = inputs
+            .into_iter()
+            .map(|(edge, input)| NodeInput {
+                agent: edge
+                    .and_then(|edge| self.workflow.edge_endpoints(edge))
+                    .map(|(source, _)| self.node_name(source)),
+                input,
+            })
+            .collect::<Vec<_>>();
+        let result = aggregator.aggregate(&inputs);
+
+        tracing::debug!("Aggregated input for node {:?}: {}", node_idx, result);
         result
     }
 
+    /// Map the nodes which belong to a feedback loop to the index of their loop
+    fn feedback_loops(&self) -> HashMap<NodeIndex, usize> {
+        let mut loops = HashMap::new();
+        for (scc_idx, scc) in petgraph::algo::kosaraju_scc(&self.workflow)
+            .into_iter()
+            .enumerate()
+        {
+            for node_idx in scc.iter().copied() {
+                let self_loop = self.workflow.find_edge(node_idx, node_idx).is_some();
+                if scc.len() > 1 || self_loop {
+                    loops.insert(node_idx, scc_idx);
+                }
+            }
+        }
+        loops
+    }
+
+    /// Get the results of every iteration of an agent in the last execution
+    ///
+    /// Agents outside of feedback loops have at most one result.
+    pub async fn get_iteration_results(
+        &self,
+        name: &str,
+    ) -> Option<Vec<Result<String, GraphWorkflowError>>> {
+        let node_idx = self.name_to_node.get(name)?;
+        let node = self.workflow.node_weight(*node_idx)?;
+        Some(node.iteration_results.lock().await.clone())
+    }
+
+    /// Get the agent name of a node, empty if the node doesn't exist
+    fn node_name(&self, node_idx: NodeIndex) -> String {
+        self.workflow
+            .node_weight(node_idx)
+            .map(|node| node.name.clone())
+            .unwrap_or_default()
+    }
+
     /// Get the current workflow as a visualization-friendly format
     pub fn get_workflow_structure(&self) -> HashMap<String, Vec<(String, Option<String>)>> {
         let mut structure = HashMap::new();
@@ -515,11 +986,11 @@ impl DAGWorkflow {
                 for edge in self.workflow.edges_directed(node_idx, Direction::Outgoing) {
                     if let Some(target) = self.workflow.node_weight(edge.target()) {
                         // TODO: can add more edge metadata here if needed
-                        let edge_label = if edge.weight().transform.is_some() {
-                            Some("transform".to_owned())
-                        } else {
-                            None
-                        };
+                        let edge_label = edge
+                            .weight()
+                            .transform
+                            .as_ref()
+                            .map(|transform| transform.to_string());
 
                         connections.push((target.name.clone(), edge_label));
                     }
@@ -549,16 +1020,41 @@ impl DAGWorkflow {
             }
         }
 
-        // Add edges
+        // Add edges labeled with their conditions and transforms, back edges of feedback loops are dashed
         for edge in self.workflow.edge_indices() {
             if let Some((source, target)) = self.workflow.edge_endpoints(edge) {
-                if let (Some(source_node), Some(target_node)) = (
+                if let (Some(source_node), Some(target_node), Some(flow)) = (
                     self.workflow.node_weight(source),
                     self.workflow.node_weight(target),
+                    self.workflow.edge_weight(edge),
                 ) {
+                    let mut attributes = Vec::new();
+                    let label = flow
+                        .condition
+                        .iter()
+                        .map(|condition| format!("if {condition}"))
+                        .chain(flow.transform.iter().map(ToString::to_string))
+                        .map(|line| {
+                            line.replace('\\', "\\\\")
+                                .replace('"', "\\\"")
+                                .replace('\n', "\\n")
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\\n");
+                    if !label.is_empty() {
+                        attributes.push(format!("label=\"{label}\""));
+                    }
+                    if self.back_edges.contains(&edge) {
+                        attributes.push("style=dashed".to_owned());
+                    }
+                    let attributes = if attributes.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" [{}]", attributes.join(", "))
+                    };
                     dot.push_str(&format!(
-                        "    \"{}\" -> \"{}\";\n",
-                        source_node.name, target_node.name
+                        "    \"{}\" -> \"{}\"{};\n",
+                        source_node.name, target_node.name, attributes
                     ));
                 }
             }
@@ -608,22 +1104,21 @@ impl DAGWorkflow {
             // Add current node to path
             current_path.push(node.name.clone());
 
-            // Check if this is a leaf node (no outgoing edges)
-            let has_outgoing = self
+            // Back edges of feedback loops are not followed, otherwise the paths would be endless
+            let neighbors = self
                 .workflow
-                .neighbors_directed(node_idx, Direction::Outgoing)
-                .count()
-                > 0;
+                .edges_directed(node_idx, Direction::Outgoing)
+                .filter(|edge| !self.back_edges.contains(&edge.id()))
+                .map(|edge| edge.target())
+                .collect::<Vec<_>>();
 
-            if !has_outgoing {
+            // Check if this is a leaf node (no outgoing edges)
+            if neighbors.is_empty() {
                 // We've reached a leaf node, save this path
                 all_paths.push(current_path.clone());
             } else {
                 // Continue DFS for all neighbors
-                for neighbor in self
-                    .workflow
-                    .neighbors_directed(node_idx, Direction::Outgoing)
-                {
+                for neighbor in neighbors {
                     self.dfs_paths(neighbor, current_path, all_paths);
                 }
             }
@@ -660,10 +1155,12 @@ impl DAGWorkflow {
             if let Some(node) = self.workflow.node_weight(node_idx) {
                 let target_dep_idx = *node_map.get(&node.name).unwrap();
 
-                // Add an edge for each incoming connection
+                // Add an edge for each incoming connection, bounded feedback loops can't deadlock
                 for source in self
                     .workflow
-                    .neighbors_directed(node_idx, Direction::Incoming)
+                    .edges_directed(node_idx, Direction::Incoming)
+                    .filter(|edge| !self.back_edges.contains(&edge.id()))
+                    .map(|edge| edge.source())
                 {
                     if let Some(source_node) = self.workflow.node_weight(source) {
                         let source_dep_idx = *node_map.get(&source_node.name).unwrap();
@@ -689,13 +1186,80 @@ impl DAGWorkflow {
 }
 
 /// Edge weight to represent the flow of data between agents
-#[allow(clippy::type_complexity)]
-#[derive(Clone, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct Flow {
-    /// Optional transformation function to apply to the output before passing to the next agent
-    pub transform: Option<Arc<dyn Fn(String) -> String + Send + Sync>>,
+    /// Optional transformation to apply to the output before passing to the next agent
+    pub transform: Option<Transform>,
     /// Optional condition to determine if this flow should be taken
-    pub condition: Option<Arc<dyn Fn(&str) -> bool + Send + Sync>>,
+    pub condition: Option<Condition>,
+    /// Maximum number of times the flow can be taken if it closes a feedback loop
+    pub max_iterations: Option<u32>,
+    /// Overrides the workflow's [`FailurePolicy`] when the source agent fails
+    pub failure_policy: Option<FailurePolicy>,
+    /// Whether this is an error flow, which is only taken under [`FailurePolicy::RouteToFallback`]
+    /// when the source agent fails, the error message is passed to the target agent
+    pub on_error: bool,
+}
+
+/// What to do when an agent fails during the workflow execution
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Cancel all running agents and return the error
+    FailFast,
+    /// Skip all agents downstream of the failed agent
+    #[default]
+    ContinueWithSkip,
+    /// Follow the error flows of the failed agent to its fallback agents, and skip all agents
+    /// downstream of its regular flows
+    RouteToFallback,
+}
+
+/// The outcome of an agent in a workflow execution
+#[derive(Clone, Debug)]
+pub enum NodeOutcome {
+    /// The agent finished with the output
+    Succeeded(String),
+    /// The agent failed
+    Failed(GraphWorkflowError),
+    /// The agent did not run because an upstream agent failed, with the reason
+    Skipped(String),
+    /// The agent was canceled while running
+    Canceled,
+}
+
+impl NodeOutcome {
+    /// Get the output of a succeeded agent
+    pub fn output(&self) -> Option<&str> {
+        match self {
+            NodeOutcome::Succeeded(output) => Some(output),
+            _ => None,
+        }
+    }
+
+    /// Whether the agent finished successfully
+    pub fn is_succeeded(&self) -> bool {
+        matches!(self, NodeOutcome::Succeeded(_))
+    }
+}
+
+impl From<Result<String, GraphWorkflowError>> for NodeOutcome {
+    fn from(result: Result<String, GraphWorkflowError>) -> Self {
+        match result {
+            Ok(output) => NodeOutcome::Succeeded(output),
+            Err(GraphWorkflowError::Canceled) => NodeOutcome::Canceled,
+            Err(e) => NodeOutcome::Failed(e),
+        }
+    }
+}
+
+/// A chunk of output produced by an agent during the workflow execution
+#[derive(Clone, Debug)]
+pub struct NodeOutputChunk {
+    /// Name of the agent which produced the chunk
+    pub agent: String,
+    /// The incremental output
+    pub chunk: String,
 }
 
 /// Node weight for the graph
@@ -705,6 +1269,62 @@ pub struct AgentNode {
     pub name: String,
     /// Cache for execution results
     pub last_result: Mutex<Option<Result<String, GraphWorkflowError>>>,
+    /// Results of every iteration in the last execution
+    pub iteration_results: Mutex<Vec<Result<String, GraphWorkflowError>>>,
+    /// Timeout of the agent, the workflow's default timeout is used if not set
+    pub timeout: Option<Duration>,
+    /// How the inputs of the agent are combined, the default [`Aggregator`] is used if not set
+    pub aggregator: Option<Aggregator>,
+}
+
+impl AgentNode {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            last_result: Mutex::new(None),
+            iteration_results: Mutex::new(Vec::new()),
+            timeout: None,
+            aggregator: None,
+        }
+    }
+}
+
+/// The state of a single workflow execution
+struct ExecutionState {
+    /// The latest outcome of each agent
+    results: DashMap<String, NodeOutcome>,
+    /// Resolved flows, `true` if the flow was taken and `false` if it was skipped
+    edge_tracker: HashMap<EdgeIndex, bool>,
+    /// Inputs delivered to each node, with the flow they came from, `None` for the workflow input
+    inputs: HashMap<NodeIndex, Vec<(Option<EdgeIndex>, String)>>,
+    /// Nodes which received new inputs since their last run
+    fresh: HashSet<NodeIndex>,
+    /// How many times each node has been scheduled or completed
+    runs: HashMap<NodeIndex, u32>,
+    /// How many times each back edge has been taken
+    iterations: HashMap<EdgeIndex, u32>,
+    /// Nodes downstream of a failed or skipped agent, with the reason to skip them
+    skip_reasons: HashMap<NodeIndex, String>,
+    /// Nodes which belong to a feedback loop, mapped to the index of their loop
+    loops: HashMap<NodeIndex, usize>,
+    /// The progress of the execution
+    checkpoint: WorkflowCheckpoint,
+}
+
+impl ExecutionState {
+    fn new(loops: HashMap<NodeIndex, usize>, checkpoint: WorkflowCheckpoint) -> Self {
+        Self {
+            results: DashMap::new(),
+            edge_tracker: HashMap::new(),
+            inputs: HashMap::new(),
+            fresh: HashSet::new(),
+            runs: HashMap::new(),
+            iterations: HashMap::new(),
+            skip_reasons: HashMap::new(),
+            loops,
+            checkpoint,
+        }
+    }
 }
 
 /// Error type for the graph workflow
@@ -717,6 +1337,8 @@ pub enum GraphWorkflowError {
     AgentNotFound(String),
     #[error("Cycle detected in workflow")]
     CycleDetected,
+    #[error("Unbounded cycle: {0}")]
+    UnboundedCycle(String),
     #[error("Execution error: {0}")]
     ExecutionError(String),
     #[error("Timeout executing agent: {0}")]
@@ -725,48 +1347,26 @@ pub enum GraphWorkflowError {
     Deadlock,
     #[error("Workflow execution canceled")]
     Canceled,
-}
-
-impl Debug for Flow {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Flow")
-            .field("transform", &self.transform.is_some())
-            .field("condition", &self.condition.is_some())
-            .finish()
-    }
+    #[error("Checkpoint error: {0}")]
+    Checkpoint(String),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use futures::future::{self, BoxFuture};
-    use mockall::mock;
+    use futures::{
+        future::{self, BoxFuture},
+        stream::{self, BoxStream},
+    };
 
-    use crate::agent::AgentError;
+    use tokio::net::TcpListener;
 
-    mock! {
-        #[derive(Debug)]
-        pub Agent{}
-
-        impl Agent for Agent {
-            fn run(&self, task: String) -> BoxFuture<'static, Result<String, AgentError>> {
-                Box::pin(future::ready(Ok(String::new())))
-            }
-            fn run_multiple_tasks(&mut self, tasks: Vec<String>) -> BoxFuture<'static, Result<Vec<String>, AgentError>> {
-                Box::pin(future::ready(Ok(vec![])))
-            }
-            fn id(&self) -> String {
-                String::new()
-            }
-            fn name(&self) -> String {
-                String::new()
-            }
-            fn description(&self) -> String {
-                String::new()
-            }
-        }
-    }
+    use crate::{
+        agent::{AgentError, tests::MockAgent},
+        llm_provider::{LLMProvider, tests::serve_response},
+        rig_agent::RigAgent,
+    };
 
     fn create_mock_agent(id: &str, name: &str, desc: &str, response: &str) -> Arc<MockAgent> {
         let mut agent = MockAgent::new();
@@ -796,16 +1396,89 @@ mod tests {
         Arc::new(agent)
     }
 
-    fn create_failing_agent(id: &str, name: &str, error_msg: &str) -> Arc<MockAgent> {
-        let mut agent = MockAgent::new();
+    /// An agent which streams the given chunks, and fails after them if `error` is set
+    struct StreamingAgent {
+        name: String,
+        chunks: Vec<String>,
+        error: Option<String>,
+    }
 
-        let id_str = id.to_owned();
-        agent.expect_id().return_const(id_str);
+    impl StreamingAgent {
+        fn new(name: &str, chunks: &[&str], error: Option<&str>) -> Arc<Self> {
+            Arc::new(Self {
+                name: name.to_owned(),
+                chunks: chunks.iter().map(|chunk| (*chunk).to_owned()).collect(),
+                error: error.map(ToOwned::to_owned),
+            })
+        }
+    }
 
-        let name_str = name.to_owned();
-        agent.expect_name().return_const(name_str);
+    impl Agent for StreamingAgent {
+        fn run(&self, _task: String) -> BoxFuture<'_, Result<String, AgentError>> {
+            let result = match &self.error {
+                Some(error) => Err(AgentError::TestError(error.clone())),
+                None => Ok(self.chunks.concat()),
+            };
+            Box::pin(future::ready(result))
+        }
 
-        agent
+        fn run_stream(&self, _task: String) -> BoxStream<'_, Result<String, AgentError>> {
+            let chunks = self.chunks.iter().cloned().map(Ok);
+            let error = self
+                .error
+                .clone()
+                .map(|error| Err(AgentError::TestError(error)));
+            Box::pin(stream::iter(chunks.chain(error)))
+        }
+
+        fn run_multiple_tasks(
+            &mut self,
+            _tasks: Vec<String>,
+        ) -> BoxFuture<'_, Result<Vec<String>, AgentError>> {
+            Box::pin(future::ready(Ok(vec![])))
+        }
+
+        fn id(&self) -> String {
+            self.name.clone()
+        }
+
+        fn name(&self) -> String {
+            self.name.clone()
+        }
+
+        fn description(&self) -> String {
+            "Streaming agent".to_owned()
+        }
+    }
+
+    /// Create an agent which responds with its input
+    fn create_echo_agent(id: &str, name: &str) -> Arc<MockAgent> {
+        let mut agent = MockAgent::new();
+        agent.expect_id().return_const(id.to_owned());
+        agent.expect_name().return_const(name.to_owned());
+        agent
+            .expect_description()
+            .return_const("Echo agent".to_owned());
+        agent
+            .expect_run()
+            .returning(|input| Box::pin(future::ready(Ok(input))));
+        agent
+            .expect_run_multiple_tasks()
+            .returning(|tasks| Box::pin(future::ready(Ok(tasks))));
+
+        Arc::new(agent)
+    }
+
+    fn create_failing_agent(id: &str, name: &str, error_msg: &str) -> Arc<MockAgent> {
+        let mut agent = MockAgent::new();
+
+        let id_str = id.to_owned();
+        agent.expect_id().return_const(id_str);
+
+        let name_str = name.to_owned();
+        agent.expect_name().return_const(name_str);
+
+        agent
             .expect_description()
             .return_const("Failing agent".to_owned());
 
@@ -1023,11 +1696,11 @@ mod tests {
             .unwrap();
         assert_eq!(results.len(), 2);
         assert_eq!(
-            results.get("agent1").unwrap().as_ref().unwrap(),
+            results.get("agent1").unwrap().output().unwrap(),
             "response1"
         );
         assert_eq!(
-            results.get("agent2").unwrap().as_ref().unwrap(),
+            results.get("agent2").unwrap().output().unwrap(),
             "response2"
         );
     }
@@ -1052,15 +1725,15 @@ mod tests {
             .unwrap();
         assert_eq!(results.len(), 3);
         assert_eq!(
-            results.get("agent1").unwrap().as_ref().unwrap(),
+            results.get("agent1").unwrap().output().unwrap(),
             "response1"
         );
         assert_eq!(
-            results.get("agent2").unwrap().as_ref().unwrap(),
+            results.get("agent2").unwrap().output().unwrap(),
             "response2"
         );
         assert_eq!(
-            results.get("agent3").unwrap().as_ref().unwrap(),
+            results.get("agent3").unwrap().output().unwrap(),
             "response3"
         );
     }
@@ -1076,10 +1749,11 @@ mod tests {
             "response2",
         ));
 
-        let transform_fn = Arc::new(|input: String| format!("transformed: {input}"));
+        let transform_fn = Transform::template("transformed: {output}");
         let flow = Flow {
             transform: Some(transform_fn),
             condition: None,
+            ..Default::default()
         };
 
         workflow.connect_agents("agent1", "agent2", flow).unwrap();
@@ -1094,7 +1768,10 @@ mod tests {
         let agent1_connections = &structure["agent1"];
         assert_eq!(agent1_connections.len(), 1);
         assert_eq!(agent1_connections[0].0, "agent2");
-        assert_eq!(agent1_connections[0].1, Some("transform".to_owned()));
+        assert_eq!(
+            agent1_connections[0].1,
+            Some("template \"transformed: {output}\"".to_owned())
+        );
     }
 
     #[tokio::test]
@@ -1103,7 +1780,7 @@ mod tests {
         workflow.register_agent(create_mock_agent("1", "agent1", "First agent", "true"));
         workflow.register_agent(create_mock_agent("2", "agent2", "Second agent", "executed"));
 
-        let true_condition = Arc::new(|output: &str| output.contains("true"));
+        let true_condition = Condition::contains("true");
 
         workflow
             .connect_agents(
@@ -1112,6 +1789,7 @@ mod tests {
                 Flow {
                     transform: None,
                     condition: Some(true_condition),
+                    ..Default::default()
                 },
             )
             .unwrap();
@@ -1136,7 +1814,7 @@ mod tests {
             "not executed",
         ));
 
-        let false_condition = Arc::new(|output: &str| output.contains("nonexistent"));
+        let false_condition = Condition::contains("nonexistent");
 
         workflow
             .connect_agents(
@@ -1145,6 +1823,7 @@ mod tests {
                 Flow {
                     transform: None,
                     condition: Some(false_condition),
+                    ..Default::default()
                 },
             )
             .unwrap();
@@ -1186,187 +1865,667 @@ mod tests {
             .execute_workflow(&["agent1"], "input")
             .await
             .unwrap();
-        assert_eq!(results.len(), 2);
-        assert!(results.contains_key("agent1"));
-        assert!(results.contains_key("agent2"));
-        assert!(!results.contains_key("agent3"));
+        assert_eq!(results.len(), 3);
+        assert!(results.get("agent1").unwrap().is_succeeded());
+        assert!(matches!(
+            *results.get("agent2").unwrap(),
+            NodeOutcome::Failed(GraphWorkflowError::AgentError(_))
+        ));
 
-        let agent2_result = results.get("agent2").unwrap();
-        assert!(agent2_result.is_err());
+        // agent3 is downstream of the failed agent2
+        match &*results.get("agent3").unwrap() {
+            NodeOutcome::Skipped(reason) => assert!(reason.contains("agent2")),
+            outcome => panic!("unexpected outcome: {outcome:?}"),
+        }
     }
 
     #[tokio::test]
-    async fn test_independent_multiple_starts() {
-        let mut workflow = DAGWorkflow::new("test", "");
-
-        let agent_a = create_mock_agent("1", "A", "A", "A_result");
-        let agent_b = create_mock_agent("2", "B", "B", "B_result");
-        let agent_c = create_mock_agent("3", "C", "C", "C_result");
-        let agent_d = create_mock_agent("4", "D", "D", "D_result");
+    async fn test_fail_fast_cancels_running_agents() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.set_failure_policy(FailurePolicy::FailFast);
+        workflow.register_agent(create_failing_agent("1", "agent1", "fail error"));
 
-        workflow.register_agent(agent_a);
-        workflow.register_agent(agent_b);
-        workflow.register_agent(agent_c);
-        workflow.register_agent(agent_d);
+        workflow.register_agent(create_pending_agent("2", "agent2"));
 
-        workflow.connect_agents("A", "C", Flow::default()).unwrap();
-        workflow.connect_agents("B", "D", Flow::default()).unwrap();
+        let mut events = workflow.subscribe_events();
+        let result = workflow
+            .execute_workflow(&["agent1", "agent2"], "input")
+            .await;
+        assert!(matches!(result, Err(GraphWorkflowError::ExecutionError(_))));
 
-        let results = workflow
-            .execute_workflow(&["A", "B"], "input")
+        let agent2_idx = workflow.name_to_node.get("agent2").unwrap();
+        let last_result = workflow
+            .workflow
+            .node_weight(*agent2_idx)
+            .unwrap()
+            .last_result
+            .lock()
             .await
-            .unwrap();
+            .clone();
+        assert!(matches!(
+            last_result,
+            Some(Err(GraphWorkflowError::Canceled))
+        ));
 
-        assert_eq!(results.get("A").unwrap().as_ref().unwrap(), "A_result");
-        assert_eq!(results.get("B").unwrap().as_ref().unwrap(), "B_result");
-        assert_eq!(results.get("C").unwrap().as_ref().unwrap(), "C_result");
-        assert_eq!(results.get("D").unwrap().as_ref().unwrap(), "D_result");
+        drop(workflow);
+        let mut canceled = Vec::new();
+        while let Some(event) = events.recv().await {
+            if let WorkflowEvent::NodeCanceled { agent, .. } = event {
+                canceled.push(agent);
+            }
+        }
+        assert_eq!(canceled, vec!["agent2"]);
     }
 
-    /// FIXME: This test fails
-    #[tokio::test]
-    async fn test_converging_multiple_starts() {
-        let mut workflow = DAGWorkflow::new("test", "");
-
-        let agent_a = create_mock_agent("1", "A", "A", "A_result");
-        let agent_b = create_mock_agent("2", "B", "B", "B_result");
-        let agent_c = create_mock_agent("3", "C", "C", "C_result");
+    /// An agent which never finishes
+    fn create_pending_agent(id: &str, name: &str) -> Arc<MockAgent> {
+        let mut agent = MockAgent::new();
+        agent.expect_name().return_const(name.to_owned());
+        agent.expect_id().return_const(id.to_owned());
+        agent
+            .expect_description()
+            .return_const("Pending agent".to_owned());
+        agent
+            .expect_run()
+            .returning(|_| Box::pin(future::pending()));
+        agent
+            .expect_run_multiple_tasks()
+            .returning(|_| Box::pin(future::pending()));
 
-        workflow.register_agent(agent_a);
-        workflow.register_agent(agent_b);
-        workflow.register_agent(agent_c);
+        Arc::new(agent)
+    }
 
-        workflow.connect_agents("A", "C", Flow::default()).unwrap();
-        workflow.connect_agents("B", "C", Flow::default()).unwrap();
+    #[tokio::test(start_paused = true)]
+    async fn test_max_concurrency() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        for i in 0..3 {
+            let mut agent = MockAgent::new();
+            agent.expect_name().return_const(format!("agent{i}"));
+            agent.expect_id().return_const(i.to_string());
+            agent
+                .expect_description()
+                .return_const("Slow agent".to_owned());
+            agent.expect_run().returning(|input| {
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    Ok(input)
+                })
+            });
+            workflow.register_agent(Arc::new(agent));
+        }
+        workflow.set_max_concurrency(NonZeroUsize::new(2).unwrap());
+        // Waiting for a turn doesn't count towards the timeout
+        workflow.set_default_timeout(Duration::from_millis(1500));
 
-        let _results = workflow
-            .execute_workflow(&["A", "B"], "input")
+        let started_at = Instant::now();
+        let results = workflow
+            .execute_workflow(&["agent0", "agent1", "agent2"], "input")
             .await
             .unwrap();
+        assert!(results.iter().all(|result| result.is_succeeded()));
+        assert_eq!(started_at.elapsed(), Duration::from_secs(2));
+    }
 
-        let c_node = workflow.name_to_node.get("C").unwrap();
-        let node_data = workflow.workflow.node_weight(*c_node).unwrap();
-        let last_result = node_data.last_result.lock().await;
-        assert!(last_result.is_some());
-        assert!(
-            last_result
-                .as_ref()
-                .unwrap()
-                .as_ref()
-                .unwrap()
-                .contains("A_result")
-        );
-        assert!(
-            last_result
-                .as_ref()
-                .unwrap()
-                .as_ref()
+    #[tokio::test(start_paused = true)]
+    async fn test_execute_workflow_with_cancel() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.register_agent(create_mock_agent("1", "agent1", "First agent", "response1"));
+        workflow.register_agent(create_pending_agent("2", "agent2"));
+        workflow
+            .connect_agents("agent1", "agent2", Flow::default())
+            .unwrap();
+
+        let cancel = CancellationToken::new();
+        let canceler = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            canceler.cancel();
+        });
+
+        let result = workflow
+            .execute_workflow_with_cancel(&["agent1"], "input", cancel)
+            .await;
+        assert!(matches!(result, Err(GraphWorkflowError::Canceled)));
+
+        let node = |name: &str| {
+            workflow
+                .workflow
+                .node_weight(*workflow.name_to_node.get(name).unwrap())
                 .unwrap()
-                .contains("B_result")
-        );
+        };
+        assert!(matches!(
+            *node("agent1").last_result.lock().await,
+            Some(Ok(_))
+        ));
+        assert!(matches!(
+            *node("agent2").last_result.lock().await,
+            Some(Err(GraphWorkflowError::Canceled))
+        ));
     }
 
-    /// FIXME: This test fails
-    #[tokio::test]
-    async fn test_conditional_branches() {
-        let mut workflow = DAGWorkflow::new("test", "");
+    #[tokio::test(start_paused = true)]
+    async fn test_agent_timeout() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.set_default_timeout(Duration::from_secs(10));
+        workflow.register_agent(create_pending_agent("1", "agent1"));
+        workflow.register_agent_with_timeout(
+            create_pending_agent("2", "agent2"),
+            Duration::from_secs(1),
+        );
 
-        let agent_a = create_mock_agent("1", "A", "A", "A_trigger");
-        let agent_b = create_mock_agent("2", "B", "B", "B_result");
-        let agent_c = create_mock_agent("3", "C", "C", "C_result");
+        let mut events = workflow.subscribe_events();
+        let results = workflow
+            .execute_workflow(&["agent1", "agent2"], "input")
+            .await
+            .unwrap();
+        drop(workflow);
 
-        workflow.register_agent(agent_a);
-        workflow.register_agent(agent_b);
-        workflow.register_agent(agent_c);
+        assert!(matches!(
+            *results.get("agent1").unwrap(),
+            NodeOutcome::Failed(GraphWorkflowError::Timeout(_))
+        ));
+        assert!(matches!(
+            *results.get("agent2").unwrap(),
+            NodeOutcome::Failed(GraphWorkflowError::Timeout(_))
+        ));
 
-        let conditional_flow = Flow {
-            condition: Some(Arc::new(|output: &str| output.contains("trigger"))),
-            transform: None,
-        };
+        let mut failed = Vec::new();
+        while let Some(event) = events.recv().await {
+            if let WorkflowEvent::NodeFailed {
+                agent, duration, ..
+            } = event
+            {
+                failed.push((agent, duration.as_secs()));
+            }
+        }
+        assert_eq!(
+            failed,
+            vec![("agent2".to_owned(), 1), ("agent1".to_owned(), 10)]
+        );
+    }
 
-        workflow.connect_agents("A", "B", conditional_flow).unwrap();
-        workflow.connect_agents("A", "C", Flow::default()).unwrap();
+    /// An agent which counts its calls, and fails the first `failures` calls
+    fn create_counting_agent(
+        id: &str,
+        name: &str,
+        failures: usize,
+    ) -> (Arc<MockAgent>, Arc<std::sync::atomic::AtomicUsize>) {
+        let mut agent = MockAgent::new();
+        agent.expect_name().return_const(name.to_owned());
+        agent.expect_id().return_const(id.to_owned());
+        agent
+            .expect_description()
+            .return_const("Counting agent".to_owned());
 
-        let results = workflow.execute_workflow(&["A"], "input").await.unwrap();
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let name = name.to_owned();
+        agent.expect_run().returning(move |_| {
+            let call = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            if call <= failures {
+                Box::pin(future::ready(Err(AgentError::TestError(
+                    "rate limited".to_owned(),
+                ))))
+            } else {
+                Box::pin(future::ready(Ok(format!("{name} call #{call}"))))
+            }
+        });
+        agent
+            .expect_run_multiple_tasks()
+            .returning(|tasks| Box::pin(future::ready(Ok(tasks))));
 
-        assert!(results.get("B").is_none());
-        assert_eq!(results.get("C").unwrap().as_ref().unwrap(), "C_result");
+        (Arc::new(agent), calls)
     }
 
-    #[test]
-    fn test_find_execution_paths() {
-        let mut workflow = DAGWorkflow::new("test", "Test workflow");
-        workflow.register_agent(create_mock_agent("0", "start", "Starting point", "start"));
-        workflow.register_agent(create_mock_agent("1", "a", "Path A", "a"));
-        workflow.register_agent(create_mock_agent("2", "b", "Path B", "b"));
-        workflow.register_agent(create_mock_agent("3", "c", "End of A", "c"));
-        workflow.register_agent(create_mock_agent("4", "d", "End of B", "d"));
+    async fn check_resume_workflow(compress: bool) {
+        let path = std::env::temp_dir()
+            .join(uuid::Uuid::new_v4().to_string())
+            .join("checkpoint.json");
 
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.enable_checkpoint(&path, compress);
+        let (agent1, agent1_calls) = create_counting_agent("1", "agent1", 0);
+        let (agent2, agent2_calls) = create_counting_agent("2", "agent2", 1);
+        workflow.register_agent(agent1);
+        workflow.register_agent(agent2);
+        workflow.register_agent(create_echo_agent("3", "agent3"));
         workflow
-            .connect_agents("start", "a", Flow::default())
+            .connect_agents("agent1", "agent2", Flow::default())
             .unwrap();
         workflow
-            .connect_agents("start", "b", Flow::default())
+            .connect_agents("agent2", "agent3", Flow::default())
             .unwrap();
-        workflow.connect_agents("a", "c", Flow::default()).unwrap();
-        workflow.connect_agents("b", "d", Flow::default()).unwrap();
 
-        let paths = workflow.find_execution_paths(&["start"]).unwrap();
-        assert_eq!(paths.len(), 2);
+        // agent2 is rate limited on its first call
+        let results = workflow
+            .execute_workflow(&["agent1"], "input")
+            .await
+            .unwrap();
+        assert!(matches!(
+            *results.get("agent2").unwrap(),
+            NodeOutcome::Failed(_)
+        ));
 
-        // path should be [start, a, c] or [start, b, d]
-        let has_path1 = paths
-            .iter()
-            .any(|p| p == &vec!["start".to_owned(), "a".to_owned(), "c".to_owned()]);
-        let has_path2 = paths
-            .iter()
-            .any(|p| p == &vec!["start".to_owned(), "b".to_owned(), "d".to_owned()]);
+        let checkpoint = WorkflowCheckpoint::load(&path, compress).await.unwrap();
+        assert_eq!(checkpoint.start_agents, vec!["agent1"]);
+        assert_eq!(checkpoint.outputs.get("agent1").unwrap().len(), 1);
+        assert!(!checkpoint.outputs.contains_key("agent2"));
 
-        assert!(has_path1);
-        assert!(has_path2);
+        let mut events = workflow.subscribe_events();
+        let results = workflow.resume_workflow().await.unwrap();
+
+        // agent1 is restored instead of being run again
+        assert_eq!(agent1_calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(agent2_calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(
+            results.get("agent1").unwrap().output().unwrap(),
+            "agent1 call #1"
+        );
+        assert_eq!(
+            results.get("agent3").unwrap().output().unwrap(),
+            "[From agent2] agent2 call #2"
+        );
+
+        drop(workflow);
+        let mut restored = Vec::new();
+        while let Some(event) = events.recv().await {
+            if let WorkflowEvent::NodeRestored { agent, .. } = event {
+                restored.push(agent);
+            }
+        }
+        assert_eq!(restored, vec!["agent1"]);
+
+        let checkpoint = WorkflowCheckpoint::load(&path, compress).await.unwrap();
+        assert_eq!(checkpoint.outputs.len(), 3);
+
+        tokio::fs::remove_dir_all(path.parent().unwrap())
+            .await
+            .unwrap();
     }
 
-    #[test]
-    fn test_find_execution_paths_start_agent_not_found() {
-        let workflow = DAGWorkflow::new("test", "Test workflow");
+    #[tokio::test]
+    async fn test_resume_workflow() {
+        check_resume_workflow(false).await;
+    }
 
-        let result = workflow.find_execution_paths(&["nonexistent"]);
-        assert!(matches!(result, Err(GraphWorkflowError::AgentNotFound(_))));
+    #[tokio::test]
+    async fn test_resume_workflow_compressed() {
+        check_resume_workflow(true).await;
     }
 
-    #[test]
-    fn test_find_execution_paths_diamond_pattern() {
+    #[tokio::test]
+    async fn test_resume_workflow_without_checkpoint() {
         let mut workflow = DAGWorkflow::new("test", "Test workflow");
-        workflow.register_agent(create_mock_agent("0", "start", "Start", "start"));
-        workflow.register_agent(create_mock_agent("1", "a", "Middle A", "a"));
-        workflow.register_agent(create_mock_agent("2", "b", "Middle B", "b"));
-        workflow.register_agent(create_mock_agent("3", "end", "End", "end"));
+        let result = workflow.resume_workflow().await;
+        assert!(matches!(result, Err(GraphWorkflowError::Checkpoint(_))));
+    }
 
-        //            start -> a -> end
-        //                 \-> b -/
+    #[tokio::test]
+    async fn test_route_to_fallback() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.set_failure_policy(FailurePolicy::RouteToFallback);
+        workflow.register_agent(create_mock_agent("1", "agent1", "First agent", "response1"));
+        workflow.register_agent(create_failing_agent("2", "agent2", "fail error"));
+        workflow.register_agent(create_mock_agent("3", "agent3", "Third agent", "response3"));
+        workflow.register_agent(create_echo_agent("4", "handler"));
+        workflow.register_agent(create_mock_agent("5", "agent5", "Fifth agent", "response5"));
+
+        // agent1 -> agent2 -> agent3 -> agent5, agent2 -(error)-> handler
         workflow
-            .connect_agents("start", "a", Flow::default())
+            .connect_agents("agent1", "agent2", Flow::default())
             .unwrap();
         workflow
-            .connect_agents("start", "b", Flow::default())
+            .connect_agents("agent2", "agent3", Flow::default())
             .unwrap();
         workflow
-            .connect_agents("a", "end", Flow::default())
+            .connect_agents("agent3", "agent5", Flow::default())
             .unwrap();
         workflow
-            .connect_agents("b", "end", Flow::default())
+            .connect_agents(
+                "agent2",
+                "handler",
+                Flow {
+                    on_error: true,
+                    ..Default::default()
+                },
+            )
             .unwrap();
 
-        let paths = workflow.find_execution_paths(&["start"]).unwrap();
-        assert_eq!(paths.len(), 2);
-
-        // path should be [start, a, end] or [start, b, end]
-        let has_path1 = paths
-            .iter()
-            .any(|p| p == &vec!["start".to_owned(), "a".to_owned(), "end".to_owned()]);
-        let has_path2 = paths
-            .iter()
-            .any(|p| p == &vec!["start".to_owned(), "b".to_owned(), "end".to_owned()]);
+        let results = workflow
+            .execute_workflow(&["agent1"], "input")
+            .await
+            .unwrap();
+
+        assert!(
+            results
+                .get("handler")
+                .unwrap()
+                .output()
+                .unwrap()
+                .contains("fail error")
+        );
+        assert!(matches!(
+            *results.get("agent3").unwrap(),
+            NodeOutcome::Skipped(_)
+        ));
+        // The skip is passed along to all descendants
+        assert!(matches!(
+            *results.get("agent5").unwrap(),
+            NodeOutcome::Skipped(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_error_flow_of_rig_agent() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let unauthorized = r#"{"error":{"code":"invalid_api_key"}}"#;
+            serve_response(&listener, "401 Unauthorized", unauthorized).await
+        });
+        let writer = RigAgent::openai_builder()
+            .provider(LLMProvider::openai_compatible(url, "mock"))
+            .unwrap()
+            .agent_name("writer")
+            .build()
+            .unwrap();
+
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.set_failure_policy(FailurePolicy::RouteToFallback);
+        workflow.register_agent(Arc::new(writer));
+        workflow.register_agent(create_echo_agent("2", "handler"));
+        workflow
+            .connect_agents(
+                "writer",
+                "handler",
+                Flow {
+                    on_error: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let results = workflow
+            .execute_workflow(&["writer"], "Write a poem")
+            .await
+            .unwrap();
+        assert!(matches!(
+            *results.get("writer").unwrap(),
+            NodeOutcome::Failed(_)
+        ));
+        assert!(
+            results
+                .get("handler")
+                .unwrap()
+                .output()
+                .unwrap()
+                .contains("invalid_api_key")
+        );
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_error_flow_not_taken_on_success() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.set_failure_policy(FailurePolicy::RouteToFallback);
+        workflow.register_agent(create_mock_agent("1", "agent1", "First agent", "response1"));
+        workflow.register_agent(create_echo_agent("2", "handler"));
+        workflow
+            .connect_agents(
+                "agent1",
+                "handler",
+                Flow {
+                    on_error: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let results = workflow
+            .execute_workflow(&["agent1"], "input")
+            .await
+            .unwrap();
+        assert!(results.get("agent1").unwrap().is_succeeded());
+        assert!(!results.contains_key("handler"));
+    }
+
+    #[tokio::test]
+    async fn test_flow_failure_policy_overrides_workflow() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.set_failure_policy(FailurePolicy::FailFast);
+        workflow.register_agent(create_failing_agent("1", "agent1", "fail error"));
+        workflow.register_agent(create_mock_agent(
+            "2",
+            "agent2",
+            "Second agent",
+            "response2",
+        ));
+        workflow
+            .connect_agents(
+                "agent1",
+                "agent2",
+                Flow {
+                    failure_policy: Some(FailurePolicy::ContinueWithSkip),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let results = workflow
+            .execute_workflow(&["agent1"], "input")
+            .await
+            .unwrap();
+        assert!(matches!(
+            *results.get("agent1").unwrap(),
+            NodeOutcome::Failed(_)
+        ));
+        assert!(matches!(
+            *results.get("agent2").unwrap(),
+            NodeOutcome::Skipped(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_independent_multiple_starts() {
+        let mut workflow = DAGWorkflow::new("test", "");
+
+        let agent_a = create_mock_agent("1", "A", "A", "A_result");
+        let agent_b = create_mock_agent("2", "B", "B", "B_result");
+        let agent_c = create_mock_agent("3", "C", "C", "C_result");
+        let agent_d = create_mock_agent("4", "D", "D", "D_result");
+
+        workflow.register_agent(agent_a);
+        workflow.register_agent(agent_b);
+        workflow.register_agent(agent_c);
+        workflow.register_agent(agent_d);
+
+        workflow.connect_agents("A", "C", Flow::default()).unwrap();
+        workflow.connect_agents("B", "D", Flow::default()).unwrap();
+
+        let results = workflow
+            .execute_workflow(&["A", "B"], "input")
+            .await
+            .unwrap();
+
+        assert_eq!(results.get("A").unwrap().output().unwrap(), "A_result");
+        assert_eq!(results.get("B").unwrap().output().unwrap(), "B_result");
+        assert_eq!(results.get("C").unwrap().output().unwrap(), "C_result");
+        assert_eq!(results.get("D").unwrap().output().unwrap(), "D_result");
+    }
+
+    /// FIXME: This test fails
+    #[tokio::test]
+    async fn test_converging_multiple_starts() {
+        let mut workflow = DAGWorkflow::new("test", "");
+
+        let agent_a = create_mock_agent("1", "A", "A", "A_result");
+        let agent_b = create_mock_agent("2", "B", "B", "B_result");
+        let agent_c = create_mock_agent("3", "C", "C", "C_result");
+
+        workflow.register_agent(agent_a);
+        workflow.register_agent(agent_b);
+        workflow.register_agent(agent_c);
+
+        workflow.connect_agents("A", "C", Flow::default()).unwrap();
+        workflow.connect_agents("B", "C", Flow::default()).unwrap();
+
+        let _results = workflow
+            .execute_workflow(&["A", "B"], "input")
+            .await
+            .unwrap();
+
+        let c_node = workflow.name_to_node.get("C").unwrap();
+        let node_data = workflow.workflow.node_weight(*c_node).unwrap();
+        let last_result = node_data.last_result.lock().await;
+        assert!(last_result.is_some());
+        assert!(
+            last_result
+                .as_ref()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .contains("A_result")
+        );
+        assert!(
+            last_result
+                .as_ref()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .contains("B_result")
+        );
+    }
+
+    /// A node fed by several start agents used to never run, it runs once with all their outputs
+    #[tokio::test]
+    async fn test_converging_node_runs_once_with_all_inputs() {
+        let mut workflow = DAGWorkflow::new("test", "");
+
+        workflow.register_agent(create_mock_agent("1", "A", "A", "A_result"));
+        workflow.register_agent(create_mock_agent("2", "B", "B", "B_result"));
+        let mut agent_c = MockAgent::new();
+        agent_c.expect_id().return_const("3".to_owned());
+        agent_c.expect_name().return_const("C".to_owned());
+        agent_c.expect_description().return_const("C".to_owned());
+        agent_c
+            .expect_run()
+            .withf(|input| input.contains("A_result") && input.contains("B_result"))
+            .times(1)
+            .returning(|_| Box::pin(future::ready(Ok("C_result".to_owned()))));
+        workflow.register_agent(Arc::new(agent_c));
+
+        workflow.connect_agents("A", "C", Flow::default()).unwrap();
+        workflow.connect_agents("B", "C", Flow::default()).unwrap();
+
+        let results = workflow
+            .execute_workflow(&["A", "B"], "input")
+            .await
+            .unwrap();
+        assert_eq!(results.get("C").unwrap().output().unwrap(), "C_result");
+    }
+
+    /// FIXME: This test fails
+    #[tokio::test]
+    async fn test_conditional_branches() {
+        let mut workflow = DAGWorkflow::new("test", "");
+
+        let agent_a = create_mock_agent("1", "A", "A", "A_trigger");
+        let agent_b = create_mock_agent("2", "B", "B", "B_result");
+        let agent_c = create_mock_agent("3", "C", "C", "C_result");
+
+        workflow.register_agent(agent_a);
+        workflow.register_agent(agent_b);
+        workflow.register_agent(agent_c);
+
+        let conditional_flow = Flow {
+            condition: Some(Condition::contains("trigger")),
+            transform: None,
+            ..Default::default()
+        };
+
+        workflow.connect_agents("A", "B", conditional_flow).unwrap();
+        workflow.connect_agents("A", "C", Flow::default()).unwrap();
+
+        let results = workflow.execute_workflow(&["A"], "input").await.unwrap();
+
+        assert!(results.get("B").is_none());
+        assert_eq!(results.get("C").unwrap().output().unwrap(), "C_result");
+    }
+
+    #[test]
+    fn test_find_execution_paths() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.register_agent(create_mock_agent("0", "start", "Starting point", "start"));
+        workflow.register_agent(create_mock_agent("1", "a", "Path A", "a"));
+        workflow.register_agent(create_mock_agent("2", "b", "Path B", "b"));
+        workflow.register_agent(create_mock_agent("3", "c", "End of A", "c"));
+        workflow.register_agent(create_mock_agent("4", "d", "End of B", "d"));
+
+        workflow
+            .connect_agents("start", "a", Flow::default())
+            .unwrap();
+        workflow
+            .connect_agents("start", "b", Flow::default())
+            .unwrap();
+        workflow.connect_agents("a", "c", Flow::default()).unwrap();
+        workflow.connect_agents("b", "d", Flow::default()).unwrap();
+
+        let paths = workflow.find_execution_paths(&["start"]).unwrap();
+        assert_eq!(paths.len(), 2);
+
+        // path should be [start, a, c] or [start, b, d]
+        let has_path1 = paths
+            .iter()
+            .any(|p| p == &vec!["start".to_owned(), "a".to_owned(), "c".to_owned()]);
+        let has_path2 = paths
+            .iter()
+            .any(|p| p == &vec!["start".to_owned(), "b".to_owned(), "d".to_owned()]);
+
+        assert!(has_path1);
+        assert!(has_path2);
+    }
+
+    #[test]
+    fn test_find_execution_paths_start_agent_not_found() {
+        let workflow = DAGWorkflow::new("test", "Test workflow");
+
+        let result = workflow.find_execution_paths(&["nonexistent"]);
+        assert!(matches!(result, Err(GraphWorkflowError::AgentNotFound(_))));
+    }
+
+    #[test]
+    fn test_find_execution_paths_diamond_pattern() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.register_agent(create_mock_agent("0", "start", "Start", "start"));
+        workflow.register_agent(create_mock_agent("1", "a", "Middle A", "a"));
+        workflow.register_agent(create_mock_agent("2", "b", "Middle B", "b"));
+        workflow.register_agent(create_mock_agent("3", "end", "End", "end"));
+
+        //            start -> a -> end
+        //                 \-> b -/
+        workflow
+            .connect_agents("start", "a", Flow::default())
+            .unwrap();
+        workflow
+            .connect_agents("start", "b", Flow::default())
+            .unwrap();
+        workflow
+            .connect_agents("a", "end", Flow::default())
+            .unwrap();
+        workflow
+            .connect_agents("b", "end", Flow::default())
+            .unwrap();
+
+        let paths = workflow.find_execution_paths(&["start"]).unwrap();
+        assert_eq!(paths.len(), 2);
+
+        // path should be [start, a, end] or [start, b, end]
+        let has_path1 = paths
+            .iter()
+            .any(|p| p == &vec!["start".to_owned(), "a".to_owned(), "end".to_owned()]);
+        let has_path2 = paths
+            .iter()
+            .any(|p| p == &vec!["start".to_owned(), "b".to_owned(), "end".to_owned()]);
 
         assert!(has_path1);
         assert!(has_path2);
@@ -1401,10 +2560,11 @@ mod tests {
 
         workflow.connect_agents("a", "b", Flow::default()).unwrap();
 
-        let transform_fn = Arc::new(|input: String| format!("transformed: {input}"));
+        let transform_fn = Transform::custom("prefix", |input| format!("transformed: {input}"));
         let flow = Flow {
             transform: Some(transform_fn),
             condition: None,
+            ..Default::default()
         };
 
         workflow.connect_agents("b", "c", flow).unwrap();
@@ -1418,7 +2578,7 @@ mod tests {
 
         assert_eq!(structure["b"].len(), 1);
         assert_eq!(structure["b"][0].0, "c");
-        assert_eq!(structure["b"][0].1, Some("transform".to_owned())); // has transform
+        assert_eq!(structure["b"][0].1, Some("prefix".to_owned())); // has transform
 
         assert_eq!(structure["c"].len(), 0); // c is a leaf node
     }
@@ -1440,10 +2600,145 @@ mod tests {
         assert!(dot.contains("}"));
     }
 
-    #[tokio::test]
-    async fn test_caching_execution_results() {
-        let mut workflow = DAGWorkflow::new("test", "Test workflow");
-
+    #[test]
+    fn test_export_workflow_dot_labels() {
+        let mut workflow = create_feedback_loop(Some(2));
+        workflow
+            .connect_agents(
+                "writer",
+                "publisher",
+                Flow {
+                    transform: Some(Transform::template("Publish \"{output}\"")),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let dot = workflow.export_workflow_dot();
+        assert!(dot.contains(r#""writer" -> "reviewer";"#));
+        assert!(dot.contains(r#""reviewer" -> "writer" [label="if is revise", style=dashed];"#));
+        assert!(dot.contains(r#""reviewer" -> "publisher" [label="if is approved"];"#));
+        assert!(dot.contains(
+            r#""writer" -> "publisher" [label="template \"Publish \\\"{output}\\\"\""];"#
+        ));
+    }
+
+    #[test]
+    fn test_condition_evaluate() {
+        assert!(Condition::contains("ok").evaluate("it is ok"));
+        assert!(!Condition::contains("OK").evaluate("it is ok"));
+        assert!(Condition::contains_ignore_case("OK").evaluate("it is ok"));
+
+        let regex = Condition::regex(r"^score: \d+$").unwrap();
+        assert!(regex.evaluate("score: 42"));
+        assert!(!regex.evaluate("score: none"));
+        assert!(Condition::regex("(").is_err());
+
+        let approved = Condition::json_pointer_equals("/review/approved", true);
+        assert!(approved.evaluate(r#"{"review": {"approved": true}}"#));
+        assert!(approved.evaluate("```json\n{\"review\": {\"approved\": true}}\n```"));
+        assert!(!approved.evaluate(r#"{"review": {"approved": false}}"#));
+        assert!(!approved.evaluate(r#"{"review": {}}"#));
+        assert!(!approved.evaluate("approved"));
+
+        assert!(Condition::min_length(3).evaluate("你好吗"));
+        assert!(!Condition::min_length(4).evaluate("你好吗"));
+        assert!(Condition::max_length(3).evaluate("abc"));
+        assert!(!Condition::max_length(2).evaluate("abc"));
+
+        let condition = Condition::contains("a")
+            .and(Condition::contains("b"))
+            .or(!Condition::min_length(1));
+        assert!(condition.evaluate("ab"));
+        assert!(!condition.evaluate("a"));
+        assert!(condition.evaluate(""));
+        assert!(Condition::custom("short", |output| output.len() < 3).evaluate("ab"));
+
+        let closure = Condition::from(|output: &str| output.len() > 3);
+        assert!(closure.evaluate("你好"));
+        assert_eq!(closure.to_string(), "custom");
+    }
+
+    #[test]
+    fn test_transform_apply() {
+        let template = Transform::template("Review: {output}");
+        assert_eq!(template.apply("draft".to_owned()), "Review: draft");
+
+        let output = r#"{"draft": {"title": "Rust", "words": 42}}"#.to_owned();
+        assert_eq!(
+            Transform::json_field("/draft/title").apply(output.clone()),
+            "Rust"
+        );
+        assert_eq!(
+            Transform::json_field("/draft/words").apply(output.clone()),
+            "42"
+        );
+        // The output is passed unchanged if the field can't be extracted
+        assert_eq!(
+            Transform::json_field("/draft/missing").apply(output.clone()),
+            output
+        );
+        assert_eq!(
+            Transform::json_field("/draft").apply("not json".to_owned()),
+            "not json"
+        );
+
+        let chain = Transform::json_field("/draft/title")
+            .then(Transform::custom("uppercase", |output| {
+                output.to_uppercase()
+            }))
+            .then(template);
+        assert_eq!(chain.apply(output), "Review: RUST");
+        assert_eq!(
+            chain.to_string(),
+            "(field /draft/title | uppercase | template \"Review: {output}\")"
+        );
+
+        let closure = Transform::from(|output: String| output.repeat(2));
+        assert_eq!(closure.apply("ab".to_owned()), "abab");
+        assert_eq!(closure.to_string(), "custom");
+    }
+
+    #[test]
+    fn test_expression_serialization() {
+        let condition = Condition::regex("^yes")
+            .unwrap()
+            .or(Condition::json_pointer_equals("/score", 5))
+            .and(!Condition::max_length(5));
+        let yaml = serde_norway::to_string(&condition).unwrap();
+        let deserialized: Condition = serde_norway::from_str(&yaml).unwrap();
+        assert_eq!(deserialized.to_string(), condition.to_string());
+        assert_eq!(
+            condition.to_string(),
+            "((matches /^yes/ || /score == 5) && !length <= 5)"
+        );
+        assert!(deserialized.evaluate("yes, but it's too short"));
+        assert!(condition.is_serializable());
+
+        let transform = Transform::json_field("/answer").then(Transform::template("{output}!"));
+        let json = serde_json::to_string(&transform).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"chain","transforms":[{"type":"json_field","pointer":"/answer"},{"type":"template","template":"{output}!"}]}"#
+        );
+        let deserialized: Transform = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            deserialized.apply(r#"{"answer": "yes"}"#.to_owned()),
+            "yes!"
+        );
+
+        // Custom closures and invalid patterns can't be (de)serialized
+        let custom = Condition::contains("a").and(Condition::custom("always", |_| true));
+        assert!(!custom.is_serializable());
+        assert!(serde_json::to_string(&custom).is_err());
+        assert!(!Transform::custom("identity", |output| output).is_serializable());
+        assert!(serde_json::from_str::<Condition>(r#"{"type":"regex","pattern":"("}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_caching_execution_results() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+
         // mock counter agent
         let mut agent = MockAgent::new();
         let agent_name = "counter".to_owned();
@@ -1471,7 +2766,7 @@ mod tests {
             .await
             .unwrap();
         assert_eq!(
-            results1.get("counter").unwrap().as_ref().unwrap(),
+            results1.get("counter").unwrap().output().unwrap(),
             "Called 1 times"
         );
 
@@ -1481,7 +2776,7 @@ mod tests {
             .await
             .unwrap();
         assert_eq!(
-            results2.get("counter").unwrap().as_ref().unwrap(),
+            results2.get("counter").unwrap().output().unwrap(),
             "Called 2 times"
         );
 
@@ -1494,99 +2789,839 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_execute_node_result_caching() {
+    async fn test_execute_node_once_per_execution() {
         let mut workflow = DAGWorkflow::new("test", "Test workflow");
 
         // Create a mock agent that records the number of calls
-        let mut agent1 = MockAgent::new();
-        agent1.expect_name().return_const("agent1".to_owned());
-        agent1.expect_id().return_const("1".to_owned());
-        agent1
+        let mut agent4 = MockAgent::new();
+        agent4.expect_name().return_const("agent4".to_owned());
+        agent4.expect_id().return_const("4".to_owned());
+        agent4
             .expect_description()
-            .return_const("First agent".to_owned());
-
-        // Set a counter to verify that the run method was called only once
-        let mut run_count = 0;
-        agent1.expect_run().returning(move |input| {
-            run_count += 1;
-            Box::pin(future::ready(Ok(format!(
-                "response for '{input}' (call #{run_count})"
-            ))))
-        });
+            .return_const("Fourth agent".to_owned());
 
-        agent1
+        let run_count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&run_count);
+        agent4.expect_run().returning(move |input| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Box::pin(future::ready(Ok(input)))
+        });
+        agent4
             .expect_run_multiple_tasks()
             .returning(|_| Box::pin(future::ready(Ok(vec![]))));
 
-        workflow.register_agent(Arc::new(agent1));
-
-        // Create a normal second proxy
+        workflow.register_agent(create_mock_agent("1", "agent1", "First agent", "response1"));
         workflow.register_agent(create_mock_agent(
             "2",
             "agent2",
             "Second agent",
             "response2",
         ));
+        workflow.register_agent(create_mock_agent("3", "agent3", "Third agent", "response3"));
+        workflow.register_agent(Arc::new(agent4));
 
-        // connect the two agents
+        // Diamond: agent1 -> (agent2, agent3) -> agent4
         workflow
             .connect_agents("agent1", "agent2", Flow::default())
             .unwrap();
+        workflow
+            .connect_agents("agent1", "agent3", Flow::default())
+            .unwrap();
+        workflow
+            .connect_agents("agent2", "agent4", Flow::default())
+            .unwrap();
+        workflow
+            .connect_agents("agent3", "agent4", Flow::default())
+            .unwrap();
 
-        let agent1_idx = *workflow.name_to_node.get("agent1").unwrap();
+        let results = workflow
+            .execute_workflow(&["agent1"], "input")
+            .await
+            .unwrap();
 
-        // create shared data structures
-        let results = Arc::new(DashMap::new());
-        let edge_tracker = Arc::new(DashMap::new());
-        let processed_nodes = Arc::new(DashMap::new());
+        // agent4 waits for both branches and runs once with both inputs
+        assert_eq!(run_count.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let output = results.get("agent4").unwrap().output().unwrap().to_owned();
+        assert!(output.contains("[From agent2] response2"));
+        assert!(output.contains("[From agent3] response3"));
 
-        // first execution of agent1
-        let result1 = workflow
-            .execute_node(
-                agent1_idx,
-                "input1".to_owned(),
-                Arc::clone(&results),
-                Arc::clone(&edge_tracker),
-                Arc::clone(&processed_nodes),
-            )
+        // Executing again runs every agent again
+        workflow
+            .execute_workflow(&["agent1"], "input")
             .await
             .unwrap();
+        assert_eq!(run_count.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
 
-        assert_eq!(result1, "response for 'input1' (call #1)");
-        assert!(results.contains_key("agent1"));
-        assert!(results.contains_key("agent2")); // agent2 also executed
-
-        // second execution of agent1 with a different input
-        let result2 = workflow
-            .execute_node(
-                agent1_idx,
-                "input2".to_owned(),
-                Arc::clone(&results),
-                Arc::clone(&edge_tracker),
-                Arc::clone(&processed_nodes),
+    #[test]
+    fn test_aggregators() {
+        let inputs = vec![
+            NodeInput {
+                agent: None,
+                input: "task".to_owned(),
+            },
+            NodeInput {
+                agent: Some("a".to_owned()),
+                input: r#"{"score": 1}"#.to_owned(),
+            },
+            NodeInput {
+                agent: Some("b".to_owned()),
+                input: "plain".to_owned(),
+            },
+        ];
+
+        assert_eq!(
+            Aggregator::default().aggregate(&inputs),
+            "task\n\n---\n\n[From a] {\"score\": 1}\n\n---\n\n[From b] plain"
+        );
+        assert_eq!(
+            Aggregator::concat("<{agent}>{input}</{agent}>", "\n").aggregate(&inputs[1..]),
+            "<a>{\"score\": 1}</a>\n<b>plain</b>"
+        );
+        assert_eq!(
+            Aggregator::JsonArray.aggregate(&inputs),
+            r#"[{"agent":null,"input":"task"},{"agent":"a","input":{"score":1}},{"agent":"b","input":"plain"}]"#
+        );
+        assert_eq!(
+            Aggregator::JsonObject.aggregate(&inputs),
+            r#"{"a":{"score":1},"b":"plain","input":"task"}"#
+        );
+        assert_eq!(
+            Aggregator::FirstArrived.aggregate(&inputs[1..]),
+            r#"{"score": 1}"#
+        );
+        assert_eq!(
+            Aggregator::custom("count", |inputs| inputs.len().to_string()).aggregate(&inputs),
+            "3"
+        );
+        // A single workflow input is passed as is
+        assert_eq!(Aggregator::JsonObject.aggregate(&inputs[..1]), "task");
+
+        let votes = ["yes", "no ", "no", "yes"]
+            .iter()
+            .enumerate()
+            .map(|(i, vote)| NodeInput {
+                agent: Some(i.to_string()),
+                input: (*vote).to_owned(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(Aggregator::MajorityVote.aggregate(&votes), "yes");
+        assert_eq!(Aggregator::MajorityVote.aggregate(&votes[1..]), "no");
+    }
+
+    #[tokio::test]
+    async fn test_json_object_aggregator() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.register_agent(create_mock_agent("1", "agent1", "First agent", "response1"));
+        workflow.register_agent(create_mock_agent("2", "agent2", "Second agent", "[1, 2]"));
+        workflow.register_agent(create_echo_agent("3", "merger"));
+        workflow
+            .connect_agents("agent1", "merger", Flow::default())
+            .unwrap();
+        workflow
+            .connect_agents("agent2", "merger", Flow::default())
+            .unwrap();
+        workflow
+            .set_aggregator("merger", Aggregator::JsonObject)
+            .unwrap();
+        assert!(matches!(
+            workflow.set_aggregator("unknown", Aggregator::JsonObject),
+            Err(GraphWorkflowError::AgentNotFound(_))
+        ));
+
+        let results = workflow
+            .execute_workflow(&["agent1", "agent2"], "input")
+            .await
+            .unwrap();
+        let output = results.get("merger").unwrap().output().unwrap().to_owned();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&output).unwrap(),
+            serde_json::json!({ "agent1": "response1", "agent2": [1, 2] })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_first_arrived_aggregator() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.register_agent(create_mock_agent("1", "fast", "Fast agent", "response1"));
+        workflow
+            .register_agent_with_timeout(create_pending_agent("2", "slow"), Duration::from_secs(1));
+        workflow.register_agent(create_echo_agent("3", "merger"));
+        workflow
+            .connect_agents("fast", "merger", Flow::default())
+            .unwrap();
+        workflow
+            .connect_agents("slow", "merger", Flow::default())
+            .unwrap();
+        workflow
+            .set_aggregator("merger", Aggregator::FirstArrived)
+            .unwrap();
+
+        // The merger runs with the first input, without waiting for the slow agent
+        let results = workflow
+            .execute_workflow(&["fast", "slow"], "input")
+            .await
+            .unwrap();
+        assert_eq!(results.get("merger").unwrap().output(), Some("response1"));
+        assert!(matches!(
+            *results.get("slow").unwrap(),
+            NodeOutcome::Failed(GraphWorkflowError::Timeout(_))
+        ));
+        assert_eq!(
+            workflow
+                .get_iteration_results("merger")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    /// A "research" sub-workflow: planner -> (searcher, failing critic)
+    fn create_research_workflow() -> DAGWorkflow {
+        let mut workflow = DAGWorkflow::new("research", "Research sub-workflow");
+        workflow.register_agent(create_echo_agent("1", "planner"));
+        workflow.register_agent(create_mock_agent("2", "searcher", "Searcher", "findings"));
+        workflow.register_agent(create_failing_agent("3", "critic", "critic error"));
+        workflow
+            .connect_agents("planner", "searcher", Flow::default())
+            .unwrap();
+        workflow
+            .connect_agents("planner", "critic", Flow::default())
+            .unwrap();
+        workflow
+    }
+
+    #[tokio::test]
+    async fn test_workflow_agent() {
+        assert!(matches!(
+            WorkflowAgent::new(create_research_workflow(), &["planner"], &["unknown"]),
+            Err(GraphWorkflowError::AgentNotFound(_))
+        ));
+
+        let research =
+            WorkflowAgent::new(create_research_workflow(), &["planner"], &["searcher"]).unwrap();
+        assert_eq!(research.name(), "research");
+
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.register_agent(create_mock_agent("1", "topic", "Topic", "rust"));
+        workflow.register_agent(Arc::new(research));
+        workflow.register_agent(create_echo_agent("2", "writer"));
+        workflow
+            .connect_agents("topic", "research", Flow::default())
+            .unwrap();
+        workflow
+            .connect_agents("research", "writer", Flow::default())
+            .unwrap();
+
+        // The sub-workflow is a single node of the parent workflow
+        let results = workflow
+            .execute_workflow(&["topic"], "input")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results.get("research").unwrap().output(), Some("findings"));
+        assert_eq!(
+            results.get("writer").unwrap().output(),
+            Some("[From research] findings")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_workflow_agent_outputs() {
+        let research = WorkflowAgent::new(create_research_workflow(), &["planner"], &["searcher"])
+            .unwrap()
+            .with_name("research")
+            .with_aggregator(Aggregator::JsonObject);
+        assert_eq!(research.run("input".to_owned()).await.unwrap(), "findings");
+        let planner = research
+            .workflow()
+            .await
+            .get_iteration_results("planner")
+            .await;
+        assert_eq!(planner.unwrap().len(), 1);
+
+        // The outputs of several output agents are aggregated
+        let mut workflow = create_research_workflow();
+        workflow.register_agent(create_mock_agent("4", "reader", "Reader", "notes"));
+        workflow
+            .connect_agents("planner", "reader", Flow::default())
+            .unwrap();
+        let research = WorkflowAgent::new(workflow, &["planner"], &["searcher", "reader"])
+            .unwrap()
+            .with_aggregator(Aggregator::JsonObject);
+        assert_eq!(
+            research.run("input".to_owned()).await.unwrap(),
+            r#"{"reader":"notes","searcher":"findings"}"#
+        );
+
+        // A failed output agent fails the agent
+        let research = WorkflowAgent::new(
+            create_research_workflow(),
+            &["planner"],
+            &["searcher", "critic"],
+        )
+        .unwrap();
+        let error = research.run("input".to_owned()).await.unwrap_err();
+        assert!(
+            matches!(error, AgentError::WorkflowError(error) if error.contains("Output agent 'critic' failed"))
+        );
+    }
+
+    /// A writer which counts its drafts, and a reviewer which approves the third one
+    fn create_feedback_loop(max_iterations: Option<u32>) -> DAGWorkflow {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.allow_cycles();
+
+        let mut writer = MockAgent::new();
+        writer.expect_name().return_const("writer".to_owned());
+        writer.expect_id().return_const("1".to_owned());
+        writer
+            .expect_description()
+            .return_const("Writer".to_owned());
+        let mut drafts = 0;
+        writer.expect_run().returning(move |_| {
+            drafts += 1;
+            Box::pin(future::ready(Ok(format!("draft {drafts}"))))
+        });
+        writer
+            .expect_run_multiple_tasks()
+            .returning(|tasks| Box::pin(future::ready(Ok(tasks))));
+
+        let mut reviewer = MockAgent::new();
+        reviewer.expect_name().return_const("reviewer".to_owned());
+        reviewer.expect_id().return_const("2".to_owned());
+        reviewer
+            .expect_description()
+            .return_const("Reviewer".to_owned());
+        reviewer.expect_run().returning(|input| {
+            let review = if input.contains("draft 3") {
+                "approved"
+            } else {
+                "revise"
+            };
+            Box::pin(future::ready(Ok(review.to_owned())))
+        });
+        reviewer
+            .expect_run_multiple_tasks()
+            .returning(|tasks| Box::pin(future::ready(Ok(tasks))));
+
+        workflow.register_agent(Arc::new(writer));
+        workflow.register_agent(Arc::new(reviewer));
+        workflow.register_agent(create_echo_agent("3", "publisher"));
+
+        workflow
+            .connect_agents("writer", "reviewer", Flow::default())
+            .unwrap();
+        workflow
+            .connect_agents(
+                "reviewer",
+                "writer",
+                Flow {
+                    condition: Some(Condition::custom("is revise", |output| output == "revise")),
+                    max_iterations,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        workflow
+            .connect_agents(
+                "reviewer",
+                "publisher",
+                Flow {
+                    condition: Some(Condition::custom("is approved", |output| {
+                        output == "approved"
+                    })),
+                    ..Default::default()
+                },
             )
+            .unwrap();
+
+        workflow
+    }
+
+    #[tokio::test]
+    async fn test_feedback_loop() {
+        let mut workflow = create_feedback_loop(Some(5));
+
+        let results = workflow
+            .execute_workflow(&["writer"], "topic")
             .await
             .unwrap();
 
-        // the results should be the same, indicating that the agent was not executed again
-        assert_eq!(result2, "response for 'input1' (call #1)"); // not "response for 'input2' (call #1)"
+        assert_eq!(results.get("writer").unwrap().output().unwrap(), "draft 3");
+        assert_eq!(
+            results.get("reviewer").unwrap().output().unwrap(),
+            "approved"
+        );
+        assert_eq!(
+            results.get("publisher").unwrap().output().unwrap(),
+            "[From reviewer] approved"
+        );
 
-        // clear the results map
-        results.clear();
+        let writer_results = workflow.get_iteration_results("writer").await.unwrap();
+        assert_eq!(
+            writer_results
+                .into_iter()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>(),
+            vec!["draft 1", "draft 2", "draft 3"]
+        );
+        let reviewer_results = workflow.get_iteration_results("reviewer").await.unwrap();
+        assert_eq!(reviewer_results.len(), 3);
+        assert_eq!(
+            workflow
+                .get_iteration_results("publisher")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
 
-        // third execution of agent1
-        let result3 = workflow
-            .execute_node(
-                agent1_idx,
-                "input3".to_owned(),
-                Arc::clone(&results),
-                Arc::clone(&edge_tracker),
-                Arc::clone(&processed_nodes),
+    #[tokio::test]
+    async fn test_feedback_loop_iteration_limit() {
+        let mut workflow = create_feedback_loop(Some(1));
+        let mut events = workflow.subscribe_events();
+
+        let results = workflow
+            .execute_workflow(&["writer"], "topic")
+            .await
+            .unwrap();
+        drop(workflow);
+
+        // The reviewer never approves the second draft, and the loop stops
+        assert_eq!(results.get("writer").unwrap().output().unwrap(), "draft 2");
+        assert_eq!(results.get("reviewer").unwrap().output().unwrap(), "revise");
+        assert!(!results.contains_key("publisher"));
+
+        let mut limit_reached = false;
+        while let Some(event) = events.recv().await {
+            if let WorkflowEvent::IterationLimitReached {
+                from,
+                to,
+                iterations,
+                ..
+            } = event
+            {
+                assert_eq!(
+                    (from.as_str(), to.as_str(), iterations),
+                    ("reviewer", "writer", 1)
+                );
+                limit_reached = true;
+            }
+        }
+        assert!(limit_reached);
+    }
+
+    #[test]
+    fn test_unbounded_cycle_rejected() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.allow_cycles();
+        workflow.register_agent(create_echo_agent("1", "agent1"));
+        workflow.register_agent(create_echo_agent("2", "agent2"));
+        workflow
+            .connect_agents("agent1", "agent2", Flow::default())
+            .unwrap();
+
+        // A back edge without condition
+        let result = workflow.connect_agents(
+            "agent2",
+            "agent1",
+            Flow {
+                max_iterations: Some(3),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(result, Err(GraphWorkflowError::UnboundedCycle(_))));
+
+        // A back edge without maximum number of iterations
+        let result = workflow.connect_agents(
+            "agent2",
+            "agent1",
+            Flow {
+                condition: Some(Condition::custom("always", |_| true)),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(result, Err(GraphWorkflowError::UnboundedCycle(_))));
+        assert_eq!(workflow.workflow.edge_count(), 1);
+
+        let result = workflow.connect_agents(
+            "agent2",
+            "agent1",
+            Flow {
+                condition: Some(Condition::custom("always", |_| true)),
+                max_iterations: Some(3),
+                ..Default::default()
+            },
+        );
+        assert!(result.is_ok());
+        assert!(workflow.detect_potential_deadlocks().is_empty());
+        assert!(
+            workflow
+                .export_workflow_dot()
+                .contains("[label=\"if always\", style=dashed]")
+        );
+
+        // Removing the back edge makes the graph acyclic again
+        workflow.disconnect_agents("agent2", "agent1").unwrap();
+        assert!(workflow.back_edges.is_empty());
+    }
+
+    #[test]
+    fn test_bounded_cycle_rejected_without_allow_cycles() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.register_agent(create_echo_agent("1", "agent1"));
+        workflow.register_agent(create_echo_agent("2", "agent2"));
+        workflow
+            .connect_agents("agent1", "agent2", Flow::default())
+            .unwrap();
+
+        let result = workflow.connect_agents(
+            "agent2",
+            "agent1",
+            Flow {
+                condition: Some(Condition::custom("always", |_| true)),
+                max_iterations: Some(3),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(result, Err(GraphWorkflowError::CycleDetected)));
+        assert_eq!(workflow.workflow.edge_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_output_streams_chunks() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+
+        workflow.register_agent(StreamingAgent::new(
+            "streamer",
+            &["Hello", ", ", "world"],
+            None,
+        ));
+        workflow.register_agent(create_echo_agent("2", "echo"));
+        workflow
+            .connect_agents("streamer", "echo", Flow::default())
+            .unwrap();
+
+        let mut output = workflow.subscribe_output();
+        let results = workflow
+            .execute_workflow(&["streamer"], "input")
+            .await
+            .unwrap();
+        drop(workflow);
+
+        // the streamed chunks are concatenated into the final result
+        assert_eq!(
+            results.get("streamer").unwrap().output().unwrap(),
+            "Hello, world"
+        );
+        assert!(
+            results
+                .get("echo")
+                .unwrap()
+                .output()
+                .unwrap()
+                .contains("Hello, world")
+        );
+
+        let mut chunks = Vec::new();
+        while let Some(chunk) = output.recv().await {
+            chunks.push((chunk.agent, chunk.chunk));
+        }
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[0], ("streamer".to_owned(), "Hello".to_owned()));
+        assert_eq!(chunks[2], ("streamer".to_owned(), "world".to_owned()));
+        // agents without incremental output send the whole response at once
+        assert_eq!(chunks[3].0, "echo");
+    }
+
+    #[tokio::test]
+    async fn test_streaming_agent_error() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+
+        workflow.register_agent(StreamingAgent::new(
+            "streamer",
+            &["partial"],
+            Some("stream broken"),
+        ));
+
+        let mut output = workflow.subscribe_output();
+        let results = workflow
+            .execute_workflow(&["streamer"], "input")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            *results.get("streamer").unwrap(),
+            NodeOutcome::Failed(GraphWorkflowError::AgentError(_))
+        ));
+        // chunks produced before the error are still delivered
+        assert_eq!(output.recv().await.unwrap().chunk, "partial");
+    }
+
+    const WORKFLOW_SPEC_YAML: &str = r#"
+name: research
+description: A research workflow
+failure_policy: route_to_fallback
+default_timeout: 5m
+max_concurrency: 2
+agents:
+  - name: researcher
+    provider: deepseek
+    model: deepseek-chat
+    system_prompt: You are a researcher.
+    temperature: 0.2
+    tools: [Add]
+    timeout: 10m
+  - name: writer
+    provider: deepseek
+    model: deepseek-chat
+    timeout: 1500ms
+    aggregator:
+      type: json_array
+  - name: fallback
+    provider: openai_compatible
+    model: llama3
+    base_url: http://localhost:11434/v1
+connections:
+  - from: researcher
+    to: writer
+    condition:
+      type: and
+      conditions:
+        - type: min_length
+          length: 10
+        - type: not
+          condition:
+            type: contains
+            keyword: error
+            case_insensitive: true
+    transform:
+      type: template
+      template: "Write about: {output}"
+  - from: researcher
+    to: fallback
+    on_error: true
+"#;
+
+    #[test]
+    fn test_workflow_spec_round_trip() {
+        let spec = WorkflowSpec::from_yaml(WORKFLOW_SPEC_YAML).unwrap();
+        assert_eq!(spec.agents.len(), 3);
+        assert_eq!(spec.agents[0].provider, ProviderKind::DeepSeek);
+        assert_eq!(spec.agents[0].tools, vec!["Add"]);
+        assert_eq!(spec.agents[0].timeout, Some(Duration::from_secs(600)));
+        // Timeouts keep their milliseconds
+        assert_eq!(spec.agents[1].timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(spec.agents[2].provider, ProviderKind::OpenAICompatible);
+        // OpenAI-compatible servers don't need an API key
+        assert!(spec.agents[2].build(&ToolRegistry::new()).is_ok());
+        assert_eq!(spec.failure_policy, FailurePolicy::RouteToFallback);
+        assert!(spec.connections[1].on_error);
+        assert_eq!(
+            spec.connections[0].condition.as_ref().unwrap().to_string(),
+            "(length >= 10 && !contains \"error\" (ignore case))"
+        );
+
+        // Building the agents needs the provider's API key, so wire the workflow as `build` does
+        let mut workflow = DAGWorkflow::new(spec.name.clone(), spec.description.clone());
+        workflow.set_failure_policy(spec.failure_policy);
+        assert_eq!(spec.default_timeout, Some(Duration::from_secs(300)));
+        workflow.set_default_timeout(spec.default_timeout.unwrap());
+        assert_eq!(spec.max_concurrency, NonZeroUsize::new(2));
+        workflow.set_max_concurrency(spec.max_concurrency.unwrap());
+        for agent in &spec.agents {
+            workflow.register_agent(create_echo_agent(&agent.name, &agent.name));
+            if let Some(aggregator) = &agent.aggregator {
+                workflow
+                    .set_aggregator(&agent.name, aggregator.clone())
+                    .unwrap();
+            }
+            workflow
+                .agent_specs
+                .insert(agent.name.clone(), agent.clone());
+        }
+        for agent in &spec.agents {
+            let node = *workflow.name_to_node.get(&agent.name).unwrap();
+            workflow.workflow[node].timeout = agent.timeout;
+        }
+        for connection in &spec.connections {
+            workflow
+                .connect_agents(
+                    &connection.from,
+                    &connection.to,
+                    Flow {
+                        transform: connection.transform.clone(),
+                        condition: connection.condition.clone(),
+                        on_error: connection.on_error,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+
+        let exported = workflow.to_spec().unwrap();
+        assert_eq!(exported.to_yaml().unwrap(), spec.to_yaml().unwrap());
+
+        // JSON and YAML describe the same workflow
+        let from_json = WorkflowSpec::from_json(&exported.to_json().unwrap()).unwrap();
+        assert_eq!(from_json.to_yaml().unwrap(), spec.to_yaml().unwrap());
+    }
+
+    #[test]
+    fn test_workflow_spec_tool_not_found() {
+        let spec = WorkflowSpec::from_yaml(WORKFLOW_SPEC_YAML).unwrap();
+        let result = spec.build(&ToolRegistry::new());
+        assert!(matches!(result, Err(WorkflowSpecError::ToolNotFound(tool)) if tool == "Add"));
+    }
+
+    #[test]
+    fn test_to_spec_unserializable() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.register_agent(create_echo_agent("1", "agent1"));
+        assert!(matches!(
+            workflow.to_spec(),
+            Err(WorkflowSpecError::AgentWithoutSpec(agent)) if agent == "agent1"
+        ));
+
+        let spec = WorkflowSpec::from_yaml(WORKFLOW_SPEC_YAML).unwrap();
+        workflow
+            .agent_specs
+            .insert("agent1".to_owned(), spec.agents[1].clone());
+        workflow.register_agent(create_echo_agent("2", "agent2"));
+        workflow
+            .agent_specs
+            .insert("agent2".to_owned(), spec.agents[2].clone());
+        workflow
+            .connect_agents(
+                "agent1",
+                "agent2",
+                Flow {
+                    transform: Some(Transform::custom("identity", |output| output)),
+                    ..Default::default()
+                },
             )
+            .unwrap();
+        assert!(matches!(
+            workflow.to_spec(),
+            Err(WorkflowSpecError::UnserializableFlow(..))
+        ));
+
+        // An agent registered again replaces the spec of the previous one
+        workflow.register_agent(create_echo_agent("3", "agent2"));
+        assert!(matches!(
+            workflow.to_spec(),
+            Err(WorkflowSpecError::AgentWithoutSpec(agent)) if agent == "agent2"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_workflow_spec_save_and_load() {
+        let spec = WorkflowSpec::from_yaml(WORKFLOW_SPEC_YAML).unwrap();
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+
+        for file in ["spec.json", "spec.yaml"] {
+            spec.save(dir.join(file)).await.unwrap();
+            let loaded = WorkflowSpec::load(dir.join(file)).await.unwrap();
+            assert_eq!(loaded.to_yaml().unwrap(), spec.to_yaml().unwrap());
+        }
+        assert!(matches!(
+            spec.save(dir.join("spec.toml")).await,
+            Err(WorkflowSpecError::UnsupportedFormat(_))
+        ));
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_workflow_events() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.register_agent(create_mock_agent("1", "a", "Agent A", "a"));
+        workflow.register_agent(create_mock_agent("2", "b", "Agent B", "b"));
+        workflow.register_agent(create_mock_agent("3", "c", "Agent C", "c"));
+
+        workflow.connect_agents("a", "b", Flow::default()).unwrap();
+        workflow
+            .connect_agents(
+                "a",
+                "c",
+                Flow {
+                    transform: None,
+                    condition: Some(Condition::contains("nonexistent")),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let mut events = workflow.subscribe_events();
+        workflow.execute_workflow(&["a"], "input").await.unwrap();
+        drop(workflow);
+
+        let mut received = Vec::new();
+        while let Some(event) = events.recv().await {
+            received.push(event);
+        }
+
+        assert!(matches!(
+            &received[0],
+            WorkflowEvent::NodeStarted { agent, .. } if agent == "a"
+        ));
+        assert!(matches!(
+            &received[1],
+            WorkflowEvent::NodeFinished { agent, .. } if agent == "a"
+        ));
+        assert!(received.iter().any(|event| matches!(
+            event,
+            WorkflowEvent::EdgeTaken { from, to, .. } if from == "a" && to == "b"
+        )));
+        assert!(received.iter().any(|event| matches!(
+            event,
+            WorkflowEvent::EdgeSkippedByCondition { from, to, .. } if from == "a" && to == "c"
+        )));
+        assert!(received.iter().any(|event| matches!(
+            event,
+            WorkflowEvent::NodeFinished { agent, .. } if agent == "b"
+        )));
+        assert!(!received.iter().any(|event| matches!(
+            event,
+            WorkflowEvent::NodeStarted { agent, .. } if agent == "c"
+        )));
+        assert!(matches!(
+            received.last().unwrap(),
+            WorkflowEvent::WorkflowFinished { workflow, .. } if workflow == "test"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_workflow_observer_node_failed() {
+        let mut workflow = DAGWorkflow::new("test", "Test workflow");
+        workflow.register_agent(create_mock_agent("1", "agent1", "First agent", "response1"));
+        workflow.register_agent(create_failing_agent("2", "agent2", "fail error"));
+        workflow
+            .connect_agents("agent1", "agent2", Flow::default())
+            .unwrap();
+
+        let failures = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let failures_clone = Arc::clone(&failures);
+        workflow.add_observer(Arc::new(move |event: &WorkflowEvent| {
+            if let WorkflowEvent::NodeFailed { agent, error, .. } = event {
+                failures_clone
+                    .lock()
+                    .unwrap()
+                    .push((agent.clone(), error.clone()));
+            }
+        }));
+
+        workflow
+            .execute_workflow(&["agent1"], "input")
             .await
             .unwrap();
 
-        // the results should contain the new call count, indicating that the agent was re-executed
-        assert_eq!(result3, "response for 'input3' (call #2)");
+        let failures = failures.lock().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, "agent2");
+        assert!(failures[0].1.contains("fail error"));
     }
 }
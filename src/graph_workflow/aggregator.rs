@@ -0,0 +1,180 @@
+//! Aggregation of the inputs of agents with several incoming flows
+//!
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+
+use super::expression::parse_json;
+
+/// An input delivered to an agent
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeInput {
+    /// Name of the agent which produced the input, `None` for the workflow input
+    pub agent: Option<String>,
+    /// The input
+    pub input: String,
+}
+
+/// How an agent combines the inputs of its incoming flows into a single input
+///
+/// Inputs are ordered by their source agents, in the order the agents were registered, with the
+/// workflow input first. A single workflow input is always passed as is.
+///
+/// Except for [`Aggregator::Custom`], aggregators are serializable, so they can be saved in a
+/// [`WorkflowSpec`](super::WorkflowSpec).
+#[allow(clippy::type_complexity)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Aggregator {
+    /// Render each input with a template and join them with a separator
+    ///
+    /// The workflow input is not rendered with the template.
+    Concat {
+        /// The template, `{agent}` is replaced with the source agent and `{input}` with the input
+        template: String,
+        /// The separator between the inputs
+        separator: String,
+    },
+    /// A JSON array of `{"agent": ..., "input": ...}` objects, `agent` is `null` for the workflow
+    /// input
+    ///
+    /// Inputs which are JSON are embedded as JSON, other inputs as strings.
+    JsonArray,
+    /// A JSON object mapping the source agents to their inputs, the workflow input is under `input`
+    ///
+    /// Inputs which are JSON are embedded as JSON, other inputs as strings.
+    JsonObject,
+    /// Run the agent as soon as the first input arrives, with that input only
+    ///
+    /// Inputs arriving later are ignored, unless the agent is part of a feedback loop.
+    FirstArrived,
+    /// The input most of the source agents agree on, ignoring surrounding whitespace
+    ///
+    /// Ties are broken by the order of the inputs.
+    MajorityVote,
+    /// A named closure, which can't be serialized
+    #[serde(skip)]
+    Custom {
+        /// Name of the aggregator, used to display it
+        name: String,
+        /// The closure
+        func: Arc<dyn Fn(&[NodeInput]) -> String + Send + Sync>,
+    },
+}
+
+impl Default for Aggregator {
+    /// Inputs formatted as `[From agent] input`, separated by `---` lines
+    fn default() -> Self {
+        Self::concat("[From {agent}] {input}", "\n\n---\n\n")
+    }
+}
+
+impl Aggregator {
+    /// Render each input with a template and join them with a separator
+    pub fn concat(template: impl Into<String>, separator: impl Into<String>) -> Self {
+        Self::Concat {
+            template: template.into(),
+            separator: separator.into(),
+        }
+    }
+
+    /// A named closure, which can't be serialized
+    pub fn custom(
+        name: impl Into<String>,
+        func: impl Fn(&[NodeInput]) -> String + Send + Sync + 'static,
+    ) -> Self {
+        Self::Custom {
+            name: name.into(),
+            func: Arc::new(func),
+        }
+    }
+
+    /// Combine the inputs of an agent
+    pub fn aggregate(&self, inputs: &[NodeInput]) -> String {
+        if let [NodeInput { agent: None, input }] = inputs {
+            return input.clone();
+        }
+
+        match self {
+            Self::Concat {
+                template,
+                separator,
+            } => inputs
+                .iter()
+                .map(|input| match &input.agent {
+                    Some(agent) => template
+                        .replace("{agent}", agent)
+                        .replace("{input}", &input.input),
+                    None => input.input.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(separator),
+            Self::JsonArray => Value::Array(
+                inputs
+                    .iter()
+                    .map(|input| json!({ "agent": input.agent, "input": to_json(&input.input) }))
+                    .collect(),
+            )
+            .to_string(),
+            Self::JsonObject => Value::Object(
+                inputs
+                    .iter()
+                    .map(|input| {
+                        let key = input.agent.clone().unwrap_or_else(|| "input".to_owned());
+                        (key, to_json(&input.input))
+                    })
+                    .collect::<Map<_, _>>(),
+            )
+            .to_string(),
+            Self::FirstArrived => inputs
+                .first()
+                .map(|input| input.input.clone())
+                .unwrap_or_default(),
+            Self::MajorityVote => {
+                let mut votes = HashMap::new();
+                for input in inputs {
+                    *votes.entry(input.input.trim()).or_insert(0) += 1;
+                }
+                let most = votes.values().copied().max().unwrap_or_default();
+                inputs
+                    .iter()
+                    .find(|input| votes.get(input.input.trim()) == Some(&most))
+                    .map(|input| input.input.trim().to_owned())
+                    .unwrap_or_default()
+            }
+            Self::Custom { func, .. } => func(inputs),
+        }
+    }
+
+    /// Whether the aggregator can be serialized, i.e. it is not a custom closure
+    pub fn is_serializable(&self) -> bool {
+        !matches!(self, Self::Custom { .. })
+    }
+}
+
+impl fmt::Debug for Aggregator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Concat {
+                template,
+                separator,
+            } => f
+                .debug_struct("Concat")
+                .field("template", template)
+                .field("separator", separator)
+                .finish(),
+            Self::JsonArray => f.write_str("JsonArray"),
+            Self::JsonObject => f.write_str("JsonObject"),
+            Self::FirstArrived => f.write_str("FirstArrived"),
+            Self::MajorityVote => f.write_str("MajorityVote"),
+            Self::Custom { name, .. } => f.debug_tuple("Custom").field(name).finish(),
+        }
+    }
+}
+
+/// Embed an input as JSON if it is JSON, otherwise as a string
+fn to_json(input: &str) -> Value {
+    parse_json(input).unwrap_or_else(|| Value::String(input.to_owned()))
+}
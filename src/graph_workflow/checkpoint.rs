@@ -0,0 +1,65 @@
+//! Checkpoints of the workflow execution
+//!
+
+use std::{collections::HashMap, path::Path};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::persistence::{self, PersistenceError};
+
+/// The progress of a workflow execution, saved after each agent completes
+///
+/// Resuming from a checkpoint replays the saved outputs instead of running the agents again,
+/// so only the agents which failed or never ran are executed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkflowCheckpoint {
+    /// Name of the workflow
+    pub workflow: String,
+    /// Names of the agents the workflow started from
+    pub start_agents: Vec<String>,
+    /// The input of the workflow
+    pub input: String,
+    /// Outputs of the successful runs of each agent, in the order of the runs
+    pub outputs: HashMap<String, Vec<String>>,
+    /// When the checkpoint was last updated
+    pub timestamp: DateTime<Local>,
+}
+
+impl WorkflowCheckpoint {
+    pub(super) fn new(workflow: String, start_agents: Vec<String>, input: String) -> Self {
+        Self {
+            workflow,
+            start_agents,
+            input,
+            outputs: HashMap::new(),
+            timestamp: Local::now(),
+        }
+    }
+
+    /// Save the checkpoint to a file as JSON, optionally compressed with zstd
+    pub async fn save(
+        &self,
+        path: impl AsRef<Path>,
+        compress: bool,
+    ) -> Result<(), PersistenceError> {
+        let json = serde_json::to_vec(self)?;
+        let data = if compress {
+            persistence::compress(json)?
+        } else {
+            json
+        };
+        persistence::save_to_file(data, path).await
+    }
+
+    /// Load a checkpoint from a file, `compress` must match the setting used to save it
+    pub async fn load(path: impl AsRef<Path>, compress: bool) -> Result<Self, PersistenceError> {
+        let data = persistence::load_from_file(path).await?;
+        let json = if compress {
+            persistence::decompress(data)?
+        } else {
+            data
+        };
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
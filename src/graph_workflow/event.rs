@@ -0,0 +1,127 @@
+//! Events emitted during the workflow execution
+//!
+
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+/// An event emitted during the workflow execution
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum WorkflowEvent {
+    /// An agent started running
+    NodeStarted {
+        /// Name of the agent
+        agent: String,
+        /// When the agent started
+        timestamp: DateTime<Local>,
+    },
+    /// An agent finished successfully
+    NodeFinished {
+        /// Name of the agent
+        agent: String,
+        /// When the agent finished
+        timestamp: DateTime<Local>,
+        /// How long the agent ran
+        duration: Duration,
+    },
+    /// An agent failed or timed out
+    NodeFailed {
+        /// Name of the agent
+        agent: String,
+        /// The error message
+        error: String,
+        /// When the agent failed
+        timestamp: DateTime<Local>,
+        /// How long the agent ran before failing
+        duration: Duration,
+    },
+    /// An agent was not run again, its output was restored from the checkpoint
+    NodeRestored {
+        /// Name of the agent
+        agent: String,
+        /// When the output was restored
+        timestamp: DateTime<Local>,
+    },
+    /// An agent did not run because an upstream agent failed
+    NodeSkipped {
+        /// Name of the agent
+        agent: String,
+        /// Why the agent was skipped
+        reason: String,
+        /// When the agent was skipped
+        timestamp: DateTime<Local>,
+    },
+    /// An agent was canceled while running
+    NodeCanceled {
+        /// Name of the agent
+        agent: String,
+        /// When the agent was canceled
+        timestamp: DateTime<Local>,
+    },
+    /// The output of an agent was passed along a flow, its condition (if any) was met
+    EdgeTaken {
+        /// Name of the source agent
+        from: String,
+        /// Name of the target agent
+        to: String,
+        /// When the flow was taken
+        timestamp: DateTime<Local>,
+    },
+    /// A flow was not taken because its condition was not met
+    EdgeSkippedByCondition {
+        /// Name of the source agent
+        from: String,
+        /// Name of the target agent
+        to: String,
+        /// When the condition was evaluated
+        timestamp: DateTime<Local>,
+    },
+    /// A back edge was not taken because its maximum number of iterations was reached
+    IterationLimitReached {
+        /// Name of the source agent
+        from: String,
+        /// Name of the target agent
+        to: String,
+        /// How many times the back edge has been taken
+        iterations: u32,
+        /// When the limit was reached
+        timestamp: DateTime<Local>,
+    },
+    /// The whole workflow finished
+    WorkflowFinished {
+        /// Name of the workflow
+        workflow: String,
+        /// When the workflow finished
+        timestamp: DateTime<Local>,
+        /// How long the workflow ran
+        duration: Duration,
+    },
+}
+
+/// Observe the execution of a workflow
+///
+/// Observers are called synchronously from the executor, so they should return quickly.
+/// Heavy work should be moved elsewhere, e.g. through the channel implementation of this trait.
+pub trait WorkflowObserver: Send + Sync {
+    /// Called for every event emitted during the execution
+    fn on_event(&self, event: &WorkflowEvent);
+}
+
+impl WorkflowObserver for mpsc::UnboundedSender<WorkflowEvent> {
+    fn on_event(&self, event: &WorkflowEvent) {
+        // A dropped receiver only means no one is interested in the events anymore
+        let _ = self.send(event.clone());
+    }
+}
+
+impl<F> WorkflowObserver for F
+where
+    F: Fn(&WorkflowEvent) + Send + Sync,
+{
+    fn on_event(&self, event: &WorkflowEvent) {
+        self(event)
+    }
+}
@@ -0,0 +1,417 @@
+//! Conditions and transforms of flows
+//!
+
+use std::{fmt, ops, sync::Arc};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A condition deciding whether a flow is taken, evaluated with the output of the source agent
+///
+/// Except for [`Condition::Custom`], conditions are serializable, so they can be saved in a
+/// [`WorkflowSpec`](super::WorkflowSpec) or produced by a planner. Conditions are composed
+/// with [`Condition::and`], [`Condition::or`] and `!`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    /// The output contains the keyword
+    Contains {
+        /// The keyword to look for
+        keyword: String,
+        /// Whether to ignore the case
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        case_insensitive: bool,
+    },
+    /// The output matches the regular expression
+    Regex {
+        /// The regular expression
+        #[serde(with = "serde_regex")]
+        pattern: Regex,
+    },
+    /// The output is JSON and the value at the pointer equals the expected value
+    JsonPointerEquals {
+        /// A JSON pointer, e.g. `/review/approved`
+        pointer: String,
+        /// The expected value
+        value: Value,
+    },
+    /// The output has at least `length` characters
+    MinLength {
+        /// The minimum number of characters
+        length: usize,
+    },
+    /// The output has at most `length` characters
+    MaxLength {
+        /// The maximum number of characters
+        length: usize,
+    },
+    /// All conditions are met
+    And {
+        /// The conditions
+        conditions: Vec<Condition>,
+    },
+    /// Any of the conditions is met
+    Or {
+        /// The conditions
+        conditions: Vec<Condition>,
+    },
+    /// The condition is not met
+    Not {
+        /// The negated condition
+        condition: Box<Condition>,
+    },
+    /// A named closure, which can't be serialized
+    #[serde(skip)]
+    Custom {
+        /// Name of the condition, used to display it
+        name: String,
+        /// The closure
+        func: Arc<dyn Fn(&str) -> bool + Send + Sync>,
+    },
+}
+
+impl Condition {
+    /// The output contains the keyword
+    pub fn contains(keyword: impl Into<String>) -> Self {
+        Self::Contains {
+            keyword: keyword.into(),
+            case_insensitive: false,
+        }
+    }
+
+    /// The output contains the keyword, ignoring the case
+    pub fn contains_ignore_case(keyword: impl Into<String>) -> Self {
+        Self::Contains {
+            keyword: keyword.into(),
+            case_insensitive: true,
+        }
+    }
+
+    /// The output matches the regular expression
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self::Regex {
+            pattern: Regex::new(pattern)?,
+        })
+    }
+
+    /// The output is JSON and the value at the pointer equals the expected value
+    pub fn json_pointer_equals(pointer: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::JsonPointerEquals {
+            pointer: pointer.into(),
+            value: value.into(),
+        }
+    }
+
+    /// The output has at least `length` characters
+    pub fn min_length(length: usize) -> Self {
+        Self::MinLength { length }
+    }
+
+    /// The output has at most `length` characters
+    pub fn max_length(length: usize) -> Self {
+        Self::MaxLength { length }
+    }
+
+    /// A named closure, which can't be serialized
+    pub fn custom(
+        name: impl Into<String>,
+        func: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self::Custom {
+            name: name.into(),
+            func: Arc::new(func),
+        }
+    }
+
+    /// Both this and the other condition are met
+    pub fn and(self, other: Condition) -> Self {
+        match self {
+            Self::And { mut conditions } => {
+                conditions.push(other);
+                Self::And { conditions }
+            }
+            _ => Self::And {
+                conditions: vec![self, other],
+            },
+        }
+    }
+
+    /// This or the other condition is met
+    pub fn or(self, other: Condition) -> Self {
+        match self {
+            Self::Or { mut conditions } => {
+                conditions.push(other);
+                Self::Or { conditions }
+            }
+            _ => Self::Or {
+                conditions: vec![self, other],
+            },
+        }
+    }
+
+    /// Evaluate the condition with the output of an agent
+    pub fn evaluate(&self, output: &str) -> bool {
+        match self {
+            Self::Contains {
+                keyword,
+                case_insensitive: false,
+            } => output.contains(keyword.as_str()),
+            Self::Contains {
+                keyword,
+                case_insensitive: true,
+            } => output.to_lowercase().contains(&keyword.to_lowercase()),
+            Self::Regex { pattern } => pattern.is_match(output),
+            Self::JsonPointerEquals { pointer, value } => parse_json(output)
+                .is_some_and(|json| json.pointer(pointer).is_some_and(|found| found == value)),
+            Self::MinLength { length } => output.chars().count() >= *length,
+            Self::MaxLength { length } => output.chars().count() <= *length,
+            Self::And { conditions } => conditions.iter().all(|cond| cond.evaluate(output)),
+            Self::Or { conditions } => conditions.iter().any(|cond| cond.evaluate(output)),
+            Self::Not { condition } => !condition.evaluate(output),
+            Self::Custom { func, .. } => func(output),
+        }
+    }
+
+    /// Whether the condition can be serialized, i.e. it contains no custom closure
+    pub fn is_serializable(&self) -> bool {
+        match self {
+            Self::And { conditions } | Self::Or { conditions } => {
+                conditions.iter().all(Condition::is_serializable)
+            }
+            Self::Not { condition } => condition.is_serializable(),
+            Self::Custom { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+impl ops::Not for Condition {
+    type Output = Condition;
+
+    fn not(self) -> Self::Output {
+        match self {
+            Self::Not { condition } => *condition,
+            _ => Self::Not {
+                condition: Box::new(self),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Contains {
+                keyword,
+                case_insensitive: false,
+            } => write!(f, "contains {keyword:?}"),
+            Self::Contains {
+                keyword,
+                case_insensitive: true,
+            } => write!(f, "contains {keyword:?} (ignore case)"),
+            Self::Regex { pattern } => write!(f, "matches /{pattern}/"),
+            Self::JsonPointerEquals { pointer, value } => write!(f, "{pointer} == {value}"),
+            Self::MinLength { length } => write!(f, "length >= {length}"),
+            Self::MaxLength { length } => write!(f, "length <= {length}"),
+            Self::And { conditions } => write_joined(f, conditions, " && "),
+            Self::Or { conditions } => write_joined(f, conditions, " || "),
+            Self::Not { condition } => write!(f, "!{condition}"),
+            Self::Custom { name, .. } => f.write_str(name),
+        }
+    }
+}
+
+/// A closure is an unnamed [`Condition::Custom`], displayed as `custom`
+impl<F> From<F> for Condition
+where
+    F: Fn(&str) -> bool + Send + Sync + 'static,
+{
+    fn from(func: F) -> Self {
+        Self::custom("custom", func)
+    }
+}
+
+impl fmt::Debug for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Condition({self})")
+    }
+}
+
+/// A transform applied to the output of the source agent before passing it to the target agent
+///
+/// Except for [`Transform::Custom`], transforms are serializable, so they can be saved in a
+/// [`WorkflowSpec`](super::WorkflowSpec) or produced by a planner.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Transform {
+    /// Render a template, `{output}` is replaced with the output
+    Template {
+        /// The template, e.g. `Review the following draft:\n{output}`
+        template: String,
+    },
+    /// Extract a field from the output, if the output is JSON and the field exists
+    ///
+    /// Strings are passed without quotes, other values as JSON.
+    /// The output is passed unchanged if the field can't be extracted.
+    JsonField {
+        /// A JSON pointer, e.g. `/draft/content`
+        pointer: String,
+    },
+    /// Apply the transforms in order
+    Chain {
+        /// The transforms
+        transforms: Vec<Transform>,
+    },
+    /// A named closure, which can't be serialized
+    #[serde(skip)]
+    Custom {
+        /// Name of the transform, used to display it
+        name: String,
+        /// The closure
+        func: Arc<dyn Fn(String) -> String + Send + Sync>,
+    },
+}
+
+impl Transform {
+    /// Render a template, `{output}` is replaced with the output
+    pub fn template(template: impl Into<String>) -> Self {
+        Self::Template {
+            template: template.into(),
+        }
+    }
+
+    /// Extract a field from the output, if the output is JSON and the field exists
+    pub fn json_field(pointer: impl Into<String>) -> Self {
+        Self::JsonField {
+            pointer: pointer.into(),
+        }
+    }
+
+    /// A named closure, which can't be serialized
+    pub fn custom(
+        name: impl Into<String>,
+        func: impl Fn(String) -> String + Send + Sync + 'static,
+    ) -> Self {
+        Self::Custom {
+            name: name.into(),
+            func: Arc::new(func),
+        }
+    }
+
+    /// Apply the other transform after this one
+    pub fn then(self, other: Transform) -> Self {
+        match self {
+            Self::Chain { mut transforms } => {
+                transforms.push(other);
+                Self::Chain { transforms }
+            }
+            _ => Self::Chain {
+                transforms: vec![self, other],
+            },
+        }
+    }
+
+    /// Apply the transform to the output of an agent
+    pub fn apply(&self, output: String) -> String {
+        match self {
+            Self::Template { template } => template.replace("{output}", &output),
+            Self::JsonField { pointer } => {
+                match parse_json(&output).and_then(|json| json.pointer(pointer).cloned()) {
+                    Some(Value::String(field)) => field,
+                    Some(field) => field.to_string(),
+                    None => output,
+                }
+            }
+            Self::Chain { transforms } => transforms
+                .iter()
+                .fold(output, |output, transform| transform.apply(output)),
+            Self::Custom { func, .. } => func(output),
+        }
+    }
+
+    /// Whether the transform can be serialized, i.e. it contains no custom closure
+    pub fn is_serializable(&self) -> bool {
+        match self {
+            Self::Chain { transforms } => transforms.iter().all(Transform::is_serializable),
+            Self::Custom { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Template { template } => write!(f, "template {template:?}"),
+            Self::JsonField { pointer } => write!(f, "field {pointer}"),
+            Self::Chain { transforms } => write_joined(f, transforms, " | "),
+            Self::Custom { name, .. } => f.write_str(name),
+        }
+    }
+}
+
+/// A closure is an unnamed [`Transform::Custom`], displayed as `custom`
+impl<F> From<F> for Transform
+where
+    F: Fn(String) -> String + Send + Sync + 'static,
+{
+    fn from(func: F) -> Self {
+        Self::custom("custom", func)
+    }
+}
+
+impl fmt::Debug for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transform({self})")
+    }
+}
+
+/// Write the items separated by `separator` within parentheses
+fn write_joined<T: fmt::Display>(
+    f: &mut fmt::Formatter<'_>,
+    items: &[T],
+    separator: &str,
+) -> fmt::Result {
+    f.write_str("(")?;
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            f.write_str(separator)?;
+        }
+        write!(f, "{item}")?;
+    }
+    f.write_str(")")
+}
+
+/// Parse the output as JSON, LLMs often wrap it in a markdown code block
+pub(super) fn parse_json(output: &str) -> Option<Value> {
+    let output = output.trim();
+    let output = output
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .map_or(output, |code| {
+            // Skip the language of the code block, e.g. ```json
+            code.split_once('\n').map_or(code, |(_, code)| code)
+        });
+    serde_json::from_str(output).ok()
+}
+
+/// Serialize regular expressions as their patterns
+mod serde_regex {
+    use regex::Regex;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub(super) fn serialize<S: Serializer>(
+        regex: &Regex,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(regex.as_str())
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Regex, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern).map_err(D::Error::custom)
+    }
+}
@@ -0,0 +1,400 @@
+//! Declarative definition of workflows
+//!
+
+use std::{collections::HashMap, num::NonZeroUsize, path::Path, sync::Arc, time::Duration};
+
+use rig::tool::{Tool, ToolDyn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{
+    Aggregator, Condition, DAGWorkflow, DEFAULT_TIMEOUT, FailurePolicy, Flow, GraphWorkflowError,
+    Transform,
+};
+use crate::{
+    agent::{Agent, AgentConfig, AgentError},
+    llm_provider::{LLMProvider, ModelConfig},
+    persistence::{self, PersistenceError},
+    rig_agent::RigAgent,
+};
+
+/// Error type for workflow specs
+#[allow(missing_docs)]
+#[derive(Debug, Error)]
+pub enum WorkflowSpecError {
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("YAML error: {0}")]
+    YamlError(#[from] serde_norway::Error),
+    #[error("Persistence error: {0}")]
+    PersistenceError(#[from] PersistenceError),
+    #[error("Agent error: {0}")]
+    AgentError(#[from] AgentError),
+    #[error("Graph workflow error: {0}")]
+    GraphWorkflowError(#[from] GraphWorkflowError),
+    #[error("Tool not found: {0}")]
+    ToolNotFound(String),
+    #[error("Unsupported spec format: {0}")]
+    UnsupportedFormat(String),
+    #[error("Agent '{0}' was not built from a spec")]
+    AgentWithoutSpec(String),
+    #[error("Flow from '{0}' to '{1}' can't be described by a spec")]
+    UnserializableFlow(String, String),
+    #[error("Aggregator of agent '{0}' can't be described by a spec")]
+    UnserializableAggregator(String),
+}
+
+/// A serializable description of a [`DAGWorkflow`]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkflowSpec {
+    /// Name of the workflow
+    pub name: String,
+    /// Description of the workflow
+    #[serde(default)]
+    pub description: String,
+    /// Agents of the workflow
+    pub agents: Vec<AgentSpec>,
+    /// Flows between the agents
+    #[serde(default)]
+    pub connections: Vec<ConnectionSpec>,
+    /// Whether flows are allowed to form feedback loops
+    #[serde(default)]
+    pub allow_cycles: bool,
+    /// What to do when an agent fails
+    #[serde(default)]
+    pub failure_policy: FailurePolicy,
+    /// Timeout of the agents without their own timeout, a human-readable duration, 60 minutes
+    /// when not set
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "humantime_serde"
+    )]
+    pub default_timeout: Option<Duration>,
+    /// Maximum number of agents running at the same time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrency: Option<NonZeroUsize>,
+}
+
+/// A serializable description of an agent
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AgentSpec {
+    /// Name of the agent
+    pub name: String,
+    /// Description of the agent
+    #[serde(default)]
+    pub description: String,
+    /// The LLM provider
+    pub provider: ProviderKind,
+    /// The model of the provider
+    pub model: String,
+    /// The base URL of the API, required for OpenAI-compatible providers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    /// System prompt of the agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    /// Temperature of the model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// Maximum tokens of the model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    /// Names of the tools, which must be registered in the [`ToolRegistry`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<String>,
+    /// Timeout of the agent, a human-readable duration, e.g. `90s`, `10m` or `1500ms`
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "humantime_serde"
+    )]
+    pub timeout: Option<Duration>,
+    /// How the agent combines the inputs of its incoming flows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregator: Option<Aggregator>,
+}
+
+/// The LLM provider of an agent
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    Anthropic,
+    DeepSeek,
+    Gemini,
+    OpenAI,
+    OpenRouter,
+    #[serde(rename = "openai_compatible")]
+    OpenAICompatible,
+}
+
+/// A serializable description of a flow between two agents
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectionSpec {
+    /// Name of the source agent
+    pub from: String,
+    /// Name of the target agent
+    pub to: String,
+    /// Condition to determine if the flow should be taken
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<Condition>,
+    /// Transformation to apply to the output before passing it to the target agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<Transform>,
+    /// Maximum number of times the flow can be taken if it closes a feedback loop
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_iterations: Option<u32>,
+    /// Overrides the workflow's failure policy when the source agent fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure_policy: Option<FailurePolicy>,
+    /// Whether this is an error flow
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub on_error: bool,
+}
+
+/// Tools which can be referenced by name in an [`AgentSpec`]
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: HashMap<String, Arc<dyn ToolDyn>>,
+}
+
+impl ToolRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a tool under its name
+    pub fn register(&mut self, tool: impl Tool + 'static) {
+        self.tools.insert(Tool::name(&tool), Arc::new(tool));
+    }
+
+    fn get(&self, name: &str) -> Result<Arc<dyn ToolDyn>, WorkflowSpecError> {
+        self.tools
+            .get(name)
+            .map(Arc::clone)
+            .ok_or_else(|| WorkflowSpecError::ToolNotFound(name.to_owned()))
+    }
+}
+
+impl AgentSpec {
+    /// Build the agent, its tools are looked up in the registry
+    pub fn build(&self, tools: &ToolRegistry) -> Result<Arc<dyn Agent>, WorkflowSpecError> {
+        // Resolve the tools first, so a missing tool is reported before the provider is set up
+        let tools = self
+            .tools
+            .iter()
+            .map(|tool| tools.get(tool))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut provider = match self.provider {
+            ProviderKind::Anthropic => LLMProvider::anthropic(&self.model),
+            ProviderKind::DeepSeek => LLMProvider::deepseek(&self.model),
+            ProviderKind::Gemini => LLMProvider::gemini(&self.model),
+            ProviderKind::OpenAI => LLMProvider::openai(&self.model),
+            ProviderKind::OpenRouter => LLMProvider::openrouter(&self.model),
+            ProviderKind::OpenAICompatible => {
+                LLMProvider::OpenAICompatible(ModelConfig::new(&self.model))
+            }
+        };
+        if let Some(base_url) = &self.base_url {
+            provider = provider.with_base_url(base_url);
+        }
+
+        let mut config = AgentConfig::builder()
+            .agent_name(&self.name)
+            .description(&self.description);
+        if let Some(system_prompt) = &self.system_prompt {
+            config = config.system_prompt(system_prompt);
+        }
+        if let Some(temperature) = self.temperature {
+            config = config.temperature(temperature);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            config = config.max_tokens(max_tokens);
+        }
+
+        Ok(RigAgent::from_provider_with_tools(
+            provider,
+            config.build(),
+            tools,
+        )?)
+    }
+}
+
+impl WorkflowSpec {
+    /// Parse a spec from JSON
+    pub fn from_json(json: &str) -> Result<Self, WorkflowSpecError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Serialize the spec to pretty JSON
+    pub fn to_json(&self) -> Result<String, WorkflowSpecError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Parse a spec from YAML
+    pub fn from_yaml(yaml: &str) -> Result<Self, WorkflowSpecError> {
+        Ok(serde_norway::from_str(yaml)?)
+    }
+
+    /// Serialize the spec to YAML
+    pub fn to_yaml(&self) -> Result<String, WorkflowSpecError> {
+        Ok(serde_norway::to_string(self)?)
+    }
+
+    /// Load a spec from a `.json`, `.yaml` or `.yml` file
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self, WorkflowSpecError> {
+        let data = persistence::load_from_file(&path).await?;
+        let data = String::from_utf8_lossy(&data);
+        match Format::of(path.as_ref())? {
+            Format::Json => Self::from_json(&data),
+            Format::Yaml => Self::from_yaml(&data),
+        }
+    }
+
+    /// Save the spec to a `.json`, `.yaml` or `.yml` file
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<(), WorkflowSpecError> {
+        let data = match Format::of(path.as_ref())? {
+            Format::Json => self.to_json()?,
+            Format::Yaml => self.to_yaml()?,
+        };
+        Ok(persistence::save_to_file(data, path).await?)
+    }
+
+    /// Build the workflow, the tools of the agents are looked up in the registry
+    pub fn build(&self, tools: &ToolRegistry) -> Result<DAGWorkflow, WorkflowSpecError> {
+        let mut workflow = DAGWorkflow::new(self.name.clone(), self.description.clone());
+        if self.allow_cycles {
+            workflow.allow_cycles();
+        }
+        workflow.set_failure_policy(self.failure_policy);
+        if let Some(timeout) = self.default_timeout {
+            workflow.set_default_timeout(timeout);
+        }
+        if let Some(max_concurrency) = self.max_concurrency {
+            workflow.set_max_concurrency(max_concurrency);
+        }
+
+        for spec in &self.agents {
+            let agent = spec.build(tools)?;
+            match spec.timeout {
+                Some(timeout) => workflow.register_agent_with_timeout(agent, timeout),
+                None => workflow.register_agent(agent),
+            }
+            if let Some(aggregator) = &spec.aggregator {
+                workflow.set_aggregator(&spec.name, aggregator.clone())?;
+            }
+            workflow.agent_specs.insert(spec.name.clone(), spec.clone());
+        }
+
+        for connection in &self.connections {
+            workflow.connect_agents(
+                &connection.from,
+                &connection.to,
+                Flow {
+                    transform: connection.transform.clone(),
+                    condition: connection.condition.clone(),
+                    max_iterations: connection.max_iterations,
+                    failure_policy: connection.failure_policy,
+                    on_error: connection.on_error,
+                },
+            )?;
+        }
+
+        Ok(workflow)
+    }
+}
+
+impl DAGWorkflow {
+    /// Describe the workflow as a spec
+    ///
+    /// Only workflows built from a [`WorkflowSpec`] can be described, agents registered
+    /// directly, and custom transforms, conditions or aggregators have no serializable form.
+    pub fn to_spec(&self) -> Result<WorkflowSpec, WorkflowSpecError> {
+        let mut agents = Vec::new();
+        for node in self.workflow.node_weights() {
+            let spec = self
+                .agent_specs
+                .get(&node.name)
+                .ok_or_else(|| WorkflowSpecError::AgentWithoutSpec(node.name.clone()))?;
+            if node
+                .aggregator
+                .as_ref()
+                .is_some_and(|aggregator| !aggregator.is_serializable())
+            {
+                return Err(WorkflowSpecError::UnserializableAggregator(
+                    node.name.clone(),
+                ));
+            }
+            agents.push(AgentSpec {
+                timeout: node.timeout,
+                aggregator: node.aggregator.clone(),
+                ..spec.clone()
+            });
+        }
+
+        let mut connections = Vec::new();
+        for edge in self.workflow.edge_indices() {
+            let (Some((source, target)), Some(flow)) = (
+                self.workflow.edge_endpoints(edge),
+                self.workflow.edge_weight(edge),
+            ) else {
+                continue;
+            };
+            let from = self.node_name(source);
+            let to = self.node_name(target);
+            let serializable = flow
+                .transform
+                .as_ref()
+                .is_none_or(Transform::is_serializable)
+                && flow
+                    .condition
+                    .as_ref()
+                    .is_none_or(Condition::is_serializable);
+            if !serializable {
+                return Err(WorkflowSpecError::UnserializableFlow(from, to));
+            }
+            connections.push(ConnectionSpec {
+                from,
+                to,
+                condition: flow.condition.clone(),
+                transform: flow.transform.clone(),
+                max_iterations: flow.max_iterations,
+                failure_policy: flow.failure_policy,
+                on_error: flow.on_error,
+            });
+        }
+
+        Ok(WorkflowSpec {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            agents,
+            connections,
+            allow_cycles: self.allow_cycles,
+            failure_policy: self.failure_policy,
+            default_timeout: (self.default_timeout != DEFAULT_TIMEOUT)
+                .then_some(self.default_timeout),
+            max_concurrency: self.max_concurrency,
+        })
+    }
+}
+
+/// File formats of specs
+enum Format {
+    Json,
+    Yaml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Result<Self, WorkflowSpecError> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(Format::Json),
+            Some("yaml" | "yml") => Ok(Format::Yaml),
+            _ => Err(WorkflowSpecError::UnsupportedFormat(
+                path.to_string_lossy().into_owned(),
+            )),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Run a workflow as an agent
+//!
+
+use futures::future::{self, BoxFuture};
+use tokio::sync::{Mutex, MutexGuard};
+
+use super::{Aggregator, DAGWorkflow, GraphWorkflowError, NodeInput, NodeOutcome};
+use crate::agent::{Agent, AgentError};
+
+/// Runs a [`DAGWorkflow`] as an [`Agent`], so a whole workflow can be registered as a single
+/// agent of another workflow
+///
+/// The task is the input of the start agents, and the output is the output of the output agents.
+/// Output agents which were not reached are ignored, the outputs of several output agents are
+/// combined with an [`Aggregator`]. The agent fails if the workflow fails, if an output agent
+/// fails or is skipped, or if no output agent was reached.
+pub struct WorkflowAgent {
+    id: String,
+    name: String,
+    description: String,
+    /// Executions of the workflow need exclusive access, concurrent tasks run one by one
+    workflow: Mutex<DAGWorkflow>,
+    start_agents: Vec<String>,
+    output_agents: Vec<String>,
+    aggregator: Aggregator,
+}
+
+impl WorkflowAgent {
+    /// Create an agent named after the workflow
+    pub fn new(
+        workflow: DAGWorkflow,
+        start_agents: &[&str],
+        output_agents: &[&str],
+    ) -> Result<Self, GraphWorkflowError> {
+        for agent in start_agents.iter().chain(output_agents) {
+            if !workflow.agents.contains_key(*agent) {
+                return Err(GraphWorkflowError::AgentNotFound(format!(
+                    "Agent '{agent}' not found"
+                )));
+            }
+        }
+
+        Ok(Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: workflow.name.clone(),
+            description: workflow.description.clone(),
+            workflow: Mutex::new(workflow),
+            start_agents: start_agents
+                .iter()
+                .map(|agent| (*agent).to_owned())
+                .collect(),
+            output_agents: output_agents
+                .iter()
+                .map(|agent| (*agent).to_owned())
+                .collect(),
+            aggregator: Aggregator::default(),
+        })
+    }
+
+    /// Set the name of the agent, which must be unique in the parent workflow
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Set the description of the agent
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = description.into();
+        self
+    }
+
+    /// Set how the outputs of several output agents are combined
+    pub fn with_aggregator(mut self, aggregator: Aggregator) -> Self {
+        self.aggregator = aggregator;
+        self
+    }
+
+    /// The nested workflow, e.g. to inspect the results of its agents after a run
+    pub async fn workflow(&self) -> MutexGuard<'_, DAGWorkflow> {
+        self.workflow.lock().await
+    }
+}
+
+impl Agent for WorkflowAgent {
+    fn run(&self, task: String) -> BoxFuture<'_, Result<String, AgentError>> {
+        Box::pin(async move {
+            let start_agents = self
+                .start_agents
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            let results = self
+                .workflow
+                .lock()
+                .await
+                .execute_workflow(&start_agents, task)
+                .await
+                .map_err(|e| AgentError::WorkflowError(e.to_string()))?;
+
+            let mut outputs = Vec::new();
+            for agent in &self.output_agents {
+                let Some(outcome) = results.get(agent) else {
+                    continue;
+                };
+                match outcome.value() {
+                    NodeOutcome::Succeeded(output) => outputs.push(NodeInput {
+                        agent: Some(agent.clone()),
+                        input: output.clone(),
+                    }),
+                    NodeOutcome::Failed(e) => {
+                        return Err(AgentError::WorkflowError(format!(
+                            "Output agent '{agent}' failed: {e}"
+                        )));
+                    }
+                    NodeOutcome::Skipped(reason) => {
+                        return Err(AgentError::WorkflowError(format!(
+                            "Output agent '{agent}' was skipped: {reason}"
+                        )));
+                    }
+                    NodeOutcome::Canceled => {
+                        return Err(AgentError::WorkflowError(format!(
+                            "Output agent '{agent}' was canceled"
+                        )));
+                    }
+                }
+            }
+
+            match outputs.as_slice() {
+                [] => Err(AgentError::WorkflowError(format!(
+                    "No output agent of workflow '{}' was reached",
+                    self.name
+                ))),
+                [output] => Ok(output.input.clone()),
+                outputs => Ok(self.aggregator.aggregate(outputs)),
+            }
+        })
+    }
+
+    fn run_multiple_tasks(
+        &mut self,
+        tasks: Vec<String>,
+    ) -> BoxFuture<'_, Result<Vec<String>, AgentError>> {
+        let agent = &*self;
+        Box::pin(future::try_join_all(
+            tasks.into_iter().map(move |task| agent.run(task)),
+        ))
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn description(&self) -> String {
+        self.description.clone()
+    }
+}
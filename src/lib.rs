@@ -198,10 +198,15 @@
 //!
 
 pub mod agent;
+pub mod context_window;
 pub mod conversation;
+pub mod fallback_agent;
 pub mod graph_workflow;
 pub mod llm_provider;
+pub mod memory;
 pub mod persistence;
+pub mod rate_limit;
+pub mod retry;
 pub mod rig_agent;
 pub mod team_workflow;
 
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use rig::{
     agent::AgentBuilder,
     providers::{
@@ -8,6 +10,8 @@ use rig::{
 };
 use thiserror::Error;
 
+use crate::rate_limit::RateLimiter;
+
 #[derive(Clone)]
 pub enum LLMProvider {
     Anthropic(ModelConfig),
@@ -15,6 +19,9 @@ pub enum LLMProvider {
     Gemini(ModelConfig),
     OpenAI(ModelConfig),
     OpenRouter(ModelConfig),
+    /// An OpenAI-compatible API, e.g. vLLM, Ollama, LM Studio, Together or a self-hosted
+    /// gateway, the base URL is required and the API key optional
+    OpenAICompatible(ModelConfig),
 }
 
 macro_rules! impl_agent_builder {
@@ -23,7 +30,7 @@ macro_rules! impl_agent_builder {
             let LLMProvider::$variant(config) = self else {
                 return Err(LLMProviderError::LLMProviderNotMatch);
             };
-            let client = <$client>::from_env();
+            let client = <$client>::from_config(config)?;
             Ok(client.agent(&config.model))
         }
     };
@@ -44,38 +51,30 @@ macro_rules! impl_agent_builder_auto {
 
 impl LLMProvider {
     pub fn anthropic(model: impl Into<String>) -> Self {
-        Self::Anthropic(ModelConfig {
-            model: model.into(),
-            stream: false,
-        })
+        Self::Anthropic(ModelConfig::new(model))
     }
 
     pub fn deepseek(model: impl Into<String>) -> Self {
-        Self::DeepSeek(ModelConfig {
-            model: model.into(),
-            stream: false,
-        })
+        Self::DeepSeek(ModelConfig::new(model))
     }
 
     pub fn gemini(model: impl Into<String>) -> Self {
-        Self::Gemini(ModelConfig {
-            model: model.into(),
-            stream: false,
-        })
+        Self::Gemini(ModelConfig::new(model))
     }
 
     pub fn openai(model: impl Into<String>) -> Self {
-        Self::OpenAI(ModelConfig {
-            model: model.into(),
-            stream: false,
-        })
+        Self::OpenAI(ModelConfig::new(model))
     }
 
     pub fn openrouter(model: impl Into<String>) -> Self {
-        Self::OpenRouter(ModelConfig {
-            model: model.into(),
-            stream: false,
-        })
+        Self::OpenRouter(ModelConfig::new(model))
+    }
+
+    /// An OpenAI-compatible API at `base_url`, e.g. `http://localhost:11434/v1` for Ollama
+    ///
+    /// Set an API key with [`LLMProvider::with_api_key`] if the server requires one.
+    pub fn openai_compatible(base_url: impl Into<String>, model: impl Into<String>) -> Self {
+        Self::OpenAICompatible(ModelConfig::new(model)).with_base_url(base_url)
     }
 
     pub fn get_config(&self) -> &ModelConfig {
@@ -84,10 +83,47 @@ impl LLMProvider {
             | LLMProvider::DeepSeek(config)
             | LLMProvider::Gemini(config)
             | LLMProvider::OpenAI(config)
-            | LLMProvider::OpenRouter(config) => config,
+            | LLMProvider::OpenRouter(config)
+            | LLMProvider::OpenAICompatible(config) => config,
+        }
+    }
+
+    pub fn get_config_mut(&mut self) -> &mut ModelConfig {
+        match self {
+            LLMProvider::Anthropic(config)
+            | LLMProvider::DeepSeek(config)
+            | LLMProvider::Gemini(config)
+            | LLMProvider::OpenAI(config)
+            | LLMProvider::OpenRouter(config)
+            | LLMProvider::OpenAICompatible(config) => config,
         }
     }
 
+    /// Set the API key, instead of reading it from the provider's environment variable, e.g.
+    /// `DEEPSEEK_API_KEY`
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.get_config_mut().api_key = Some(api_key.into());
+        self
+    }
+
+    /// Set the base URL of the API, e.g. a proxy, a gateway or a local mock server
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.get_config_mut().base_url = Some(base_url.into());
+        self
+    }
+
+    /// Enable streaming completion for agents built from this provider
+    pub fn with_stream(mut self) -> Self {
+        self.get_config_mut().stream = true;
+        self
+    }
+
+    /// Limit the requests of all agents built from this provider, see [`RateLimiter`]
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.get_config_mut().rate_limiter = Some(rate_limiter);
+        self
+    }
+
     impl_agent_builder_auto!(
         Anthropic,
         anthropic::Client,
@@ -101,16 +137,273 @@ impl LLMProvider {
     impl_agent_builder_auto!(OpenAI, openai::Client, openai::CompletionModel);
 
     impl_agent_builder_auto!(OpenRouter, openrouter::Client, openrouter::CompletionModel);
+
+    pub fn get_openai_compatible_agent_builder(
+        &self,
+    ) -> Result<AgentBuilder<openai::CompletionModel>, LLMProviderError> {
+        let LLMProvider::OpenAICompatible(config) = self else {
+            return Err(LLMProviderError::LLMProviderNotMatch);
+        };
+        let base_url = config
+            .base_url
+            .as_deref()
+            .ok_or(LLMProviderError::MissingBaseUrl)?;
+        // Local servers usually don't check the API key
+        let api_key = config.api_key.as_deref().unwrap_or_default();
+        let client = openai::Client::from_url(api_key, base_url);
+        Ok(client.agent(&config.model))
+    }
 }
 
+impl fmt::Display for LLMProvider {
+    /// The provider and its model, e.g. `deepseek/deepseek-chat`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let provider = match self {
+            LLMProvider::Anthropic(_) => "anthropic",
+            LLMProvider::DeepSeek(_) => "deepseek",
+            LLMProvider::Gemini(_) => "gemini",
+            LLMProvider::OpenAI(_) => "openai",
+            LLMProvider::OpenRouter(_) => "openrouter",
+            LLMProvider::OpenAICompatible(_) => "openai_compatible",
+        };
+        write!(f, "{}/{}", provider, self.get_config().model)
+    }
+}
+
+/// The configuration of a provider's model and client
+///
+/// Extra HTTP headers are not supported yet: the rig 0.11 clients build their own HTTP client
+/// with the provider's headers and don't accept another one. They can be added once rig accepts
+/// a custom HTTP client, until then a proxy or a gateway at the base URL has to add them.
 #[derive(Clone)]
 pub struct ModelConfig {
     pub model: String,
     pub stream: bool,
+    pub rate_limiter: Option<RateLimiter>,
+    /// The API key, read from the provider's environment variable if unset
+    pub api_key: Option<String>,
+    /// The base URL of the API, the provider's official API if unset
+    pub base_url: Option<String>,
+}
+
+impl ModelConfig {
+    pub fn new(model: impl Into<String>) -> Self {
+        Self {
+            model: model.into(),
+            stream: false,
+            rate_limiter: None,
+            api_key: None,
+            base_url: None,
+        }
+    }
+}
+
+/// A client of a provider, built from a [ModelConfig]
+trait ConfiguredClient: Sized {
+    /// The environment variable holding the API key
+    const API_KEY_ENV: &'static str;
+
+    fn new(api_key: &str, base_url: Option<&str>) -> Self;
+
+    fn from_config(config: &ModelConfig) -> Result<Self, LLMProviderError> {
+        let api_key = match &config.api_key {
+            Some(api_key) => api_key.clone(),
+            None => std::env::var(Self::API_KEY_ENV)
+                .map_err(|_| LLMProviderError::MissingApiKey(Self::API_KEY_ENV))?,
+        };
+        Ok(Self::new(&api_key, config.base_url.as_deref()))
+    }
+}
+
+impl ConfiguredClient for anthropic::Client {
+    const API_KEY_ENV: &'static str = "ANTHROPIC_API_KEY";
+
+    fn new(api_key: &str, base_url: Option<&str>) -> Self {
+        let builder = anthropic::ClientBuilder::new(api_key);
+        match base_url {
+            Some(base_url) => builder.base_url(base_url).build(),
+            None => builder.build(),
+        }
+    }
+}
+
+macro_rules! impl_configured_client {
+    ($client:ty, $env:literal) => {
+        impl ConfiguredClient for $client {
+            const API_KEY_ENV: &'static str = $env;
+
+            fn new(api_key: &str, base_url: Option<&str>) -> Self {
+                match base_url {
+                    Some(base_url) => <$client>::from_url(api_key, base_url),
+                    None => <$client>::new(api_key),
+                }
+            }
+        }
+    };
 }
 
+impl_configured_client!(deepseek::Client, "DEEPSEEK_API_KEY");
+impl_configured_client!(gemini::Client, "GEMINI_API_KEY");
+impl_configured_client!(openai::Client, "OPENAI_API_KEY");
+impl_configured_client!(openrouter::Client, "OPENROUTER_API_KEY");
+
 #[derive(Debug, Error)]
 pub enum LLMProviderError {
     #[error("LLM provider not match")]
     LLMProviderNotMatch,
+    #[error("API key not set, set it on the provider or in the {0} environment variable")]
+    MissingApiKey(&'static str),
+    #[error("Base URL not set, an OpenAI-compatible provider requires one")]
+    MissingBaseUrl,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        agent::{Agent, AgentConfig},
+        rig_agent::RigAgent,
+    };
+
+    /// Answer a single chat completion request with `content`, returns the request
+    pub(crate) async fn serve_completion(listener: &TcpListener, content: &str) -> String {
+        let response = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "mock",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": "stop",
+            }],
+        });
+        serve_response(listener, "200 OK", &response.to_string()).await
+    }
+
+    /// Answer a single request with the `status` and the JSON `body`, returns the request
+    pub(crate) async fn serve_response(listener: &TcpListener, status: &str, body: &str) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        respond(stream, status, body).await
+    }
+
+    /// Answer the request of an accepted connection, returns the request
+    pub(crate) async fn respond(mut stream: TcpStream, status: &str, body: &str) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        // Read the headers, then the body
+        let body_len = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).to_lowercase();
+            if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                let content_length = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |len| len.trim().parse::<usize>().unwrap());
+                break content_length.saturating_sub(body.len());
+            }
+        };
+        let mut request_body = vec![0; body_len];
+        stream.read_exact(&mut request_body).await.unwrap();
+        request.extend_from_slice(&request_body);
+
+        let response = format!(
+            "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api_key_and_base_url() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { serve_completion(&listener, "Hi there").await });
+
+        let provider = LLMProvider::openai("mock")
+            .with_api_key("test-key")
+            .with_base_url(base_url);
+        let agent = RigAgent::openai_builder()
+            .provider(provider)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(agent.run("Hello".to_owned()).await.unwrap(), "Hi there");
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /chat/completions"));
+        assert!(request.contains("authorization: Bearer test-key"));
+        assert!(request.contains("Hello"));
+    }
+
+    #[tokio::test]
+    async fn test_openai_compatible() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { serve_completion(&listener, "Hi there").await });
+
+        let provider = LLMProvider::openai_compatible(base_url, "llama3");
+        assert_eq!(provider.to_string(), "openai_compatible/llama3");
+        let agent = RigAgent::openai_builder()
+            .provider(provider)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(agent.run("Hello".to_owned()).await.unwrap(), "Hi there");
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /v1/chat/completions"));
+        assert!(request.contains(r#""model":"llama3""#));
+
+        let provider = LLMProvider::OpenAICompatible(ModelConfig::new("llama3"));
+        assert!(matches!(
+            provider.get_openai_compatible_agent_builder(),
+            Err(LLMProviderError::MissingBaseUrl)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_from_provider() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { serve_completion(&listener, "Hi there").await });
+
+        let config = AgentConfig::builder()
+            .agent_name("greeter")
+            .system_prompt("You greet people.")
+            .build();
+        let agent =
+            RigAgent::from_provider(LLMProvider::openai_compatible(base_url, "llama3"), config)
+                .unwrap();
+        assert_eq!(agent.name(), "greeter");
+        assert_eq!(agent.run("Hello".to_owned()).await.unwrap(), "Hi there");
+
+        let request = server.await.unwrap();
+        assert!(request.contains("You greet people."));
+    }
+
+    #[test]
+    fn test_missing_api_key() {
+        // The key can't be removed from the environment safely, only check when it is unset
+        if std::env::var("GEMINI_API_KEY").is_err() {
+            let result = LLMProvider::gemini("gemini-2.0-flash").get_gemini_agent_builder();
+            assert!(matches!(
+                result,
+                Err(LLMProviderError::MissingApiKey("GEMINI_API_KEY"))
+            ));
+        }
+
+        let result = LLMProvider::gemini("gemini-2.0-flash")
+            .with_api_key("test-key")
+            .get_gemini_agent_builder();
+        assert!(result.is_ok());
+    }
 }
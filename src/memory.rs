@@ -0,0 +1,374 @@
+//! Strategies to keep the short memory of an agent bounded
+//!
+#![deny(missing_docs)]
+
+use std::{ops::Range, sync::Arc};
+
+use serde::Serialize;
+
+use crate::{
+    agent::{Agent, AgentError},
+    conversation::{AgentShortMemory, Content, Message, Role},
+};
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+pub use store::{FileStore, InMemoryStore, MemoryError, MemoryStore};
+
+/// The default instructions given to the summarizer, followed by the messages to summarize
+pub const DEFAULT_SUMMARY_PROMPT: &str = "Summarize the following conversation concisely. \
+    Keep the facts, decisions, results and open questions needed to continue it.";
+
+/// Compresses the older messages of a conversation into a summary, once it gets too long
+///
+/// When a conversation has more than `max_messages` messages, every message except the
+/// original task and the `keep_recent` most recent messages is sent to the summarizer agent.
+/// The summary replaces them as a [`Role::System`] message, so it is saved with the task state
+/// and kept when the history is truncated to fit the context window. Later summaries include
+/// the previous one.
+#[derive(Clone, Serialize)]
+pub struct SummarizingMemory {
+    #[serde(skip)]
+    summarizer: Arc<dyn Agent>,
+    max_messages: usize,
+    keep_recent: usize,
+    prompt: String,
+}
+
+impl SummarizingMemory {
+    /// Summarize with `summarizer`, once a conversation has more than 20 messages, keeping the 6
+    /// most recent ones
+    pub fn new(summarizer: Arc<dyn Agent>) -> Self {
+        Self {
+            summarizer,
+            max_messages: 20,
+            keep_recent: 6,
+            prompt: DEFAULT_SUMMARY_PROMPT.to_owned(),
+        }
+    }
+
+    /// Summarize once a conversation has more than `max_messages` messages
+    pub fn max_messages(mut self, max_messages: usize) -> Self {
+        self.max_messages = max_messages;
+        self
+    }
+
+    /// The number of recent messages which are not summarized
+    pub fn keep_recent(mut self, keep_recent: usize) -> Self {
+        self.keep_recent = keep_recent;
+        self
+    }
+
+    /// The instructions given to the summarizer, followed by the messages to summarize
+    pub fn prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.prompt = prompt.into();
+        self
+    }
+
+    /// Summarize the older messages of the conversation of `task`, if it is too long
+    ///
+    /// Returns whether messages were summarized. The conversation is not locked while the
+    /// summarizer runs, messages appended meanwhile are kept. If the summarized messages were
+    /// changed meanwhile, e.g. the conversation was replaced, the summary is discarded.
+    ///
+    /// A summarizer which fails or gives an empty summary is an error, and the conversation is
+    /// kept as is.
+    pub async fn compress(
+        &self,
+        short_memory: &AgentShortMemory,
+        task: &str,
+    ) -> Result<bool, AgentError> {
+        let Some((range, summarized)) =
+            short_memory.get(task).await?.and_then(|mut conversation| {
+                let range = self.range_to_summarize(&conversation.history)?;
+                conversation.history.truncate(range.end);
+                Some((range, conversation.history))
+            })
+        else {
+            return Ok(false);
+        };
+        let messages = summarized[range.clone()]
+            .iter()
+            .map(|message| format!("{}: {}", message.role, message.content))
+            .collect::<Vec<_>>();
+
+        let summary = self
+            .summarizer
+            .run_checked(format!("{}\n\n{}", self.prompt, messages.join("\n")))
+            .await?;
+        if summary.trim().is_empty() {
+            return Err(AgentError::EmptySummary);
+        }
+        let summary = Message::new(
+            Role::System,
+            Content::Text(format!("Summary of the earlier conversation: {summary}")),
+        );
+
+        // Replaced in one store operation, so the messages appended meanwhile are kept
+        let summarized = short_memory
+            .update(task, move |mut conversation| {
+                let unchanged = conversation.history.len() >= summarized.len()
+                    && summarized
+                        .iter()
+                        .zip(&conversation.history)
+                        .all(|(before, now)| is_same_message(before, now));
+                if !unchanged {
+                    return None;
+                }
+                conversation
+                    .history
+                    .splice(range.clone(), [summary.clone()]);
+                Some(conversation)
+            })
+            .await?;
+        Ok(summarized)
+    }
+
+    /// The messages to summarize, `None` if the history is short enough
+    fn range_to_summarize(&self, history: &[Message]) -> Option<Range<usize>> {
+        if history.len() <= self.max_messages {
+            return None;
+        }
+
+        // The original task is kept
+        let start = usize::from(matches!(
+            history.first().map(|message| &message.role),
+            Some(Role::User(_))
+        ));
+        // Tool results are kept with their calls
+        let mut end = history.len().saturating_sub(self.keep_recent);
+        while end > start && end < history.len() && history[end].is_tool_result() {
+            end -= 1;
+        }
+        // Summarizing a single message, e.g. the previous summary, doesn't shorten anything
+        (end >= start + 2).then_some(start..end)
+    }
+}
+
+/// Whether two snapshots of a message are the same message
+fn is_same_message(a: &Message, b: &Message) -> bool {
+    a.timestamp == b.timestamp
+        && a.metadata == b.metadata
+        && a.content.to_string() == b.content.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::BoxFuture;
+
+    use super::*;
+    use crate::agent::tests::{MockAgent, mock_agent, provider_error};
+
+    fn summarizer(times: usize) -> Arc<dyn Agent> {
+        Arc::new(mock_agent(times, |prompt| {
+            let lines = prompt.lines().filter(|line| line.contains(": ")).count();
+            Ok(format!("{lines} messages"))
+        }))
+    }
+
+    async fn short_memory(answers: usize) -> AgentShortMemory {
+        let short_memory = AgentShortMemory::new();
+        short_memory
+            .add("task", "writer", Role::User("user".to_owned()), "task")
+            .await
+            .unwrap();
+        for answer in 0..answers {
+            short_memory
+                .add(
+                    "task",
+                    "writer",
+                    Role::Assistant("writer".to_owned()),
+                    format!("answer {answer}"),
+                )
+                .await
+                .unwrap();
+        }
+        short_memory
+    }
+
+    async fn texts(short_memory: &AgentShortMemory) -> Vec<String> {
+        short_memory
+            .get("task")
+            .await
+            .unwrap()
+            .unwrap()
+            .history
+            .iter()
+            .map(|message| message.content.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_compress() {
+        let memory = SummarizingMemory::new(summarizer(2))
+            .max_messages(5)
+            .keep_recent(2)
+            .prompt("Summarize");
+
+        let short_memory = short_memory(4).await;
+        assert!(!memory.compress(&short_memory, "task").await.unwrap());
+        assert!(!memory.compress(&short_memory, "other").await.unwrap());
+
+        short_memory
+            .add(
+                "task",
+                "writer",
+                Role::Assistant("writer".to_owned()),
+                "answer 4",
+            )
+            .await
+            .unwrap();
+        assert!(memory.compress(&short_memory, "task").await.unwrap());
+        assert_eq!(
+            texts(&short_memory).await,
+            [
+                "task",
+                "Summary of the earlier conversation: 3 messages",
+                "answer 3",
+                "answer 4"
+            ]
+        );
+
+        // The previous summary is summarized again
+        for answer in 5..7 {
+            short_memory
+                .add(
+                    "task",
+                    "writer",
+                    Role::Assistant("writer".to_owned()),
+                    format!("answer {answer}"),
+                )
+                .await
+                .unwrap();
+        }
+        assert!(memory.compress(&short_memory, "task").await.unwrap());
+        assert_eq!(
+            texts(&short_memory).await,
+            [
+                "task",
+                "Summary of the earlier conversation: 3 messages",
+                "answer 5",
+                "answer 6"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failing_summarizer() {
+        let failing = mock_agent(1, |_| Err(provider_error("Service Unavailable")));
+        let empty = mock_agent(1, |_| Ok(" \n".to_owned()));
+        for summarizer in [failing, empty] {
+            let memory = SummarizingMemory::new(Arc::new(summarizer))
+                .max_messages(3)
+                .keep_recent(1);
+            let short_memory = short_memory(3).await;
+            assert!(memory.compress(&short_memory, "task").await.is_err());
+            // The conversation is kept as is
+            assert_eq!(
+                texts(&short_memory).await,
+                ["task", "answer 0", "answer 1", "answer 2"]
+            );
+        }
+    }
+
+    /// A summarizer which changes the conversation while it runs
+    fn changing_summarizer(
+        change: impl Fn() -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    ) -> SummarizingMemory {
+        let mut agent = MockAgent::new();
+        agent.expect_run().times(1).returning(move |_| {
+            let changed = change();
+            Box::pin(async move {
+                changed.await;
+                Ok("summary".to_owned())
+            })
+        });
+        SummarizingMemory::new(Arc::new(agent))
+            .max_messages(3)
+            .keep_recent(1)
+    }
+
+    #[tokio::test]
+    async fn test_history_changed_while_summarizing() {
+        // Messages appended meanwhile are kept
+        let appended = short_memory(3).await;
+        let memory = changing_summarizer({
+            let appended = appended.clone();
+            move || {
+                let appended = appended.clone();
+                Box::pin(async move {
+                    appended
+                        .add("task", "writer", Role::User("user".to_owned()), "question")
+                        .await
+                        .unwrap();
+                })
+            }
+        });
+        assert!(memory.compress(&appended, "task").await.unwrap());
+        assert_eq!(
+            texts(&appended).await,
+            [
+                "task",
+                "Summary of the earlier conversation: summary",
+                "answer 2",
+                "question"
+            ]
+        );
+
+        // The summary is discarded if the summarized messages were replaced meanwhile
+        let replaced = short_memory(3).await;
+        let memory = changing_summarizer({
+            let replaced = replaced.clone();
+            move || {
+                let replaced = replaced.clone();
+                Box::pin(async move {
+                    replaced.evict("task").await.unwrap();
+                    replaced
+                        .add("task", "writer", Role::User("user".to_owned()), "new task")
+                        .await
+                        .unwrap();
+                })
+            }
+        });
+        assert!(!memory.compress(&replaced, "task").await.unwrap());
+        assert_eq!(texts(&replaced).await, ["new task"]);
+    }
+
+    #[tokio::test]
+    async fn test_tool_results_stay_with_calls() {
+        let memory = SummarizingMemory::new(summarizer(1))
+            .max_messages(3)
+            .keep_recent(1);
+        let short_memory = short_memory(2).await;
+        short_memory
+            .add_content(
+                "task",
+                "writer",
+                Role::Assistant("writer".to_owned()),
+                Content::tool_call("call_1", "search", serde_json::json!({})),
+            )
+            .await
+            .unwrap();
+        short_memory
+            .add_content(
+                "task",
+                "writer",
+                Role::User("search".to_owned()),
+                Content::tool_result("call_1", "result"),
+            )
+            .await
+            .unwrap();
+        assert!(memory.compress(&short_memory, "task").await.unwrap());
+        assert_eq!(
+            texts(&short_memory).await[2..],
+            [
+                "[Tool call call_1: search({})]",
+                "[Tool result call_1]: result"
+            ]
+        );
+    }
+}
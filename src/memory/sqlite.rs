@@ -0,0 +1,203 @@
+//! A memory store in a SQLite database
+//!
+
+use std::{path::Path, sync::Mutex, time::Duration};
+
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+
+use super::store::{MemoryError, MemoryStore, lock};
+use crate::conversation::{Conversation, Message};
+
+/// Keeps conversations in a SQLite database, which several processes can share
+///
+/// Messages are appended as rows, so processes appending to the same task concurrently don't
+/// lose messages.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open the database at `path`, which is created if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, MemoryError> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Open a database in memory, e.g. for tests
+    pub fn open_in_memory() -> Result<Self, MemoryError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(connection: Connection) -> Result<Self, MemoryError> {
+        // Wait for the other processes instead of failing right away
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS conversations (
+                task TEXT PRIMARY KEY,
+                agent_name TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                task TEXT NOT NULL,
+                message TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS messages_task ON messages (task, id);",
+        )?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn read(connection: &Connection, task: &str) -> Result<Option<Conversation>, MemoryError> {
+        let Some(agent_name) = connection
+            .query_row(
+                "SELECT agent_name FROM conversations WHERE task = ?1",
+                params![task],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let mut conversation = Conversation::new(agent_name);
+        let mut statement =
+            connection.prepare("SELECT message FROM messages WHERE task = ?1 ORDER BY id")?;
+        let messages = statement.query_map(params![task], |row| row.get::<_, String>(0))?;
+        for message in messages {
+            conversation
+                .history
+                .push(serde_json::from_str::<Message>(&message?)?);
+        }
+        Ok(Some(conversation))
+    }
+
+    /// Replace the conversation of a task, within a transaction
+    fn write(
+        connection: &Connection,
+        task: &str,
+        conversation: &Conversation,
+    ) -> Result<(), MemoryError> {
+        let messages = conversation
+            .history
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        connection.execute(
+            "INSERT OR REPLACE INTO conversations (task, agent_name) VALUES (?1, ?2)",
+            params![task, conversation.agent_name()],
+        )?;
+        connection.execute("DELETE FROM messages WHERE task = ?1", params![task])?;
+        for message in messages {
+            connection.execute(
+                "INSERT INTO messages (task, message) VALUES (?1, ?2)",
+                params![task, message],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl MemoryStore for SqliteStore {
+    fn get(&self, task: &str) -> Result<Option<Conversation>, MemoryError> {
+        Self::read(&lock(&self.connection), task)
+    }
+
+    fn append(&self, task: &str, agent_name: &str, message: Message) -> Result<(), MemoryError> {
+        let message = serde_json::to_string(&message)?;
+        let mut connection = lock(&self.connection);
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT OR IGNORE INTO conversations (task, agent_name) VALUES (?1, ?2)",
+            params![task, agent_name],
+        )?;
+        transaction.execute(
+            "INSERT INTO messages (task, message) VALUES (?1, ?2)",
+            params![task, message],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn put(&self, task: &str, conversation: Conversation) -> Result<(), MemoryError> {
+        let mut connection = lock(&self.connection);
+        let transaction = connection.transaction()?;
+        Self::write(&transaction, task, &conversation)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        task: &str,
+        update: &mut dyn FnMut(Conversation) -> Option<Conversation>,
+    ) -> Result<bool, MemoryError> {
+        let mut connection = lock(&self.connection);
+        // Take the write lock before reading, so other processes can't append in between
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let Some(updated) = Self::read(&transaction, task)?.and_then(update) else {
+            return Ok(false);
+        };
+        Self::write(&transaction, task, &updated)?;
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    fn list(&self) -> Result<Vec<String>, MemoryError> {
+        let connection = lock(&self.connection);
+        let mut statement = connection.prepare("SELECT task FROM conversations ORDER BY task")?;
+        let tasks = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(tasks)
+    }
+
+    fn evict(&self, task: &str) -> Result<bool, MemoryError> {
+        let mut connection = lock(&self.connection);
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM messages WHERE task = ?1", params![task])?;
+        let removed =
+            transaction.execute("DELETE FROM conversations WHERE task = ?1", params![task])?;
+        transaction.commit()?;
+        Ok(removed > 0)
+    }
+
+    fn clear(&self) -> Result<(), MemoryError> {
+        lock(&self.connection).execute_batch("DELETE FROM messages; DELETE FROM conversations;")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        conversation::{Content, Role},
+        memory::store::tests::check_store,
+    };
+
+    #[test]
+    fn test_sqlite_store() {
+        check_store(&SqliteStore::open_in_memory().unwrap());
+
+        // Processes sharing the database see each other's messages
+        let path = std::env::temp_dir().join(format!("{}.db", uuid::Uuid::new_v4()));
+        let first = SqliteStore::open(&path).unwrap();
+        let second = SqliteStore::open(&path).unwrap();
+        for (store, text) in [(&first, "first"), (&second, "second")] {
+            store
+                .append(
+                    "task",
+                    "writer",
+                    Message::new(
+                        Role::User("user".to_owned()),
+                        Content::Text(text.to_owned()),
+                    ),
+                )
+                .unwrap();
+        }
+        let history = first.get("task").unwrap().unwrap().history;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].content.to_string(), "second");
+        std::fs::remove_file(path).unwrap();
+    }
+}
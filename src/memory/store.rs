@@ -0,0 +1,420 @@
+//! Backends storing the conversations of the short memory
+//!
+
+use std::{
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use twox_hash::XxHash3_64;
+
+use crate::conversation::{Conversation, ConversationError, ConversationFormat, Message};
+
+/// Errors of a [`MemoryStore`]
+#[derive(Debug, Error)]
+pub enum MemoryError {
+    /// The store couldn't be read or written
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    /// A stored conversation couldn't be read or written
+    #[error("Conversation error: {0}")]
+    ConversationError(#[from] ConversationError),
+    /// A stored message couldn't be read or written
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    /// The SQLite database failed
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+}
+
+/// Stores the conversations of an agent's short memory, by task
+///
+/// Implementations are shared by the clones of an agent, and possibly by several agents or
+/// processes, so they synchronize their own access.
+///
+/// The operations are synchronous, [`AgentShortMemory`](crate::conversation::AgentShortMemory)
+/// runs them on the blocking threads of tokio if the store [blocks](MemoryStore::blocks).
+pub trait MemoryStore: Send + Sync {
+    /// Whether the operations block the thread, e.g. on I/O or on a lock held by another process
+    fn blocks(&self) -> bool {
+        true
+    }
+
+    /// The conversation of a task
+    fn get(&self, task: &str) -> Result<Option<Conversation>, MemoryError>;
+
+    /// Append a message to the conversation of a task, which is created for `agent_name` if
+    /// needed
+    fn append(&self, task: &str, agent_name: &str, message: Message) -> Result<(), MemoryError>;
+
+    /// Replace the conversation of a task
+    fn put(&self, task: &str, conversation: Conversation) -> Result<(), MemoryError>;
+
+    /// Replace the conversation of a task with the one returned by `update`, if it returns one
+    ///
+    /// The conversation is read and replaced in one operation, so the messages appended
+    /// meanwhile are not lost. Returns whether the conversation was replaced, a task without a
+    /// conversation is not updated.
+    fn update(
+        &self,
+        task: &str,
+        update: &mut dyn FnMut(Conversation) -> Option<Conversation>,
+    ) -> Result<bool, MemoryError>;
+
+    /// The tasks which have a conversation
+    fn list(&self) -> Result<Vec<String>, MemoryError>;
+
+    /// Remove the conversation of a task, returns whether it existed
+    fn evict(&self, task: &str) -> Result<bool, MemoryError>;
+
+    /// Remove all conversations
+    fn clear(&self) -> Result<(), MemoryError>;
+}
+
+/// Lock a mutex, even if a thread panicked while holding it, the data stays consistent
+pub(super) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Keeps conversations in memory, optionally evicting the least recently used ones
+pub struct InMemoryStore {
+    conversations: Mutex<LruCache<String, Conversation>>,
+}
+
+impl InMemoryStore {
+    /// A store which never evicts conversations
+    pub fn new() -> Self {
+        Self {
+            conversations: Mutex::new(LruCache::unbounded()),
+        }
+    }
+
+    /// A store keeping the `capacity` most recently used conversations
+    pub fn with_capacity(capacity: NonZeroUsize) -> Self {
+        Self {
+            conversations: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore for InMemoryStore {
+    fn blocks(&self) -> bool {
+        false
+    }
+
+    fn get(&self, task: &str) -> Result<Option<Conversation>, MemoryError> {
+        Ok(lock(&self.conversations).get(task).cloned())
+    }
+
+    fn append(&self, task: &str, agent_name: &str, message: Message) -> Result<(), MemoryError> {
+        lock(&self.conversations)
+            .get_or_insert_mut(task.to_owned(), || Conversation::new(agent_name.to_owned()))
+            .add_message(message);
+        Ok(())
+    }
+
+    fn put(&self, task: &str, conversation: Conversation) -> Result<(), MemoryError> {
+        lock(&self.conversations).put(task.to_owned(), conversation);
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        task: &str,
+        update: &mut dyn FnMut(Conversation) -> Option<Conversation>,
+    ) -> Result<bool, MemoryError> {
+        let mut conversations = lock(&self.conversations);
+        let Some(conversation) = conversations.get_mut(task) else {
+            return Ok(false);
+        };
+        match update(conversation.clone()) {
+            Some(updated) => {
+                *conversation = updated;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, MemoryError> {
+        Ok(lock(&self.conversations)
+            .iter()
+            .map(|(task, _)| task.clone())
+            .collect())
+    }
+
+    fn evict(&self, task: &str) -> Result<bool, MemoryError> {
+        Ok(lock(&self.conversations).pop(task).is_some())
+    }
+
+    fn clear(&self) -> Result<(), MemoryError> {
+        lock(&self.conversations).clear();
+        Ok(())
+    }
+}
+
+/// The extension of the files of a [`FileStore`], which sets them apart from the state files
+const CONVERSATION_EXTENSION: &str = ".conversation.json";
+
+/// A conversation saved by a [`FileStore`], with its task
+#[derive(Serialize, Deserialize)]
+struct StoredConversation {
+    task: String,
+    /// The conversation exported as [`ConversationFormat::Json`]
+    conversation: serde_json::Value,
+}
+
+/// Keeps each conversation in a `.conversation.json` file of a directory
+///
+/// The directory may be shared with other files, e.g. the state files of the agents, only the
+/// conversation files are listed and cleared. Files are replaced atomically, so readers never
+/// see partial conversations. Processes sharing a directory may lose the messages appended
+/// concurrently to the same task, use a `SqliteStore` (feature `sqlite`) to share memory safely.
+///
+/// Appending a message reads and rewrites the whole conversation, so appends get slower as the
+/// conversation grows. A `SqliteStore` appends a single row instead.
+pub struct FileStore {
+    dir: PathBuf,
+    /// Serializes the read-modify-write of the files within the process
+    lock: Mutex<()>,
+}
+
+impl FileStore {
+    /// A store in `dir`, which is created if needed
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, MemoryError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, task: &str) -> PathBuf {
+        let mut hasher = XxHash3_64::default();
+        task.hash(&mut hasher);
+        self.dir
+            .join(format!("{:016x}{CONVERSATION_EXTENSION}", hasher.finish()))
+    }
+
+    fn read(path: &Path) -> Result<Option<StoredConversation>, MemoryError> {
+        match std::fs::read(path) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn load(stored: StoredConversation) -> Result<Conversation, MemoryError> {
+        let mut conversation = Conversation::new(String::new());
+        conversation.import(&stored.conversation.to_string(), ConversationFormat::Json)?;
+        Ok(conversation)
+    }
+
+    fn write(&self, task: &str, conversation: &Conversation) -> Result<(), MemoryError> {
+        let stored = StoredConversation {
+            task: task.to_owned(),
+            conversation: serde_json::from_str(&conversation.export(ConversationFormat::Json)?)?,
+        };
+        let path = self.path(task);
+        let temp = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+        std::fs::write(&temp, serde_json::to_vec(&stored)?)?;
+        std::fs::rename(temp, path)?;
+        Ok(())
+    }
+
+    /// The files of the stored conversations, skipping the other files of the directory
+    fn files(&self) -> Result<Vec<PathBuf>, MemoryError> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().ends_with(CONVERSATION_EXTENSION))
+            {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+}
+
+impl MemoryStore for FileStore {
+    fn get(&self, task: &str) -> Result<Option<Conversation>, MemoryError> {
+        let _lock = lock(&self.lock);
+        Self::read(&self.path(task))?.map(Self::load).transpose()
+    }
+
+    fn append(&self, task: &str, agent_name: &str, message: Message) -> Result<(), MemoryError> {
+        let _lock = lock(&self.lock);
+        let mut conversation = match Self::read(&self.path(task))? {
+            Some(stored) => Self::load(stored)?,
+            None => Conversation::new(agent_name.to_owned()),
+        };
+        conversation.add_message(message);
+        self.write(task, &conversation)
+    }
+
+    fn put(&self, task: &str, conversation: Conversation) -> Result<(), MemoryError> {
+        let _lock = lock(&self.lock);
+        self.write(task, &conversation)
+    }
+
+    fn update(
+        &self,
+        task: &str,
+        update: &mut dyn FnMut(Conversation) -> Option<Conversation>,
+    ) -> Result<bool, MemoryError> {
+        let _lock = lock(&self.lock);
+        let Some(stored) = Self::read(&self.path(task))? else {
+            return Ok(false);
+        };
+        match update(Self::load(stored)?) {
+            Some(updated) => self.write(task, &updated).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, MemoryError> {
+        let _lock = lock(&self.lock);
+        let mut tasks = Vec::new();
+        for path in self.files()? {
+            match Self::read(&path) {
+                Ok(Some(stored)) => tasks.push(stored.task),
+                // Removed since it was listed
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Skipped the conversation file {}: {}", path.display(), e),
+            }
+        }
+        Ok(tasks)
+    }
+
+    fn evict(&self, task: &str) -> Result<bool, MemoryError> {
+        let _lock = lock(&self.lock);
+        match std::fs::remove_file(self.path(task)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn clear(&self) -> Result<(), MemoryError> {
+        let _lock = lock(&self.lock);
+        for path in self.files()? {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::conversation::{Content, Role};
+
+    fn message(text: &str) -> Message {
+        Message::new(
+            Role::Assistant("writer".to_owned()),
+            Content::Text(text.to_owned()),
+        )
+    }
+
+    fn texts(store: &dyn MemoryStore, task: &str) -> Vec<String> {
+        store
+            .get(task)
+            .unwrap()
+            .unwrap()
+            .history
+            .iter()
+            .map(|message| message.content.to_string())
+            .collect()
+    }
+
+    /// The behavior every store must have
+    pub(in crate::memory) fn check_store(store: &dyn MemoryStore) {
+        assert!(store.get("a").unwrap().is_none());
+        store.append("a", "writer", message("a1")).unwrap();
+        store.append("a", "other", message("a2")).unwrap();
+        store.append("b", "writer", message("b1")).unwrap();
+        assert_eq!(texts(store, "a"), ["a1", "a2"]);
+        assert_eq!(store.get("a").unwrap().unwrap().agent_name(), "writer");
+        let mut tasks = store.list().unwrap();
+        tasks.sort();
+        assert_eq!(tasks, ["a", "b"]);
+
+        let mut conversation = Conversation::new("editor".to_owned());
+        conversation.add_message(message("b2"));
+        store.put("b", conversation).unwrap();
+        assert_eq!(texts(store, "b"), ["b2"]);
+        assert_eq!(store.get("b").unwrap().unwrap().agent_name(), "editor");
+
+        let mut update = |mut conversation: Conversation| {
+            conversation.history[0] = message("b3");
+            Some(conversation)
+        };
+        assert!(store.update("b", &mut update).unwrap());
+        assert_eq!(texts(store, "b"), ["b3"]);
+        assert!(!store.update("b", &mut |_| None).unwrap());
+        assert_eq!(texts(store, "b"), ["b3"]);
+        assert!(!store.update("c", &mut update).unwrap());
+        assert!(store.get("c").unwrap().is_none());
+
+        assert!(store.evict("a").unwrap());
+        assert!(!store.evict("a").unwrap());
+        assert!(store.get("a").unwrap().is_none());
+        store.clear().unwrap();
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_in_memory_store() {
+        check_store(&InMemoryStore::new());
+
+        let store = InMemoryStore::with_capacity(NonZeroUsize::new(2).unwrap());
+        store.append("a", "writer", message("a1")).unwrap();
+        store.append("b", "writer", message("b1")).unwrap();
+        store.get("a").unwrap();
+        store.append("c", "writer", message("c1")).unwrap();
+        let mut tasks = store.list().unwrap();
+        tasks.sort();
+        assert_eq!(tasks, ["a", "c"]);
+    }
+
+    #[test]
+    fn test_file_store() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        check_store(&FileStore::new(&dir).unwrap());
+
+        // The conversations outlive the store
+        FileStore::new(&dir)
+            .unwrap()
+            .append("task", "writer", message("hello"))
+            .unwrap();
+        let store = FileStore::new(&dir).unwrap();
+        assert_eq!(texts(&store, "task"), ["hello"]);
+        assert_eq!(store.list().unwrap(), ["task"]);
+
+        // Other files of the directory are neither listed nor cleared, a corrupt conversation
+        // file is skipped
+        let state = dir.join("writer_1a2b3c4d.json");
+        std::fs::write(&state, r#"{"agent_name":"writer","history":[]}"#).unwrap();
+        std::fs::write(dir.join(format!("corrupt{CONVERSATION_EXTENSION}")), "{").unwrap();
+        assert_eq!(store.list().unwrap(), ["task"]);
+        store.clear().unwrap();
+        assert!(store.list().unwrap().is_empty());
+        assert!(state.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+//! Concurrency and rate limits for LLM providers
+//!
+#![deny(missing_docs)]
+
+use std::{num::NonZeroUsize, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+/// Limits the requests sent to a provider, shared by all clones
+///
+/// A limiter is attached to an [`LLMProvider`](crate::llm_provider::LLMProvider), so all agents
+/// built from the provider (or its clones) share the same limits. Attach the same limiter to
+/// several providers to limit them together.
+///
+/// Requests wait until they are allowed, they are never rejected.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    concurrency: Option<Arc<Semaphore>>,
+    requests: Option<Arc<TokenBucket>>,
+    tokens: Option<Arc<TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Create a limiter without any limit
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the number of requests in flight
+    pub fn max_concurrency(mut self, max_concurrency: NonZeroUsize) -> Self {
+        self.concurrency = Some(Arc::new(Semaphore::new(max_concurrency.get())));
+        self
+    }
+
+    /// Limit the number of requests per minute
+    pub fn requests_per_minute(mut self, requests: u32) -> Self {
+        self.requests = Some(Arc::new(TokenBucket::per_minute(requests)));
+        self
+    }
+
+    /// Limit the number of tokens per minute
+    ///
+    /// The tokens of a request are estimated before it is sent, see [`RateLimiter::acquire`].
+    pub fn tokens_per_minute(mut self, tokens: u32) -> Self {
+        self.tokens = Some(Arc::new(TokenBucket::per_minute(tokens)));
+        self
+    }
+
+    /// Wait until a request using `tokens` tokens is allowed
+    ///
+    /// The request counts as in flight until the returned permit is dropped. Requests using more
+    /// tokens than the limit per minute wait for a full bucket instead of waiting forever.
+    pub async fn acquire(&self, tokens: u32) -> RateLimitPermit {
+        let permit = match &self.concurrency {
+            Some(semaphore) => Some(
+                Arc::clone(semaphore)
+                    .acquire_owned()
+                    .await
+                    .expect("the semaphore is never closed"),
+            ),
+            None => None,
+        };
+        if let Some(requests) = &self.requests {
+            requests.take(1).await;
+        }
+        if let Some(bucket) = &self.tokens {
+            bucket.take(tokens).await;
+        }
+        RateLimitPermit { _permit: permit }
+    }
+}
+
+/// A request allowed by a [`RateLimiter`], which is in flight until the permit is dropped
+#[derive(Debug)]
+pub struct RateLimitPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Estimate the tokens of a text, about four characters per token
+pub fn estimate_tokens(text: &str) -> u32 {
+    u32::try_from(text.chars().count().div_ceil(4)).unwrap_or(u32::MAX)
+}
+
+/// A token bucket refilled continuously, up to its capacity per minute
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    /// The available tokens and when they were last refilled
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn per_minute(capacity: u32) -> Self {
+        let capacity = f64::from(capacity.max(1));
+        Self {
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    async fn take(&self, tokens: u32) {
+        let tokens = f64::from(tokens).min(self.capacity);
+        let refill_per_sec = self.capacity / 60.0;
+        loop {
+            let wait = {
+                let mut state = self.state.lock().await;
+                let (available, last_refill) = &mut *state;
+                let now = Instant::now();
+                *available = now
+                    .duration_since(*last_refill)
+                    .as_secs_f64()
+                    .mul_add(refill_per_sec, *available)
+                    .min(self.capacity);
+                *last_refill = now;
+
+                if *available >= tokens {
+                    *available -= tokens;
+                    return;
+                }
+                Duration::from_secs_f64((tokens - *available) / refill_per_sec)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_per_minute() {
+        let limiter = RateLimiter::new().requests_per_minute(2);
+        let started_at = Instant::now();
+        limiter.acquire(0).await;
+        limiter.acquire(0).await;
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+
+        // The bucket is refilled with one request every 30 seconds
+        limiter.acquire(0).await;
+        assert_eq!(started_at.elapsed().as_secs(), 30);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tokens_per_minute() {
+        let limiter = RateLimiter::new().tokens_per_minute(600);
+        let started_at = Instant::now();
+        limiter.acquire(500).await;
+        limiter.acquire(200).await;
+        // 100 tokens were missing, 10 tokens are refilled every second
+        assert_eq!(started_at.elapsed().as_secs(), 10);
+
+        // A request larger than the limit waits for a full bucket
+        limiter.acquire(1000).await;
+        assert_eq!(started_at.elapsed().as_secs(), 70);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_concurrency() {
+        let limiter = RateLimiter::new().max_concurrency(NonZeroUsize::MIN);
+        let permit = limiter.acquire(0).await;
+
+        let shared = limiter.clone();
+        let waiting = tokio::spawn(async move { shared.acquire(0).await });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!waiting.is_finished());
+
+        drop(permit);
+        waiting.await.unwrap();
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("hello"), 2);
+        assert_eq!(estimate_tokens("你好你好"), 1);
+    }
+}
@@ -0,0 +1,378 @@
+//! Retries of failed requests with exponential backoff
+//!
+#![deny(missing_docs)]
+
+use std::{future::Future, sync::LazyLock, time::Duration};
+
+use regex::Regex;
+use rig::completion::{CompletionError, PromptError};
+use serde::{Deserialize, Serialize};
+
+use crate::agent::AgentError;
+
+/// How long to wait before retrying a failed request
+///
+/// The delay grows exponentially from `initial_delay` by `multiplier` for each attempt, up to
+/// `max_delay`, and is shortened by a random fraction of up to `jitter` so that agents failing
+/// together don't retry together. A retry-after hint of the provider is honored instead, up to
+/// `max_delay`. Fatal errors, see [`ErrorKind`], are never retried.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+    /// Maximum delay between two attempts
+    pub max_delay: Duration,
+    /// Factor applied to the delay after each attempt
+    pub multiplier: f64,
+    /// Fraction of the delay which is randomized, between 0 and 1
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Retry immediately, like agents did before retry policies existed
+    pub fn immediate() -> Self {
+        Self {
+            initial_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            multiplier: 1.0,
+            jitter: 0.0,
+        }
+    }
+
+    /// The backoff delay after the failed attempt, starting from 0
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = i32::try_from(attempt).unwrap_or(i32::MAX);
+        let delay = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * fastrand::f64();
+        Duration::from_secs_f64(delay * (1.0 - jitter))
+    }
+
+    /// The delay before retrying the failed attempt, `None` if the error is fatal
+    pub fn delay(&self, attempt: u32, error: &AgentError) -> Option<Duration> {
+        if !ErrorKind::of(error).is_retryable() {
+            return None;
+        }
+        Some(retry_after(error).map_or_else(
+            || self.backoff(attempt),
+            |retry_after| retry_after.min(self.max_delay),
+        ))
+    }
+
+    /// Run the operation until it succeeds, fails with a fatal error, or was attempted `attempts`
+    /// times, which is at least once
+    ///
+    /// The operation gets the number of the attempt, starting from 0.
+    pub async fn retry<T, F, Fut>(&self, attempts: u32, mut operation: F) -> Result<T, AgentError>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T, AgentError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let error = match operation(attempt).await {
+                Ok(output) => return Ok(output),
+                Err(error) => error,
+            };
+            attempt += 1;
+            if attempt >= attempts {
+                return Err(error);
+            }
+            let Some(delay) = self.delay(attempt - 1, &error) else {
+                return Err(error);
+            };
+            tracing::debug!("Retrying in {:?} after: {}", delay, error);
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// The kind of a failed request, which decides whether it is retried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The provider's rate limit was hit, retryable
+    RateLimited,
+    /// The request timed out, retryable
+    Timeout,
+    /// The provider failed or is overloaded, e.g. a 5xx status or a connection error, retryable
+    ServerError,
+    /// The credentials were rejected, fatal
+    Auth,
+    /// The request is invalid, e.g. the context is too long, fatal
+    InvalidRequest,
+    /// Any other error, retryable
+    Other,
+}
+
+impl ErrorKind {
+    /// Classify an error by its HTTP status, or by the error message of the provider
+    pub fn of(error: &AgentError) -> Self {
+        if let AgentError::PromptError(PromptError::CompletionError(CompletionError::HttpError(
+            error,
+        ))) = error
+        {
+            if error.is_timeout() {
+                return Self::Timeout;
+            }
+            if error.is_connect() {
+                return Self::ServerError;
+            }
+            if let Some(status) = error.status() {
+                return match status.as_u16() {
+                    429 => Self::RateLimited,
+                    408 => Self::Timeout,
+                    401 | 403 => Self::Auth,
+                    500..=599 => Self::ServerError,
+                    400..=499 => Self::InvalidRequest,
+                    _ => Self::Other,
+                };
+            }
+        }
+
+        // Providers mostly report errors with the response body only
+        let message = error.to_string().to_lowercase();
+        let mentions = |keywords: &[&str]| keywords.iter().any(|keyword| message.contains(keyword));
+        if mentions(&["rate limit", "rate_limit", "too many requests"]) {
+            Self::RateLimited
+        } else if mentions(&["timeout", "timed out"]) {
+            Self::Timeout
+        } else if mentions(&[
+            "overloaded",
+            "internal server error",
+            "server_error",
+            "service unavailable",
+            "bad gateway",
+        ]) {
+            Self::ServerError
+        } else if mentions(&[
+            "authentication",
+            "unauthorized",
+            "invalid_api_key",
+            "invalid api key",
+            "permission",
+            "forbidden",
+        ]) {
+            Self::Auth
+        } else if mentions(&["invalid_request", "invalid request", "bad request"]) {
+            Self::InvalidRequest
+        } else {
+            Self::Other
+        }
+    }
+
+    /// Whether a request failing with this kind of error may succeed when retried
+    pub fn is_retryable(self) -> bool {
+        !matches!(self, Self::Auth | Self::InvalidRequest)
+    }
+}
+
+/// The retry-after hint in the error message, e.g. `Please try again in 1.5s` or `6m32.448s`
+///
+/// The components of the duration are summed, a number without a unit is in seconds.
+pub fn retry_after(error: &AgentError) -> Option<Duration> {
+    static RETRY_AFTER: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?i)(?:retry[-_ ]?after|try again in)\D{0,5}\d").expect("the pattern is valid")
+    });
+    static COMPONENT: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"^\s*(\d+(?:\.\d+)?)\s*([a-zA-Z]*)").expect("the pattern is valid")
+    });
+
+    let message = error.to_string();
+    // The hint starts at its first digit
+    let mut rest = &message[RETRY_AFTER.find(&message)?.end() - 1..];
+    let mut total = Duration::ZERO;
+    while let Some(captures) = COMPONENT.captures(rest) {
+        let value = captures[1].parse::<f64>().ok()?;
+        let unit = captures[2].to_lowercase();
+        let unit_secs = match unit.as_str() {
+            "ms" | "millisecond" | "milliseconds" => Some(0.001),
+            "" | "s" | "sec" | "secs" | "second" | "seconds" => Some(1.0),
+            "m" | "min" | "mins" | "minute" | "minutes" => Some(60.0),
+            "h" | "hr" | "hrs" | "hour" | "hours" => Some(3600.0),
+            // Another word follows the number, e.g. `retry after 30 or later`
+            _ => None,
+        };
+        total = total
+            .checked_add(Duration::try_from_secs_f64(value * unit_secs.unwrap_or(1.0)).ok()?)?;
+        if unit.is_empty() || unit_secs.is_none() {
+            break;
+        }
+        rest = &rest[captures[0].len()..];
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::agent::tests::provider_error;
+
+    fn without_jitter() -> RetryPolicy {
+        RetryPolicy {
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = without_jitter();
+        let delays = (0..8).map(|attempt| policy.backoff(attempt).as_secs());
+        assert_eq!(delays.collect::<Vec<_>>(), [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(60));
+
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..RetryPolicy::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.backoff(2);
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let cases = [
+            ("Rate limit reached for gpt-4o", ErrorKind::RateLimited),
+            (r#"{"type":"rate_limit_error"}"#, ErrorKind::RateLimited),
+            ("Too Many Requests", ErrorKind::RateLimited),
+            ("request timed out", ErrorKind::Timeout),
+            (r#"{"type":"overloaded_error"}"#, ErrorKind::ServerError),
+            ("503 Service Unavailable", ErrorKind::ServerError),
+            (r#"{"type":"authentication_error"}"#, ErrorKind::Auth),
+            (r#"{"code":"invalid_api_key"}"#, ErrorKind::Auth),
+            (
+                r#"{"type":"invalid_request_error"}"#,
+                ErrorKind::InvalidRequest,
+            ),
+            ("something went wrong", ErrorKind::Other),
+        ];
+        for (message, kind) in cases {
+            assert_eq!(ErrorKind::of(&provider_error(message)), kind, "{message}");
+        }
+
+        assert!(ErrorKind::RateLimited.is_retryable());
+        assert!(ErrorKind::Other.is_retryable());
+        assert!(!ErrorKind::Auth.is_retryable());
+        assert!(!ErrorKind::InvalidRequest.is_retryable());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let cases = [
+            ("Rate limit reached. Please try again in 20s.", Some(20_000)),
+            ("Rate limit reached. Please try again in 1.5s.", Some(1_500)),
+            ("Rate limit reached. Please try again in 250ms.", Some(250)),
+            ("Rate limit reached. Please try again in 2m.", Some(120_000)),
+            (
+                "Rate limit reached. Please try again in 6m32.448s.",
+                Some(392_448),
+            ),
+            (
+                "Rate limit reached. Please try again in 1m20s.",
+                Some(80_000),
+            ),
+            (
+                "Rate limit reached. Please try again in 1h30m.",
+                Some(5_400_000),
+            ),
+            (
+                "Rate limit reached. Please try again in 1h 5m 10s.",
+                Some(3_910_000),
+            ),
+            ("Retry after 30 seconds.", Some(30_000)),
+            (r#"{"retry-after": 30}"#, Some(30_000)),
+            ("Rate limit reached.", None),
+        ];
+        for (message, millis) in cases {
+            let retry_after = retry_after(&provider_error(message));
+            assert_eq!(retry_after, millis.map(Duration::from_millis), "{message}");
+        }
+
+        let policy = without_jitter();
+        let error = provider_error("Rate limit reached. Please try again in 5s.");
+        assert_eq!(policy.delay(0, &error), Some(Duration::from_secs(5)));
+        let error = provider_error("Rate limit reached. Please try again in 5m.");
+        assert_eq!(policy.delay(0, &error), Some(Duration::from_secs(60)));
+        let error = provider_error(r#"{"type":"authentication_error"}"#);
+        assert_eq!(policy.delay(0, &error), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry() {
+        let policy = without_jitter();
+        let attempts = AtomicU32::new(0);
+        let started_at = Instant::now();
+        let output = policy
+            .retry(5, |attempt| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if attempt < 3 {
+                        Err(provider_error("Too Many Requests"))
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(output, 3);
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+        // 1 + 2 + 4 seconds
+        assert_eq!(started_at.elapsed().as_secs(), 7);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_gives_up() {
+        let policy = without_jitter();
+
+        // A fatal error is not retried
+        let attempts = AtomicU32::new(0);
+        let started_at = Instant::now();
+        let result = policy
+            .retry(5, |_| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async { Err::<(), _>(provider_error("Unauthorized")) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+
+        // The last error is returned after the last attempt, without waiting
+        let attempts = AtomicU32::new(0);
+        let result = policy
+            .retry(3, |attempt| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async move { Err::<(), _>(provider_error(&format!("timeout {attempt}"))) }
+            })
+            .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Agent prompt error: CompletionError: ProviderError: timeout 2"
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(started_at.elapsed().as_secs(), 3);
+
+        // The operation is attempted at least once
+        let result = policy.retry(0, |_| async { Ok(()) }).await;
+        assert!(result.is_ok());
+    }
+}
@@ -1,36 +1,72 @@
 use std::{
+    collections::HashSet,
+    future::Future,
     hash::{Hash, Hasher},
-    path::Path,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
     vec,
 };
 
-use futures::{StreamExt, future::BoxFuture, stream};
+use futures::{
+    FutureExt, StreamExt,
+    channel::mpsc::UnboundedSender,
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+};
 use rig::{
+    OneOrMany,
     agent::AgentBuilder,
     providers::{anthropic, deepseek, gemini, openrouter},
-    tool::Tool,
+    streaming::{StreamingChat, StreamingChoice, StreamingResult},
+    tool::{Tool, ToolDyn, ToolError},
 };
 use rig::{
-    completion::{Chat, Prompt},
+    completion::{Chat, CompletionError, Prompt, PromptError, ToolDefinition},
     providers::openai,
 };
 use serde::Serialize;
-use tokio::sync::mpsc;
 use twox_hash::XxHash3_64;
 
 use crate::{
-    agent::{Agent, AgentConfig, AgentError},
-    conversation::{AgentShortMemory, Conversation, Role},
+    agent::{Agent, AgentConfig, AgentError, DEFAULT_MAX_CONCURRENT_TASKS},
+    context_window::{ContextWindow, TokenCounter},
+    conversation::{
+        AgentShortMemory, Content, Conversation, ConversationFormat, MediaSource, Message,
+        MessageMetadata, Role,
+    },
     llm_provider::LLMProvider,
+    memory::{MemoryStore, SummarizingMemory},
     persistence,
+    rate_limit::{self, RateLimitPermit, RateLimiter},
+    retry::RetryPolicy,
 };
 
+mod session;
+
+pub use session::Session;
+
+/// Starts a streaming chat on a rig agent.
+///
+/// Only models implementing rig's `StreamingCompletionModel` support streaming, so the builder
+/// sets this for those providers and [RigAgent] falls back to [Agent::run] otherwise.
+type StreamChatFn<M> = for<'a> fn(
+    &'a rig::agent::Agent<M>,
+    String,
+    Vec<rig::message::Message>,
+) -> BoxFuture<'a, Result<StreamingResult, CompletionError>>;
+
 pub struct RigAgentBuilder<M: rig::completion::CompletionModel> {
     agent_builder: Option<AgentBuilder<M>>,
     config: AgentConfig,
     system_prompt: Option<String>,
     long_term_memory: Option<Arc<dyn rig::vector_store::VectorStoreIndexDyn>>,
+    stream_chat: Option<StreamChatFn<M>>,
+    rate_limiter: Option<RateLimiter>,
+    token_counter: Option<Arc<dyn TokenCounter>>,
+    summarizing_memory: Option<SummarizingMemory>,
+    memory_store: Option<Arc<dyn MemoryStore>>,
 }
 
 impl<M: rig::completion::CompletionModel> RigAgentBuilder<M> {
@@ -40,6 +76,11 @@ impl<M: rig::completion::CompletionModel> RigAgentBuilder<M> {
             config: AgentConfig::default(),
             system_prompt: None,
             long_term_memory: None,
+            stream_chat: None,
+            rate_limiter: None,
+            token_counter: None,
+            summarizing_memory: None,
+            memory_store: None,
         }
     }
 
@@ -77,9 +118,20 @@ impl<M: rig::completion::CompletionModel> RigAgentBuilder<M> {
         };
 
         let config = self.config.clone();
-        let short_memory = AgentShortMemory::new();
+        let short_memory = match self.memory_store {
+            Some(store) => AgentShortMemory::with_store(store),
+            None => AgentShortMemory::new(),
+        };
         let long_term_memory = self.long_term_memory.clone();
         let system_prompt = self.system_prompt.clone();
+        let stream_chat = self.stream_chat;
+        let context_window = self.config.context_window.map(|tokens| {
+            let context_window = ContextWindow::new(tokens);
+            match self.token_counter {
+                Some(counter) => context_window.with_counter(counter),
+                None => context_window,
+            }
+        });
 
         let rig_agent = agent_builder
             .preamble(&system_prompt.unwrap_or("You are a helpful assistant.".to_owned()))
@@ -92,6 +144,10 @@ impl<M: rig::completion::CompletionModel> RigAgentBuilder<M> {
             config,
             short_memory,
             long_term_memory,
+            stream_chat,
+            rate_limiter: self.rate_limiter,
+            context_window,
+            summarizing_memory: self.summarizing_memory,
         })
     }
 
@@ -127,6 +183,30 @@ impl<M: rig::completion::CompletionModel> RigAgentBuilder<M> {
         self
     }
 
+    /// Truncate the chat history to fit in a context window of `context_window` tokens
+    pub fn context_window(mut self, context_window: usize) -> Self {
+        self.config.context_window = Some(context_window);
+        self
+    }
+
+    /// Count the tokens of the chat history with a tokenizer instead of estimating them
+    pub fn token_counter(mut self, counter: impl TokenCounter + 'static) -> Self {
+        self.token_counter = Some(Arc::new(counter));
+        self
+    }
+
+    /// Keep the conversations in a [`MemoryStore`] instead of an unbounded map in memory
+    pub fn memory_store(mut self, store: Arc<dyn MemoryStore>) -> Self {
+        self.memory_store = Some(store);
+        self
+    }
+
+    /// Summarize the older messages of long conversations, see [`SummarizingMemory`]
+    pub fn summarizing_memory(mut self, memory: SummarizingMemory) -> Self {
+        self.summarizing_memory = Some(memory);
+        self
+    }
+
     pub fn enable_plan(mut self, planning_prompt: impl Into<Option<String>>) -> Self {
         self.config.plan_enabled = true;
         self.config.planning_prompt = planning_prompt.into();
@@ -143,11 +223,27 @@ impl<M: rig::completion::CompletionModel> RigAgentBuilder<M> {
         self
     }
 
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.config.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn enable_stream(mut self) -> Self {
+        self.config.stream = true;
+        self
+    }
+
     pub fn enable_rag_every_loop(mut self) -> Self {
         self.config.rag_every_loop = true;
         self
     }
 
+    /// Run at most `max_concurrent_tasks` tasks at once in [`Agent::run_multiple_tasks`]
+    pub fn max_concurrent_tasks(mut self, max_concurrent_tasks: NonZeroUsize) -> Self {
+        self.config.max_concurrent_tasks = Some(max_concurrent_tasks);
+        self
+    }
+
     pub fn save_state_dir(mut self, path: impl Into<String>) -> Self {
         self.config.save_state_dir = Some(path.into());
         self
@@ -175,7 +271,12 @@ impl RigAgentBuilder<anthropic::completion::CompletionModel> {
     pub fn provider(mut self, provider: LLMProvider) -> Result<Self, AgentError> {
         let model_config = provider.get_config();
         self.config.model_name = model_config.model.clone();
+        self.config.stream = model_config.stream;
+        self.rate_limiter = model_config.rate_limiter.clone();
         self.agent_builder = Some(provider.get_anthropic_agent_builder()?);
+        self.stream_chat = Some(|agent, prompt, history| {
+            Box::pin(async move { agent.stream_chat(&prompt, history).await })
+        });
         Ok(self)
     }
 }
@@ -184,7 +285,12 @@ impl RigAgentBuilder<deepseek::DeepSeekCompletionModel> {
     pub fn provider(mut self, provider: LLMProvider) -> Result<Self, AgentError> {
         let model_config = provider.get_config();
         self.config.model_name = model_config.model.clone();
+        self.config.stream = model_config.stream;
+        self.rate_limiter = model_config.rate_limiter.clone();
         self.agent_builder = Some(provider.get_deep_seek_agent_builder()?);
+        self.stream_chat = Some(|agent, prompt, history| {
+            Box::pin(async move { agent.stream_chat(&prompt, history).await })
+        });
         Ok(self)
     }
 }
@@ -193,7 +299,12 @@ impl RigAgentBuilder<gemini::completion::CompletionModel> {
     pub fn provider(mut self, provider: LLMProvider) -> Result<Self, AgentError> {
         let model_config = provider.get_config();
         self.config.model_name = model_config.model.clone();
+        self.config.stream = model_config.stream;
+        self.rate_limiter = model_config.rate_limiter.clone();
         self.agent_builder = Some(provider.get_gemini_agent_builder()?);
+        self.stream_chat = Some(|agent, prompt, history| {
+            Box::pin(async move { agent.stream_chat(&prompt, history).await })
+        });
         Ok(self)
     }
 }
@@ -202,7 +313,15 @@ impl RigAgentBuilder<openai::CompletionModel> {
     pub fn provider(mut self, provider: LLMProvider) -> Result<Self, AgentError> {
         let model_config = provider.get_config();
         self.config.model_name = model_config.model.clone();
-        self.agent_builder = Some(provider.get_open_a_i_agent_builder()?);
+        self.config.stream = model_config.stream;
+        self.rate_limiter = model_config.rate_limiter.clone();
+        self.agent_builder = Some(match provider {
+            LLMProvider::OpenAICompatible(_) => provider.get_openai_compatible_agent_builder()?,
+            _ => provider.get_open_a_i_agent_builder()?,
+        });
+        self.stream_chat = Some(|agent, prompt, history| {
+            Box::pin(async move { agent.stream_chat(&prompt, history).await })
+        });
         Ok(self)
     }
 }
@@ -211,11 +330,96 @@ impl RigAgentBuilder<openrouter::CompletionModel> {
     pub fn provider(mut self, provider: LLMProvider) -> Result<Self, AgentError> {
         let model_config = provider.get_config();
         self.config.model_name = model_config.model.clone();
+        // rig has no streaming support for OpenRouter yet, `run_stream` falls back to `run`
+        self.config.stream = model_config.stream;
+        self.rate_limiter = model_config.rate_limiter.clone();
         self.agent_builder = Some(provider.get_open_router_agent_builder()?);
         Ok(self)
     }
 }
 
+/// Type-erased construction, the model type of the agent follows the provider
+///
+/// The factories live on `RigAgent<openai::CompletionModel>` only so they can be called as
+/// `RigAgent::from_provider(..)`, they build agents of any provider.
+impl RigAgent<openai::CompletionModel> {
+    /// Build an agent from a provider and a configuration, e.g. from runtime configuration
+    ///
+    /// The model of the agent is the model of the provider. Streaming is enabled if either the
+    /// provider or the configuration enables it.
+    pub fn from_provider(
+        provider: LLMProvider,
+        config: AgentConfig,
+    ) -> Result<Arc<dyn Agent>, AgentError> {
+        Self::from_provider_with_tools(provider, config, Vec::new())
+    }
+
+    /// Build an agent with type-erased tools, like [`RigAgent::from_provider`]
+    pub fn from_provider_with_tools(
+        provider: LLMProvider,
+        config: AgentConfig,
+        tools: Vec<Arc<dyn ToolDyn>>,
+    ) -> Result<Arc<dyn Agent>, AgentError> {
+        macro_rules! build {
+            ($builder:expr) => {{
+                let stream = config.stream || provider.get_config().stream;
+                let mut builder = $builder
+                    .system_prompt(config.system_prompt.clone())
+                    .config(config)
+                    .provider(provider)?;
+                if stream {
+                    builder = builder.enable_stream();
+                }
+                for tool in tools {
+                    builder = builder.tool(SharedTool(tool))?;
+                }
+                Arc::new(builder.build()?) as Arc<dyn Agent>
+            }};
+        }
+
+        Ok(match provider {
+            LLMProvider::Anthropic(_) => build!(RigAgent::anthropic_builder()),
+            LLMProvider::DeepSeek(_) => build!(RigAgent::deepseek_builder()),
+            LLMProvider::Gemini(_) => build!(RigAgent::gemini_builder()),
+            LLMProvider::OpenAI(_) | LLMProvider::OpenAICompatible(_) => {
+                build!(RigAgent::openai_builder())
+            }
+            LLMProvider::OpenRouter(_) => build!(RigAgent::openrouter_builder()),
+        })
+    }
+}
+
+/// A type-erased tool, which may be shared by several agents
+struct SharedTool(Arc<dyn ToolDyn>);
+
+impl Tool for SharedTool {
+    const NAME: &'static str = "shared_tool";
+
+    type Error = ToolError;
+    type Args = serde_json::Value;
+    type Output = serde_json::Value;
+
+    fn name(&self) -> String {
+        self.0.name()
+    }
+
+    fn definition(&self, prompt: String) -> impl Future<Output = ToolDefinition> + Send + Sync {
+        self.0.definition(prompt)
+    }
+
+    fn call(
+        &self,
+        args: Self::Args,
+    ) -> impl Future<Output = Result<Self::Output, Self::Error>> + Send + Sync {
+        let tool = Arc::clone(&self.0);
+        async move {
+            // The output is already serialized by the tool, keep it as is
+            let output = tool.call(args.to_string()).await?;
+            Ok(serde_json::from_str(&output).unwrap_or(serde_json::Value::String(output)))
+        }
+    }
+}
+
 /// Wrapper for rig's Agent
 #[derive(Clone, Serialize)]
 pub struct RigAgent<M>
@@ -228,6 +432,13 @@ where
     short_memory: AgentShortMemory,
     #[serde(skip)]
     long_term_memory: Option<Arc<dyn rig::vector_store::VectorStoreIndexDyn>>,
+    #[serde(skip)]
+    stream_chat: Option<StreamChatFn<M>>,
+    #[serde(skip)]
+    rate_limiter: Option<RateLimiter>,
+    #[serde(skip)]
+    context_window: Option<ContextWindow>,
+    summarizing_memory: Option<SummarizingMemory>,
 }
 
 impl RigAgent<anthropic::completion::CompletionModel> {
@@ -264,8 +475,36 @@ impl<M> RigAgent<M>
 where
     M: rig::completion::CompletionModel,
 {
+    /// Attempt an operation of a task with the retry policy of the agent, see [`RetryPolicy::retry`]
+    ///
+    /// Every failed attempt is logged, and the task state is saved if autosave is enabled. Fails
+    /// with [`AgentError::NoAttempts`] without calling the operation if `retry_attempts` is 0.
+    async fn with_retries<T, F, Fut>(&self, task: &str, mut operation: F) -> Result<T, AgentError>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T, AgentError>>,
+    {
+        if self.config.retry_attempts == 0 {
+            return Err(AgentError::NoAttempts);
+        }
+
+        self.config
+            .retry_policy
+            .retry(self.config.retry_attempts, |attempt| {
+                let attempted = operation(attempt);
+                async move {
+                    let result = attempted.await;
+                    if let Err(e) = &result {
+                        self.handle_error_in_attempts(task, e, attempt).await;
+                    }
+                    result
+                }
+            })
+            .await
+    }
+
     /// Handle error in attempts
-    async fn handle_error_in_attempts(&self, task: &str, error: AgentError, attempt: u32) {
+    async fn handle_error_in_attempts(&self, task: &str, error: &AgentError, attempt: u32) {
         let err_msg = format!("Attempt {}, task: {}, failed: {}", attempt + 1, task, error);
         tracing::error!(err_msg);
 
@@ -281,60 +520,353 @@ where
         }
     }
 
+    /// Wait until the provider's rate limiter allows a request with the prompt
+    ///
+    /// The tokens of the request are estimated from the system prompt, the prompt and the
+    /// `history_tokens` of the chat history which is sent, plus the `max_tokens` reserved for the
+    /// response.
+    async fn acquire_rate_limit(
+        &self,
+        prompt: &str,
+        history_tokens: u32,
+    ) -> Option<RateLimitPermit> {
+        let rate_limiter = self.rate_limiter.as_ref()?;
+        let tokens = rate_limit::estimate_tokens(&self.config.system_prompt)
+            .saturating_add(rate_limit::estimate_tokens(prompt))
+            .saturating_add(history_tokens)
+            .saturating_add(u32::try_from(self.config.max_tokens).unwrap_or(u32::MAX));
+        Some(rate_limiter.acquire(tokens).await)
+    }
+
+    /// Add the task to memory and gather the context needed before the agent loop starts
+    async fn prepare_task(&self, task: &str) -> Result<(), AgentError> {
+        // Add task to memory
+        self.short_memory
+            .add(
+                task,
+                &self.config.name,
+                Role::User(self.config.user_name.clone()),
+                task,
+            )
+            .await?;
+
+        // Plan
+        if self.config.plan_enabled {
+            self.plan(task.to_owned()).await?;
+        }
+
+        // Query long term memory
+        if self.long_term_memory.is_some() {
+            self.query_long_term_memory(task, task).await?;
+        }
+
+        // Save state
+        if self.config.autosave {
+            self.save_task_state(task.to_owned()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the chat history which will be sent along with the task
+    async fn chat_history(&self, task: &str, loop_count: u32) -> Result<ChatHistory, AgentError> {
+        // Since rig's agent requires concatenating prompt and chat_history,
+        // this would cause the initial prompt to be duplicated.
+        // Here we check if it's the first loop by verifying loop_count == 0
+        // If it's the first loop, use empty chat_history
+        if loop_count == 0 {
+            return Ok(ChatHistory::default());
+        }
+
+        self.fitted_history(task, task).await
+    }
+
+    /// The conversation of `key` as chat history, fitted in the context window with `prompt`
+    async fn fitted_history(&self, key: &str, prompt: &str) -> Result<ChatHistory, AgentError> {
+        let conversation = self
+            .short_memory
+            .get(key)
+            .await?
+            .unwrap_or_else(|| Conversation::new(self.name()));
+        let Some(context_window) = &self.context_window else {
+            return Ok(ChatHistory {
+                messages: (&conversation).into(),
+                dropped: 0,
+                tokens: rate_limit::estimate_tokens(&conversation.to_string()),
+            });
+        };
+
+        // Keep room for the system prompt, the prompt and the response
+        let reserved = context_window
+            .count_tokens(&self.config.system_prompt)
+            .saturating_add(context_window.count_tokens(prompt))
+            .saturating_add(usize::try_from(self.config.max_tokens).unwrap_or(usize::MAX));
+        let fitted = context_window.fit(&conversation.history, reserved);
+        if fitted.is_truncated() {
+            tracing::warn!(
+                "Agent<{}>, task: {}, dropped {} messages to fit the context window of {} tokens",
+                self.config.name,
+                key,
+                fitted.dropped,
+                context_window.tokens()
+            );
+        }
+        let text = fitted
+            .messages
+            .iter()
+            .map(|message| format!("{}: {}\n", message.role, message.content))
+            .collect::<String>();
+        Ok(ChatHistory {
+            messages: to_rig_messages(fitted.messages),
+            dropped: fitted.dropped,
+            tokens: rate_limit::estimate_tokens(&text),
+        })
+    }
+
+    /// A single attempt to generate the response of a loop, with the number of messages dropped
+    /// from its chat history
+    async fn attempt(&self, task: &str, loop_count: u32) -> Result<(String, usize), AgentError> {
+        if self.long_term_memory.is_some() && self.config.rag_every_loop {
+            // FIXME: if RAG success, but then LLM fails, then RAG is not removed and maybe causes issues
+            self.query_long_term_memory(task, task).await?;
+        }
+
+        // Generate response using LLM
+        let history = self.chat_history(task, loop_count).await?;
+        let _permit = self.acquire_rate_limit(task, history.tokens).await;
+        let response = self.agent.chat(task.to_owned(), history.messages).await?;
+        Ok((response, history.dropped))
+    }
+
+    /// Run the agent loop, returning the output and the error which stopped it early, if any
+    ///
+    /// A loop whose attempts all failed stops the agent loop, the output of the earlier loops is
+    /// still returned, see [`Agent::run_checked`].
+    async fn run_loops(&self, task: &str) -> Result<(String, Option<AgentError>), AgentError> {
+        self.prepare_task(task).await?;
+
+        // Run agent loop
+        let mut all_responses = vec![];
+        let mut stopped_by = None;
+        for loop_count in 0..self.config.max_loops {
+            let attempted = self
+                .with_retries(task, |_| async move {
+                    let started = Instant::now();
+                    let (response, dropped) = self.attempt(task, loop_count).await?;
+                    Ok((response, started.elapsed(), dropped))
+                })
+                .await;
+            let (last_response, latency, dropped) = match attempted {
+                Ok(response) => response,
+                Err(e) => {
+                    // Exit the loop if all retry failed
+                    stopped_by = Some(e);
+                    break;
+                }
+            };
+
+            self.add_response(task, last_response.clone(), latency, dropped)
+                .await?;
+            self.compress_memory(task).await;
+
+            // Add response to all_responses
+            all_responses.push(last_response.clone());
+
+            // TODO: evaluate response
+            // TODO: Sentiment analysis
+
+            if self.is_response_complete(last_response) {
+                break;
+            }
+
+            // TODO: Loop interval, maybe add a sleep here
+        }
+
+        // TODO: Apply the cleaning function to the responses
+        // clean and add to short memory. role: Assistant(Output Cleaner)
+
+        // Save state
+        if self.config.autosave {
+            self.save_task_state(task.to_owned()).await?;
+        }
+
+        // TODO: Handle artifacts
+
+        // TODO: More flexible output types, e.g. JSON, CSV, etc.
+        Ok((all_responses.concat(), stopped_by))
+    }
+
+    /// Run the agent loop like [Agent::run], but forward every chunk of the responses to `tx`
+    ///
+    /// A loop whose attempts all failed stops the agent loop, and its error is returned after the
+    /// chunks of the earlier loops, like [`Agent::run_checked`] does.
+    async fn stream_task(
+        &self,
+        stream_chat: StreamChatFn<M>,
+        task: String,
+        tx: &UnboundedSender<Result<String, AgentError>>,
+    ) -> Result<(), AgentError> {
+        self.prepare_task(&task).await?;
+
+        let mut stopped_by = None;
+        for loop_count in 0..self.config.max_loops {
+            let attempted = self
+                .with_retries(&task, |_| {
+                    let task = &task;
+                    async move {
+                        if self.long_term_memory.is_some() && self.config.rag_every_loop {
+                            self.query_long_term_memory(task, task).await?;
+                        }
+
+                        let started = Instant::now();
+                        let history = self.chat_history(task, loop_count).await?;
+                        let dropped = history.dropped;
+                        let mut response = String::new();
+                        match self
+                            .stream_response(stream_chat, task, history, tx, &mut response)
+                            .await
+                        {
+                            Ok(()) => Ok(Ok((response, started.elapsed(), dropped))),
+                            // Nothing has been sent yet, so it is safe to retry
+                            Err(e) if response.is_empty() => Err(e),
+                            // Retrying would send the chunks again
+                            Err(e) => Ok(Err(e)),
+                        }
+                    }
+                })
+                .await;
+            let (last_response, latency, dropped) = match attempted {
+                Ok(response) => response?,
+                Err(e) => {
+                    // Exit the loop if all retry failed
+                    stopped_by = Some(e);
+                    break;
+                }
+            };
+
+            self.add_response(&task, last_response.clone(), latency, dropped)
+                .await?;
+            self.compress_memory(&task).await;
+
+            if self.is_response_complete(last_response) {
+                break;
+            }
+        }
+
+        // Save state
+        if self.config.autosave {
+            self.save_task_state(task).await?;
+        }
+
+        stopped_by.map_or(Ok(()), Err)
+    }
+
+    /// Stream a single response, the received chunks are appended to `response` and sent to `tx`
+    async fn stream_response(
+        &self,
+        stream_chat: StreamChatFn<M>,
+        task: &str,
+        history: ChatHistory,
+        tx: &UnboundedSender<Result<String, AgentError>>,
+        response: &mut String,
+    ) -> Result<(), AgentError> {
+        let _permit = self.acquire_rate_limit(task, history.tokens).await;
+        let mut stream = stream_chat(&self.agent, task.to_owned(), history.messages)
+            .await
+            .map_err(PromptError::from)?;
+
+        while let Some(choice) = stream.next().await {
+            let chunk = match choice.map_err(PromptError::from)? {
+                StreamingChoice::Message(text) => text,
+                StreamingChoice::ToolCall(name, _, params) => self
+                    .agent
+                    .tools
+                    .call(&name, params.to_string())
+                    .await
+                    .map_err(PromptError::from)?,
+            };
+            response.push_str(&chunk);
+            // The receiver may be dropped if the caller is no longer interested in the output
+            let _ = tx.unbounded_send(Ok(chunk));
+        }
+
+        Ok(())
+    }
+
     async fn plan(&self, task: String) -> Result<(), AgentError> {
         if let Some(planning_prompt) = &self.config.planning_prompt {
             let planning_prompt = format!("{planning_prompt} {task}");
+            let permit = self.acquire_rate_limit(&planning_prompt, 0).await;
             let plan = self.agent.prompt(planning_prompt).await?;
+            drop(permit);
             tracing::debug!("Plan: {}", plan);
             // Add plan to memory
-            self.short_memory.add(
-                task,
-                self.config.name.clone(),
-                Role::Assistant(self.config.name.clone()),
-                plan,
-            );
+            self.short_memory
+                .add(
+                    task,
+                    self.config.name.clone(),
+                    Role::System,
+                    format!("Plan: {plan}"),
+                )
+                .await?;
         };
         Ok(())
     }
 
-    async fn query_long_term_memory(&self, task: String) -> Result<(), AgentError> {
+    /// Query the long term memory with `query`, the documents are added to the conversation of `key`
+    async fn query_long_term_memory(&self, key: &str, query: &str) -> Result<(), AgentError> {
         if let Some(long_term_memory) = &self.long_term_memory {
-            let (_score, _id, memory_retrieval) = &long_term_memory.top_n(&task, 1).await?[0];
+            let (_score, _id, memory_retrieval) = &long_term_memory.top_n(query, 1).await?[0];
             let memory_retrieval = format!("Documents Available: {memory_retrieval}");
-            self.short_memory.add(
-                task,
-                &self.config.name,
-                Role::Assistant("[RAG] Database".to_owned()),
-                memory_retrieval,
-            );
+            self.short_memory
+                .add(
+                    key,
+                    &self.config.name,
+                    Role::Retrieval {
+                        source: "long term memory".to_owned(),
+                    },
+                    memory_retrieval,
+                )
+                .await?;
         }
 
         Ok(())
     }
 
-    /// Save the agent state to a file
-    async fn save_task_state(&self, task: String) -> Result<(), AgentError> {
+    /// The file of the state of a task or a session, if the agent has a state directory
+    fn task_state_path(&self, task: &str) -> Option<PathBuf> {
         let mut hasher = XxHash3_64::default();
         task.hash(&mut hasher);
         let task_hash = hasher.finish();
         let task_hash = format!("{:x}", task_hash & 0xFFFFFFFF); // lower 32 bits of the hash
 
-        let save_state_path = self.config.save_state_dir.clone();
-        if let Some(save_state_path) = save_state_path {
-            let save_state_path = Path::new(&save_state_path);
-            if !save_state_path.exists() {
-                tokio::fs::create_dir_all(save_state_path).await?;
-            }
-
-            let path = save_state_path
+        let save_state_path = self.config.save_state_dir.as_ref()?;
+        Some(
+            Path::new(save_state_path)
                 .join(format!("{}_{}", self.name(), task_hash))
-                .with_extension("json");
+                .with_extension("json"),
+        )
+    }
 
-            let json = serde_json::to_string_pretty(&self.short_memory.0.get(&task).unwrap())
-                    .map_err(|e| AgentError::JsonError {
-                    detail: "Failed to serialize short memory to JSON string when saving agent's task state".into(),
-                    source: e,
-                })?; // TODO: Safety?
+    /// Save the agent state to a file
+    ///
+    /// The file is the conversation as [`ConversationFormat::Json`], with the task, which the
+    /// file name only hashes.
+    async fn save_task_state(&self, task: String) -> Result<(), AgentError> {
+        if let Some(path) = self.task_state_path(&task)
+            && let Some(conversation) = self.short_memory.get(&task).await?
+        {
+            let json_error = |e| AgentError::JsonError {
+                detail:
+                    "Failed to serialize short memory to JSON string when saving agent's task state"
+                        .into(),
+                source: e,
+            };
+            let mut state: serde_json::Value =
+                serde_json::from_str(&conversation.export(ConversationFormat::Json)?)
+                    .map_err(json_error)?;
+            state["task"] = serde_json::Value::String(task);
+            let json = serde_json::to_string_pretty(&state).map_err(json_error)?;
             persistence::save_to_file(&json, path).await.map_err(|e| {
                 AgentError::PersistenceError {
                     detail: "Failed to save agent's task state to file".into(),
@@ -345,6 +877,159 @@ where
         Ok(())
     }
 
+    /// Load the state of a task or a session saved by [`RigAgent::save_task_state`]
+    async fn load_task_state(&self, task: &str) -> Result<Conversation, AgentError> {
+        let path = self
+            .task_state_path(task)
+            .ok_or(AgentError::MissingStateDir)?;
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(AgentError::MissingTaskState(task.to_owned()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match self.parse_task_state(&data)? {
+            // Another task with the same hash
+            (Some(saved_task), _) if saved_task != task => {
+                Err(AgentError::MissingTaskState(task.to_owned()))
+            }
+            (_, conversation) => Ok(conversation),
+        }
+    }
+
+    /// Parse a state file, returns the task if the file records it, and the conversation
+    ///
+    /// Files saved before the conversations were versioned are supported, but files saved by a
+    /// newer version of the crate fail with [`ConversationError::UnsupportedVersion`].
+    ///
+    /// [`ConversationError::UnsupportedVersion`]: crate::conversation::ConversationError::UnsupportedVersion
+    fn parse_task_state(&self, data: &[u8]) -> Result<(Option<String>, Conversation), AgentError> {
+        let data = String::from_utf8_lossy(data);
+        let task = serde_json::from_str::<serde_json::Value>(&data)
+            .ok()
+            .and_then(|state| state.get("task")?.as_str().map(ToOwned::to_owned));
+        let mut conversation = Conversation::new(self.name());
+        conversation.import(&data, ConversationFormat::Json)?;
+        Ok((task, conversation))
+    }
+
+    /// Restore the conversation of a task, or a session, saved in the state directory
+    ///
+    /// The conversation replaces the one in short memory, so an agent restarted e.g. after a
+    /// crash continues it.
+    pub async fn restore_task(&self, task: &str) -> Result<(), AgentError> {
+        let conversation = self.load_task_state(task).await?;
+        self.short_memory.put(task, conversation).await?;
+        Ok(())
+    }
+
+    /// Restore the conversations of all the tasks this agent saved in `dir`, returns the tasks
+    ///
+    /// The files of other agents in the directory are skipped. Files saved before they
+    /// recorded their task are restored under their first user message, which is the task when
+    /// the agent ran it. Files which can't be parsed, e.g. corrupt ones or those saved by a newer
+    /// version of the crate, are skipped with a warning.
+    pub async fn load_state(&self, dir: impl AsRef<Path>) -> Result<Vec<String>, AgentError> {
+        let prefix = format!("{}_", self.name());
+        let mut tasks = Vec::new();
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let is_state_file = path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+                && path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with(&prefix));
+            if !is_state_file {
+                continue;
+            }
+
+            let data = tokio::fs::read(&path).await?;
+            let (task, conversation) = match self.parse_task_state(&data) {
+                Ok(state) => state,
+                Err(e) => {
+                    tracing::warn!(
+                        "Agent<{}>, skipped the state file {}: {}",
+                        self.config.name,
+                        path.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            if conversation.agent_name() != self.config.name {
+                continue;
+            }
+            let Some(task) = task.or_else(|| {
+                conversation.history.iter().find_map(|message| {
+                    match (&message.role, &message.content) {
+                        (Role::User(_), Content::Text(text)) => Some(text.clone()),
+                        _ => None,
+                    }
+                })
+            }) else {
+                tracing::warn!(
+                    "Agent<{}>, skipped the state file {} without task",
+                    self.config.name,
+                    path.display()
+                );
+                continue;
+            };
+            self.short_memory.put(&task, conversation).await?;
+            tasks.push(task);
+        }
+        Ok(tasks)
+    }
+
+    /// Add a response to memory, with the model which answered, how long it took and how many
+    /// messages were dropped from its chat history
+    async fn add_response(
+        &self,
+        task: &str,
+        response: String,
+        latency: Duration,
+        dropped: usize,
+    ) -> Result<(), AgentError> {
+        let metadata = MessageMetadata {
+            id: Some(uuid::Uuid::new_v4().to_string()),
+            model: Some(self.config.model_name.clone()),
+            latency: Some(latency),
+            dropped_messages: (dropped > 0).then_some(dropped),
+            ..Default::default()
+        };
+        self.short_memory
+            .add_message(
+                task,
+                &self.config.name,
+                Message::new(
+                    Role::Assistant(self.config.name.clone()),
+                    Content::Text(response),
+                )
+                .with_metadata(metadata),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Summarize the older messages of the task's conversation, if the memory is summarizing
+    ///
+    /// A failing summarizer is not fatal, the conversation is kept as is.
+    async fn compress_memory(&self, task: &str) {
+        let Some(memory) = &self.summarizing_memory else {
+            return;
+        };
+        if let Err(e) = memory.compress(&self.short_memory, task).await {
+            tracing::warn!(
+                "Agent<{}>, task: {}, failed to summarize the conversation: {}",
+                self.config.name,
+                task,
+                e
+            );
+        }
+    }
+
     fn is_response_complete(&self, response: String) -> bool {
         self.config
             .stop_words
@@ -359,148 +1044,67 @@ where
 {
     fn run(&self, task: String) -> BoxFuture<'_, Result<String, AgentError>> {
         Box::pin(async move {
-            // Add task to memory
-            self.short_memory.add(
-                &task,
-                &self.config.name,
-                Role::User(self.config.user_name.clone()),
-                task.clone(),
-            );
-
-            // Plan
-            if self.config.plan_enabled {
-                self.plan(task.clone()).await?;
-            }
-
-            // Query long term memory
-            if self.long_term_memory.is_some() {
-                self.query_long_term_memory(task.clone()).await?;
-            }
-
-            // Save state
-            if self.config.autosave && !self.short_memory.0.is_empty() {
-                self.save_task_state(task.clone()).await?;
-            }
-
-            // Run agent loop
-            let mut last_response = String::new();
-            let mut all_responses = vec![];
-            for loop_count in 0..self.config.max_loops {
-                let mut success = false;
-                for attempt in 0..self.config.retry_attempts {
-                    if success {
-                        break;
-                    }
-
-                    if self.long_term_memory.is_some() && self.config.rag_every_loop {
-                        // FIXME: if RAG success, but then LLM fails, then RAG is not removed and maybe causes issues
-                        if let Err(e) = self.query_long_term_memory(task.clone()).await {
-                            self.handle_error_in_attempts(&task, e, attempt).await;
-                            continue;
-                        };
-                    }
-
-                    // Generate response using LLM
-                    let mut history = (&(*self
-                        .short_memory
-                        .0
-                        .entry(task.clone())
-                        .or_insert(Conversation::new(self.name()))))
-                        .into();
-
-                    // Since rig's agent requires concatenating prompt and chat_history,
-                    // this would cause the initial prompt to be duplicated.
-                    // Here we check if it's the first loop by verifying loop_count == 0
-                    // If it's the first loop, use empty chat_history
-                    if loop_count == 0 {
-                        history = vec![];
-                    }
-
-                    last_response = match self.agent.chat(task.clone(), history).await {
-                        Ok(response) => response,
-                        Err(e) => {
-                            self.handle_error_in_attempts(&task, e.into(), attempt)
-                                .await;
-                            continue;
-                        }
-                    };
-
-                    // Add response to memory
-                    self.short_memory.add(
-                        &task,
-                        &self.config.name,
-                        Role::Assistant(self.config.name.to_owned()),
-                        last_response.clone(),
-                    );
-
-                    // Add response to all_responses
-                    all_responses.push(last_response.clone());
-
-                    // TODO: evaluate response
-                    // TODO: Sentiment analysis
-
-                    success = true;
-                }
-
-                if !success {
-                    // Exit the loop if all retry failed
-                    break;
-                }
-
-                if self.is_response_complete(last_response.clone()) {
-                    break;
-                }
+            let (output, _) = self.run_loops(&task).await?;
+            Ok(output)
+        })
+    }
 
-                // TODO: Loop interval, maybe add a sleep here
+    fn run_checked(&self, task: String) -> BoxFuture<'_, Result<String, AgentError>> {
+        Box::pin(async move {
+            match self.run_loops(&task).await? {
+                (_, Some(e)) => Err(e),
+                (output, None) => Ok(output),
             }
+        })
+    }
 
-            // TODO: Apply the cleaning function to the responses
-            // clean and add to short memory. role: Assistant(Output Cleaner)
+    fn run_stream(&self, task: String) -> BoxStream<'_, Result<String, AgentError>> {
+        let Some(stream_chat) = self.stream_chat.filter(|_| self.config.stream) else {
+            return Box::pin(stream::once(self.run(task)));
+        };
 
-            // Save state
-            if self.config.autosave {
-                self.save_task_state(task.clone()).await?;
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let driver = async move {
+            if let Err(e) = self.stream_task(stream_chat, task, &tx).await {
+                let _ = tx.unbounded_send(Err(e));
             }
+        };
 
-            // TODO: Handle artifacts
-
-            // TODO: More flexible output types, e.g. JSON, CSV, etc.
-            Ok(all_responses.concat())
-        })
+        // The driver never yields items, it only needs to be polled along with the receiver
+        let driver = driver.into_stream().filter_map(|()| future::ready(None));
+        Box::pin(stream::select(rx, driver))
     }
 
     fn run_multiple_tasks(
         &mut self,
         tasks: Vec<String>,
     ) -> BoxFuture<'_, Result<Vec<String>, AgentError>> {
-        let agent_name = self.name();
-        let mut results = Vec::with_capacity(tasks.len());
+        let agent = &*self;
+        let max_concurrent_tasks = agent
+            .config
+            .max_concurrent_tasks
+            .map_or(DEFAULT_MAX_CONCURRENT_TASKS, NonZeroUsize::get);
 
         Box::pin(async move {
-            let agent_arc = Arc::new(self);
-            let (tx, mut rx) = mpsc::channel(1);
-            stream::iter(tasks)
-                .for_each_concurrent(None, |task| {
-                    let tx = tx.clone();
-                    let agent = Arc::clone(&agent_arc);
-                    async move {
-                        let result = agent.run(task.clone()).await;
-                        tx.send((task, result)).await.unwrap(); // Safety: we know rx is not dropped
+            let results = stream::iter(tasks)
+                .map(|task| async move {
+                    match agent.run(task.clone()).await {
+                        Ok(result) => Some(result),
+                        Err(e) => {
+                            tracing::error!(
+                                "| Agent: {} | Task: {} | Error: {}",
+                                agent.config.name,
+                                task,
+                                e
+                            );
+                            None
+                        }
                     }
                 })
+                .buffered(max_concurrent_tasks)
+                .filter_map(future::ready)
+                .collect()
                 .await;
-            drop(tx);
-
-            while let Some((task, result)) = rx.recv().await {
-                match result {
-                    Ok(result) => {
-                        results.push(result);
-                    }
-                    Err(e) => {
-                        tracing::error!("| Agent: {} | Task: {} | Error: {}", agent_name, task, e);
-                    }
-                }
-            }
 
             Ok(results)
         })
@@ -519,18 +1123,446 @@ where
     }
 }
 
+/// The chat history sent along with a prompt
+#[derive(Default)]
+struct ChatHistory {
+    messages: Vec<rig::message::Message>,
+    /// The number of messages dropped to fit the context window
+    dropped: usize,
+    /// The estimated tokens of the kept messages, for the rate limiter
+    tokens: u32,
+}
+
 impl From<&Conversation> for Vec<rig::message::Message> {
     fn from(conv: &Conversation) -> Self {
-        conv.history
-            .iter()
-            .map(|msg| match &msg.role {
-                Role::User(name) => {
-                    rig::message::Message::user(format!("{}: {}", name, msg.content))
+        to_rig_messages(&conv.history)
+    }
+}
+
+/// Convert the messages of a conversation to rig messages
+///
+/// Tool outputs are sent as tool results only after their tool call, providers reject the
+/// results of calls missing from the history, e.g. calls dropped to fit the context window. The
+/// other tool outputs are sent as text.
+fn to_rig_messages<'a>(
+    messages: impl IntoIterator<Item = &'a Message>,
+) -> Vec<rig::message::Message> {
+    let mut calls = HashSet::new();
+    messages
+        .into_iter()
+        .map(|message| {
+            match (&message.role, &message.content) {
+                (_, Content::ToolCall { id, .. }) => {
+                    calls.insert(id.as_str());
                 }
-                Role::Assistant(name) => {
-                    rig::message::Message::assistant(format!("{}: {}", name, msg.content))
+                (Role::Tool { call_id: id, .. }, Content::Text(_))
+                | (_, Content::ToolResult { id, .. })
+                    if !calls.contains(id.as_str()) =>
+                {
+                    return rig::message::Message::user(format!(
+                        "{}: {}",
+                        message.role, message.content
+                    ));
                 }
-            })
-            .collect()
+                _ => {}
+            }
+            to_rig_message(message)
+        })
+        .collect()
+}
+
+/// Convert a message of a conversation to a rig message
+///
+/// Text is prefixed with the name of the sender. Images, documents and tool results are always
+/// sent as user content and tool calls as assistant content, as rig requires.
+fn to_rig_message(message: &Message) -> rig::message::Message {
+    use rig::message::{
+        AssistantContent, ContentFormat, DocumentMediaType, ImageMediaType, MimeType,
+        ToolResultContent, UserContent,
+    };
+
+    let format = |source: &MediaSource| match source {
+        MediaSource::Bytes(_) => ContentFormat::Base64,
+        MediaSource::Url(_) => ContentFormat::String,
+    };
+    // Media are sent with the name of the sender, like text
+    let user_media = |name: &str, media: UserContent| {
+        let mut content = OneOrMany::one(UserContent::text(format!("{name}:")));
+        content.push(media);
+        rig::message::Message::User { content }
+    };
+
+    let name = match &message.role {
+        Role::User(name) | Role::Assistant(name) => name.as_str(),
+        Role::System => "System",
+        Role::Tool { name, .. } => name.as_str(),
+        Role::Retrieval { source } => source.as_str(),
+    };
+    match (&message.role, &message.content) {
+        (Role::User(_), Content::Text(text)) => {
+            rig::message::Message::user(format!("{name}: {text}"))
+        }
+        (Role::Assistant(_), Content::Text(text)) => {
+            rig::message::Message::assistant(format!("{name}: {text}"))
+        }
+        // rig has no system messages in the history, the preamble is the system prompt
+        (Role::System, Content::Text(text)) => {
+            rig::message::Message::user(format!("System: {text}"))
+        }
+        (Role::Tool { call_id, .. }, Content::Text(text)) => rig::message::Message::User {
+            content: OneOrMany::one(UserContent::tool_result(
+                call_id,
+                OneOrMany::one(ToolResultContent::text(text)),
+            )),
+        },
+        (Role::Retrieval { source }, Content::Text(text)) => {
+            rig::message::Message::user(format!("Retrieved from {source}: {text}"))
+        }
+        (_, Content::Image { source, mime_type }) => user_media(
+            name,
+            UserContent::image(
+                source.to_data(),
+                Some(format(source)),
+                ImageMediaType::from_mime_type(mime_type),
+                None,
+            ),
+        ),
+        (_, Content::Document { source, mime_type }) => user_media(
+            name,
+            UserContent::document(
+                source.to_data(),
+                Some(format(source)),
+                DocumentMediaType::from_mime_type(mime_type),
+            ),
+        ),
+        (
+            _,
+            Content::ToolCall {
+                id,
+                name,
+                arguments,
+            },
+        ) => rig::message::Message::Assistant {
+            content: OneOrMany::one(AssistantContent::tool_call(id, name, arguments.clone())),
+        },
+        (_, Content::ToolResult { id, result }) => rig::message::Message::User {
+            content: OneOrMany::one(UserContent::tool_result(
+                id,
+                OneOrMany::one(ToolResultContent::text(result)),
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        conversation::ConversationError,
+        llm_provider::tests::{respond, serve_response},
+    };
+
+    /// An agent of a server answering with the responses in order, returns the requests
+    async fn served_agent(
+        responses: &'static [(&'static str, &'static str)],
+    ) -> (
+        RigAgent<impl rig::completion::CompletionModel>,
+        tokio::task::JoinHandle<Vec<String>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for (status, body) in responses {
+                requests.push(serve_response(&listener, status, body).await);
+            }
+            requests
+        });
+        let agent = RigAgent::openai_builder()
+            .provider(LLMProvider::openai_compatible(url, "mock"))
+            .unwrap()
+            .agent_name("writer")
+            .retry_attempts(3)
+            .retry_policy(RetryPolicy::immediate())
+            .build()
+            .unwrap();
+        (agent, server)
+    }
+
+    const COMPLETION: &str = r#"{"id":"1","object":"chat.completion","created":0,"model":"mock",
+        "choices":[{"index":0,"message":{"role":"assistant","content":"Roses are red"},"finish_reason":"stop"}]}"#;
+
+    #[tokio::test]
+    async fn test_retries() {
+        // A server error is retried
+        let (writer, server) = served_agent(&[
+            ("503 Service Unavailable", "Service Unavailable"),
+            ("200 OK", COMPLETION),
+        ])
+        .await;
+        assert_eq!(
+            writer.run("Write a poem".to_owned()).await.unwrap(),
+            "Roses are red"
+        );
+        assert_eq!(server.await.unwrap().len(), 2);
+
+        // Rejected credentials are not
+        let (writer, server) = served_agent(&[
+            (
+                "401 Unauthorized",
+                r#"{"error":{"code":"invalid_api_key"}}"#,
+            ),
+            ("200 OK", COMPLETION),
+        ])
+        .await;
+        // `run` returns the output of the loops before the failure, none here
+        assert_eq!(writer.run("Write a poem".to_owned()).await.unwrap(), "");
+        assert!(!server.is_finished());
+        server.abort();
+
+        // `run_checked` returns the error
+        let (writer, server) = served_agent(&[(
+            "401 Unauthorized",
+            r#"{"error":{"code":"invalid_api_key"}}"#,
+        )])
+        .await;
+        let error = writer
+            .run_checked("Write a poem".to_owned())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("invalid_api_key"));
+        assert_eq!(server.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stream_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let unauthorized = r#"{"error":{"code":"invalid_api_key"}}"#;
+            serve_response(&listener, "401 Unauthorized", unauthorized).await
+        });
+        let writer = RigAgent::openai_builder()
+            .provider(LLMProvider::openai_compatible(url, "mock"))
+            .unwrap()
+            .enable_stream()
+            .build()
+            .unwrap();
+
+        // The stream ends with the error which stopped the agent
+        let chunks = writer
+            .run_stream("Write a poem".to_owned())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(chunks.len(), 1);
+        let error = chunks[0].as_ref().unwrap_err();
+        assert!(error.to_string().contains("invalid_api_key"));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_multiple_tasks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        // Every request is answered after a while, returns the most requests in flight
+        let server = tokio::spawn(async move {
+            let in_flight = Arc::new(AtomicUsize::new(0));
+            let max_in_flight = Arc::new(AtomicUsize::new(0));
+            let mut handlers = Vec::new();
+            for _ in 0..5 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let in_flight = Arc::clone(&in_flight);
+                let max_in_flight = Arc::clone(&max_in_flight);
+                handlers.push(tokio::spawn(async move {
+                    let count = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(count, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    respond(stream, "200 OK", COMPLETION).await;
+                }));
+            }
+            future::join_all(handlers).await;
+            max_in_flight.load(Ordering::SeqCst)
+        });
+        let mut writer = RigAgent::openai_builder()
+            .provider(LLMProvider::openai_compatible(url, "mock"))
+            .unwrap()
+            .max_concurrent_tasks(NonZeroUsize::new(2).unwrap())
+            .build()
+            .unwrap();
+
+        let tasks = (0..5).map(|i| format!("Write poem {i}")).collect();
+        let outputs = writer.run_multiple_tasks(tasks).await.unwrap();
+        assert_eq!(outputs, vec!["Roses are red"; 5]);
+        assert_eq!(server.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_history_tokens() {
+        let writer = |context_window: Option<usize>| {
+            let builder = RigAgent::openai_builder()
+                .provider(LLMProvider::openai_compatible("http://127.0.0.1:0", "mock"))
+                .unwrap()
+                .max_tokens(100);
+            match context_window {
+                Some(tokens) => builder.context_window(tokens),
+                None => builder,
+            }
+            .build()
+            .unwrap()
+        };
+
+        for writer in [writer(None), writer(Some(400))] {
+            for _ in 0..50 {
+                writer
+                    .short_memory
+                    .add(
+                        "task",
+                        "writer",
+                        Role::User("User".to_owned()),
+                        "a".repeat(100),
+                    )
+                    .await
+                    .unwrap();
+            }
+            let conversation = writer.short_memory.get("task").await.unwrap().unwrap();
+            let history = writer.fitted_history("task", "task").await.unwrap();
+            if writer.context_window.is_none() {
+                assert_eq!(
+                    history.tokens,
+                    rate_limit::estimate_tokens(&conversation.to_string())
+                );
+            } else {
+                // Only the messages sent count for the rate limiter
+                assert!(history.dropped > 0);
+                assert!(history.tokens > 0 && history.tokens <= 400);
+            }
+        }
+    }
+
+    fn agent(name: &str, save_state_dir: &Path) -> RigAgent<impl rig::completion::CompletionModel> {
+        RigAgent::openai_builder()
+            .provider(LLMProvider::openai_compatible("http://127.0.0.1:0", "mock"))
+            .unwrap()
+            .agent_name(name)
+            .save_state_dir(save_state_dir.to_string_lossy())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_restore_task() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let writer = agent("writer", &dir);
+        for (role, text) in [
+            (Role::User("user".to_owned()), "Write a poem"),
+            (Role::Assistant("writer".to_owned()), "Roses are red"),
+        ] {
+            writer
+                .short_memory
+                .add("Write a poem", "writer", role, text)
+                .await
+                .unwrap();
+        }
+        writer
+            .save_task_state("Write a poem".to_owned())
+            .await
+            .unwrap();
+
+        // Restarted, the agent continues the conversation
+        let restarted = agent("writer", &dir);
+        restarted.restore_task("Write a poem").await.unwrap();
+        let conversation = restarted
+            .short_memory
+            .get("Write a poem")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conversation.history.len(), 2);
+        assert_eq!(conversation.history[1].content.to_string(), "Roses are red");
+        assert!(matches!(
+            restarted.restore_task("Write a song").await,
+            Err(AgentError::MissingTaskState(_))
+        ));
+
+        let restarted = agent("writer", &dir);
+        assert_eq!(restarted.load_state(&dir).await.unwrap(), ["Write a poem"]);
+        assert!(
+            restarted
+                .short_memory
+                .get("Write a poem")
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            agent("editor", &dir)
+                .load_state(&dir)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_legacy_and_future_states() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let writer = agent("writer", &dir);
+        let path = writer.task_state_path("Write a poem").unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Saved before the state files were versioned
+        std::fs::write(
+            &path,
+            r#"{"agent_name":"writer","save_filepath":null,"history":[
+                {"role":{"User":"user"},"content":{"Text":"Time: 1700000000 \nWrite a poem"}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(writer.load_state(&dir).await.unwrap(), ["Write a poem"]);
+        let conversation = writer
+            .short_memory
+            .get("Write a poem")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conversation.history[0].content.to_string(), "Write a poem");
+
+        // Saved by a newer version
+        std::fs::write(
+            &path,
+            r#"{"version":2,"agent_name":"writer","task":"Write a poem","messages":[]}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            writer.restore_task("Write a poem").await,
+            Err(AgentError::ConversationError(
+                ConversationError::UnsupportedVersion(2)
+            ))
+        ));
+
+        // The files which can't be parsed don't stop the others from loading
+        std::fs::write(dir.join("writer_corrupt.json"), "{").unwrap();
+        writer
+            .short_memory
+            .add(
+                "Write a story",
+                "writer",
+                Role::User("user".to_owned()),
+                "Write a story",
+            )
+            .await
+            .unwrap();
+        writer
+            .save_task_state("Write a story".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(writer.load_state(&dir).await.unwrap(), ["Write a story"]);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,316 @@
+//! Multi-turn chat sessions
+//!
+#![deny(missing_docs)]
+
+use std::time::Instant;
+
+use rig::completion::Chat;
+
+use super::RigAgent;
+use crate::{
+    agent::AgentError,
+    conversation::{Content, Conversation, Message, Role},
+};
+
+/// A multi-turn chat with a [`RigAgent`], keeping one conversation across the turns
+///
+/// [`Agent::run`](crate::agent::Agent::run) keys the conversation by the task, so every task
+/// starts a new conversation. A session keys it by its id instead: each message sent to the
+/// session is a turn of the same conversation, and sessions of different users never collide.
+///
+/// The conversation is kept in the short memory of the agent, with its memory store. With a
+/// state directory, it is saved after each turn when autosave is enabled, or with
+/// [`Session::save`], and [`RigAgent::load_session`] loads it again, e.g. after a restart.
+pub struct Session<M>
+where
+    M: rig::completion::CompletionModel,
+{
+    agent: RigAgent<M>,
+    id: String,
+}
+
+impl<M> RigAgent<M>
+where
+    M: rig::completion::CompletionModel,
+{
+    /// Open a new session, with a random id
+    pub fn session(&self) -> Session<M> {
+        self.resume_session(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// Continue the session `id` with the conversation in the short memory, a new conversation
+    /// is started if there is none
+    pub fn resume_session(&self, id: impl Into<String>) -> Session<M> {
+        Session {
+            agent: self.clone(),
+            id: id.into(),
+        }
+    }
+
+    /// Continue the session `id` saved in the state directory
+    pub async fn load_session(&self, id: impl Into<String>) -> Result<Session<M>, AgentError> {
+        let session = self.resume_session(id);
+        self.restore_task(&session.id).await?;
+        Ok(session)
+    }
+
+    /// Answer a message of the session `id`, with the previous turns as chat history
+    ///
+    /// The message is added to the conversation with the answer, a failed turn leaves the
+    /// conversation as it was.
+    async fn chat_turn(&self, id: &str, message: String) -> Result<String, AgentError> {
+        if self.config.retry_attempts == 0 {
+            return Err(AgentError::NoAttempts);
+        }
+        if self.long_term_memory.is_some() {
+            self.query_long_term_memory(id, &message).await?;
+        }
+        let history = self.fitted_history(id, &message).await?;
+        let user_message = Message::new(
+            Role::User(self.config.user_name.clone()),
+            Content::Text(message.clone()),
+        );
+
+        let (response, latency) = self
+            .with_retries(id, |_| {
+                let messages = history.messages.clone();
+                let message = message.clone();
+                async move {
+                    let started = Instant::now();
+                    let _permit = self.acquire_rate_limit(&message, history.tokens).await;
+                    let response = self.agent.chat(message, messages).await?;
+                    Ok((response, started.elapsed()))
+                }
+            })
+            .await?;
+
+        self.short_memory
+            .add_message(id, &self.config.name, user_message)
+            .await?;
+        self.add_response(id, response.clone(), latency, history.dropped)
+            .await?;
+        self.compress_memory(id).await;
+        if self.config.autosave {
+            self.save_task_state(id.to_owned()).await?;
+        }
+        Ok(response)
+    }
+}
+
+impl<M> Session<M>
+where
+    M: rig::completion::CompletionModel,
+{
+    /// The id of the session, to resume or load it later
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Send a user message and wait for the answer of the agent
+    pub async fn send(&self, message: impl Into<String>) -> Result<String, AgentError> {
+        self.agent.chat_turn(&self.id, message.into()).await
+    }
+
+    /// The conversation of the session so far
+    pub async fn conversation(&self) -> Result<Conversation, AgentError> {
+        Ok(self
+            .agent
+            .short_memory
+            .get(&self.id)
+            .await?
+            .unwrap_or_else(|| Conversation::new(self.agent.config.name.clone())))
+    }
+
+    /// Save the conversation to the state directory of the agent
+    pub async fn save(&self) -> Result<(), AgentError> {
+        if self.agent.config.save_state_dir.is_none() {
+            return Err(AgentError::MissingStateDir);
+        }
+        self.agent.save_task_state(self.id.clone()).await
+    }
+
+    /// End the session, its conversation is removed from the short memory
+    ///
+    /// The saved conversation is kept, so the session can still be loaded.
+    pub async fn close(self) -> Result<(), AgentError> {
+        self.agent.short_memory.evict(&self.id).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::llm_provider::{
+        LLMProvider,
+        tests::{serve_completion, serve_response},
+    };
+
+    async fn chat_agent(
+        answers: &'static [&'static str],
+        save_state_dir: Option<String>,
+    ) -> (
+        RigAgent<impl rig::completion::CompletionModel>,
+        tokio::task::JoinHandle<Vec<String>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for answer in answers {
+                requests.push(serve_completion(&listener, answer).await);
+            }
+            requests
+        });
+
+        let mut builder = RigAgent::openai_builder()
+            .provider(LLMProvider::openai_compatible(url, "mock"))
+            .unwrap()
+            .agent_name("assistant")
+            .retry_attempts(1);
+        if let Some(dir) = save_state_dir {
+            builder = builder.save_state_dir(dir);
+        }
+        (builder.build().unwrap(), server)
+    }
+
+    #[tokio::test]
+    async fn test_session_turns() {
+        let (agent, server) = chat_agent(&["Hi Alice", "Your name is Alice", "Hello"], None).await;
+        let session = agent.session();
+        assert_eq!(session.send("I am Alice").await.unwrap(), "Hi Alice");
+        assert_eq!(
+            session.send("What is my name?").await.unwrap(),
+            "Your name is Alice"
+        );
+        // Another session doesn't see the conversation
+        let other = agent.session();
+        assert_ne!(other.id(), session.id());
+        other.send("I am Alice").await.unwrap();
+
+        let requests = server.await.unwrap();
+        assert!(!requests[0].contains("Hi Alice"));
+        assert!(requests[1].contains("I am Alice") && requests[1].contains("Hi Alice"));
+        assert!(!requests[2].contains("Hi Alice"));
+
+        let conversation = session.conversation().await.unwrap();
+        assert_eq!(conversation.history.len(), 4);
+        assert_eq!(other.conversation().await.unwrap().history.len(), 2);
+
+        session.close().await.unwrap();
+        assert!(agent.short_memory.get(&other.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_failed_turn() {
+        // No attempt is made
+        let (mut agent, server) = chat_agent(&[], None).await;
+        agent.config.retry_attempts = 0;
+        let session = agent.session();
+        assert!(matches!(
+            session.send("I am Alice").await,
+            Err(AgentError::NoAttempts)
+        ));
+        assert!(session.conversation().await.unwrap().history.is_empty());
+        assert!(server.await.unwrap().is_empty());
+
+        // The attempt fails, the message is not kept without an answer
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let unauthorized = r#"{"error":{"code":"invalid_api_key"}}"#;
+            let failed = serve_response(&listener, "401 Unauthorized", unauthorized).await;
+            let answered = serve_completion(&listener, "Hi Bob").await;
+            (failed, answered)
+        });
+        let agent = RigAgent::openai_builder()
+            .provider(LLMProvider::openai_compatible(url, "mock"))
+            .unwrap()
+            .retry_attempts(1)
+            .build()
+            .unwrap();
+        let session = agent.session();
+        assert!(session.send("I am Alice").await.is_err());
+        assert!(session.conversation().await.unwrap().history.is_empty());
+
+        assert_eq!(session.send("I am Bob").await.unwrap(), "Hi Bob");
+        let (_, answered) = server.await.unwrap();
+        assert!(!answered.contains("I am Alice"));
+        let history = session.conversation().await.unwrap().history;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].content.to_string(), "I am Bob");
+    }
+
+    #[tokio::test]
+    async fn test_dropped_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            for answer in ["Hi Alice", "Hello", "Your name is Alice"] {
+                serve_completion(&listener, answer).await;
+            }
+        });
+        // Every text is a token, the history fits the task and one more message
+        let agent = RigAgent::openai_builder()
+            .provider(LLMProvider::openai_compatible(url, "mock"))
+            .unwrap()
+            .retry_attempts(1)
+            .max_tokens(1)
+            .context_window(5)
+            .token_counter(|_: &str| 1)
+            .build()
+            .unwrap();
+        let session = agent.session();
+        for message in ["I am Alice", "Hi", "What is my name?"] {
+            session.send(message).await.unwrap();
+        }
+        server.await.unwrap();
+
+        let dropped = session
+            .conversation()
+            .await
+            .unwrap()
+            .history
+            .iter()
+            .filter(|message| matches!(message.role, Role::Assistant(_)))
+            .map(|message| message.metadata.dropped_messages)
+            .collect::<Vec<_>>();
+        // The first answer and the second message were dropped
+        assert_eq!(dropped, [None, None, Some(2)]);
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load_session() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let (agent, server) = chat_agent(
+            &["Hi Alice", "Your name is Alice"],
+            Some(dir.to_string_lossy().into_owned()),
+        )
+        .await;
+        let session = agent.session();
+        session.send("I am Alice").await.unwrap();
+        session.save().await.unwrap();
+        let id = session.id().to_owned();
+        session.close().await.unwrap();
+
+        let session = agent.load_session(&id).await.unwrap();
+        assert_eq!(session.conversation().await.unwrap().history.len(), 2);
+        session.send("What is my name?").await.unwrap();
+        let requests = server.await.unwrap();
+        assert!(requests[1].contains("Hi Alice"));
+
+        assert!(matches!(
+            agent.load_session("unknown").await,
+            Err(AgentError::MissingTaskState(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let (agent, _) = chat_agent(&[], None).await;
+        assert!(matches!(
+            agent.session().save().await,
+            Err(AgentError::MissingStateDir)
+        ));
+    }
+}
@@ -8,15 +8,17 @@ use std::{
 };
 
 use dashmap::DashMap;
+use futures::future::{self, BoxFuture};
 use rigs_macro::tool;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio::sync::Mutex;
 
 use crate::{
     self as rigs,
-    agent::{Agent, AgentError},
-    graph_workflow::{DAGWorkflow, Flow, GraphWorkflowError},
+    agent::{Agent, AgentConfig, AgentError},
+    graph_workflow::{Aggregator, DAGWorkflow, Flow, GraphWorkflowError, NodeInput, NodeOutcome},
     llm_provider::LLMProvider,
     rig_agent::RigAgent,
 };
@@ -65,6 +67,8 @@ pub struct TeamWorkflow {
     leader_agent: Option<Arc<dyn Agent>>,
     /// The underlying DAG workflow for execution
     workflow: DAGWorkflow,
+    /// The connections of the last orchestration plan, replaced when the team plans again
+    connections: Vec<(String, String)>,
 }
 
 impl TeamWorkflow {
@@ -79,6 +83,7 @@ impl TeamWorkflow {
             model_registry: Arc::new(DashMap::new()),
             leader_agent: None,
             workflow: DAGWorkflow::new(name, description),
+            connections: Vec::new(),
         }
     }
 
@@ -207,6 +212,31 @@ impl TeamWorkflow {
         &mut self,
         task: impl Into<String>,
     ) -> Result<DashMap<String, String>, TeamWorkflowError> {
+        let outcomes = self.execute_outcomes(task).await?;
+
+        // Combine the results from the output agents, if error, transform the error to "Error: <error message>" String
+        let final_result = DashMap::new();
+        for (output_agent, outcome) in outcomes {
+            let result = match outcome {
+                NodeOutcome::Succeeded(result) => result,
+                NodeOutcome::Failed(err) => format!("Agent: {output_agent}, Error: {err}"),
+                NodeOutcome::Skipped(reason) => {
+                    format!("Agent: {output_agent}, Skipped: {reason}")
+                }
+                NodeOutcome::Canceled => format!("Agent: {output_agent}, Canceled"),
+            };
+            final_result.insert(output_agent, result);
+        }
+
+        Ok(final_result)
+    }
+
+    /// Execute the workflow like [`TeamWorkflow::execute`], returning the outcomes of the output
+    /// agents which were reached
+    pub async fn execute_outcomes(
+        &mut self,
+        task: impl Into<String>,
+    ) -> Result<DashMap<String, NodeOutcome>, TeamWorkflowError> {
         let task = task.into();
 
         // Ensure we have a leader agent
@@ -244,19 +274,11 @@ impl TeamWorkflow {
             .collect::<Vec<&str>>();
         let results = self.workflow.execute_workflow(&start_agents, task).await?;
 
-        // Combine the results from the output agents, if error, transform the error to "Error: <error message>" String
-        let final_result = DashMap::new();
-        for output_agent in &orchestration_plan.output_agents {
-            if let Some(result) = results.get(output_agent) {
-                let result = match result.as_deref() {
-                    Ok(result) => result.to_owned(),
-                    Err(err) => format!("Agent: {output_agent}, Error: {err}").to_owned(),
-                };
-                final_result.insert(output_agent.to_owned(), result);
-            };
-        }
-
-        Ok(final_result)
+        Ok(orchestration_plan
+            .output_agents
+            .iter()
+            .filter_map(|output_agent| results.remove(output_agent))
+            .collect())
     }
 
     /// Parse the leader's analysis into an orchestration plan
@@ -271,61 +293,18 @@ impl TeamWorkflow {
     ) -> Result<(), TeamWorkflowError> {
         for worker in &plan.workers {
             // Get the model from the registry
-            let (provider, _) = self.get_model(&worker.model)?;
+            let (provider, description) = self.get_model(&worker.model)?;
 
             // Create the agent
-            let agent: Arc<dyn Agent> = match provider {
-                LLMProvider::Anthropic(_) => Arc::new(
-                    RigAgent::anthropic_builder()
-                        .provider(provider)?
-                        .agent_name(&worker.name)
-                        .description(&worker.description)
-                        .system_prompt(&worker.system_prompt)
-                        .temperature(worker.temperature)
-                        .max_tokens(worker.max_tokens as u64)
-                        .build()?,
-                ),
-                LLMProvider::DeepSeek(_) => Arc::new(
-                    RigAgent::deepseek_builder()
-                        .provider(provider)?
-                        .agent_name(&worker.name)
-                        .description(&worker.description)
-                        .system_prompt(&worker.system_prompt)
-                        .temperature(worker.temperature)
-                        .max_tokens(worker.max_tokens as u64)
-                        .build()?,
-                ),
-                LLMProvider::Gemini(_) => Arc::new(
-                    RigAgent::gemini_builder()
-                        .provider(provider)?
-                        .agent_name(&worker.name)
-                        .description(&worker.description)
-                        .system_prompt(&worker.system_prompt)
-                        .temperature(worker.temperature)
-                        .max_tokens(worker.max_tokens as u64)
-                        .build()?,
-                ),
-                LLMProvider::OpenAI(_) => Arc::new(
-                    RigAgent::openai_builder()
-                        .provider(provider)?
-                        .agent_name(&worker.name)
-                        .description(&worker.description)
-                        .system_prompt(&worker.system_prompt)
-                        .temperature(worker.temperature)
-                        .max_tokens(worker.max_tokens as u64)
-                        .build()?,
-                ),
-                LLMProvider::OpenRouter(_) => Arc::new(
-                    RigAgent::openrouter_builder()
-                        .provider(provider)?
-                        .agent_name(&worker.name)
-                        .description(&worker.description)
-                        .system_prompt(&worker.system_prompt)
-                        .temperature(worker.temperature)
-                        .max_tokens(worker.max_tokens as u64)
-                        .build()?,
-                ),
-            };
+            let config = AgentConfig::builder()
+                .agent_name(&worker.name)
+                .description(&worker.description)
+                .system_prompt(&worker.system_prompt)
+                .temperature(worker.temperature)
+                .max_tokens(worker.max_tokens as u64)
+                .context_window(description.context_window)
+                .build();
+            let agent = RigAgent::from_provider(provider, config)?;
 
             // Register the agent with the workflow
             self.workflow.register_agent(agent);
@@ -334,20 +313,129 @@ impl TeamWorkflow {
         Ok(())
     }
 
-    /// Create workflow connections based on the orchestration plan
+    /// Create workflow connections based on the orchestration plan, replacing the connections
+    /// of the previous plan
     fn create_workflow_connections(
         &mut self,
         plan: &OrchestrationPlan,
     ) -> Result<(), TeamWorkflowError> {
+        for (from, to) in std::mem::take(&mut self.connections) {
+            self.workflow.disconnect_agents(&from, &to)?;
+        }
         for connection in &plan.connections {
             self.workflow
                 .connect_agents(&connection.from, &connection.to, Flow::default())?;
+            self.connections
+                .push((connection.from.clone(), connection.to.clone()));
         }
 
         Ok(())
     }
 }
 
+/// Runs a [`TeamWorkflow`] as an [`Agent`], so a whole team can be registered as a single agent
+/// of a [`DAGWorkflow`]
+///
+/// The outputs of several output agents are combined with an [`Aggregator`].
+pub struct TeamWorkflowAgent {
+    id: String,
+    name: String,
+    description: String,
+    /// Executions of the team need exclusive access, concurrent tasks run one by one
+    team: Mutex<TeamWorkflow>,
+    aggregator: Aggregator,
+}
+
+impl TeamWorkflowAgent {
+    /// Create an agent named after the team
+    pub fn new(team: TeamWorkflow) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: team.name.clone(),
+            description: team.description.clone(),
+            team: Mutex::new(team),
+            aggregator: Aggregator::default(),
+        }
+    }
+
+    /// Set how the outputs of several output agents are combined
+    pub fn with_aggregator(mut self, aggregator: Aggregator) -> Self {
+        self.aggregator = aggregator;
+        self
+    }
+}
+
+impl Agent for TeamWorkflowAgent {
+    fn run(&self, task: String) -> BoxFuture<'_, Result<String, AgentError>> {
+        Box::pin(async move {
+            let outcomes = self
+                .team
+                .lock()
+                .await
+                .execute_outcomes(task)
+                .await
+                .map_err(|e| AgentError::WorkflowError(e.to_string()))?;
+
+            let mut outputs = Vec::new();
+            for (agent, outcome) in outcomes {
+                match outcome {
+                    NodeOutcome::Succeeded(output) => outputs.push(NodeInput {
+                        agent: Some(agent),
+                        input: output,
+                    }),
+                    NodeOutcome::Failed(e) => {
+                        return Err(AgentError::WorkflowError(format!(
+                            "Output agent '{agent}' failed: {e}"
+                        )));
+                    }
+                    NodeOutcome::Skipped(reason) => {
+                        return Err(AgentError::WorkflowError(format!(
+                            "Output agent '{agent}' was skipped: {reason}"
+                        )));
+                    }
+                    NodeOutcome::Canceled => {
+                        return Err(AgentError::WorkflowError(format!(
+                            "Output agent '{agent}' was canceled"
+                        )));
+                    }
+                }
+            }
+            outputs.sort_by(|a, b| a.agent.cmp(&b.agent));
+
+            match outputs.as_slice() {
+                [] => Err(AgentError::WorkflowError(format!(
+                    "Team '{}' produced no output",
+                    self.name
+                ))),
+                [output] => Ok(output.input.clone()),
+                outputs => Ok(self.aggregator.aggregate(outputs)),
+            }
+        })
+    }
+
+    fn run_multiple_tasks(
+        &mut self,
+        tasks: Vec<String>,
+    ) -> BoxFuture<'_, Result<Vec<String>, AgentError>> {
+        let agent = &*self;
+        Box::pin(future::try_join_all(
+            tasks.into_iter().map(move |task| agent.run(task)),
+        ))
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn description(&self) -> String {
+        self.description.clone()
+    }
+}
+
 /// Represents the complete orchestration plan created by the leader agent
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct OrchestrationPlan {
@@ -479,3 +567,135 @@ impl Display for ModelDescription {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        agent::tests::mock_agent,
+        llm_provider::tests::{serve_completion, serve_response},
+    };
+
+    /// A plan of two workers, `from` answers first and `to` gives the output
+    fn plan(from: &str, to: &str) -> String {
+        let worker = |name: &str| {
+            serde_json::json!({
+                "name": name,
+                "description": name,
+                "system_prompt": "You are a writer.",
+                "model": "mock",
+                "temperature": 0.5,
+                "max_tokens": 256,
+            })
+        };
+        serde_json::json!({
+            "workers": [worker(from), worker(to)],
+            "connections": [{ "from": from, "to": to }],
+            "starting_agents": [from],
+            "output_agents": [to],
+        })
+        .to_string()
+    }
+
+    /// A team whose leader returns the plans in order, its workers are answered by the server
+    async fn team(
+        plans: Vec<String>,
+        answers: &'static [&'static str],
+    ) -> (TeamWorkflow, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for answer in answers {
+                requests.push(serve_completion(&listener, answer).await);
+            }
+            requests
+        });
+        (team_at(plans, url), server)
+    }
+
+    /// A team whose leader returns the plans in order, its workers use the model at `url`
+    fn team_at(plans: Vec<String>, url: String) -> TeamWorkflow {
+        let planned = AtomicUsize::new(0);
+        let mut leader = mock_agent(plans.len(), move |_| {
+            Ok(plans[planned.fetch_add(1, Ordering::SeqCst)].clone())
+        });
+        leader.expect_name().return_const("leader".to_owned());
+
+        let mut team = TeamWorkflow::new("team", "A team of writers");
+        team.register_model(
+            "mock",
+            LLMProvider::openai_compatible(url, "mock"),
+            ModelDescription {
+                name: "mock".to_owned(),
+                description: "A mock model".to_owned(),
+                capabilities: vec![],
+                context_window: 8192,
+                max_tokens: 256,
+            },
+        );
+        team.set_leader(Arc::new(leader));
+        team
+    }
+
+    #[tokio::test]
+    async fn test_team_as_agent() {
+        let (team, server) = team(vec![plan("drafter", "editor")], &["Draft", "Edited"]).await;
+        let agent = TeamWorkflowAgent::new(team);
+        assert_eq!(agent.name(), "team");
+        assert_eq!(
+            agent.run("Write a poem".to_owned()).await.unwrap(),
+            "Edited"
+        );
+
+        let requests = server.await.unwrap();
+        assert!(requests[1].contains("Draft"));
+    }
+
+    #[tokio::test]
+    async fn test_team_as_agent_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let unauthorized = r#"{"error":{"code":"invalid_api_key"}}"#;
+            serve_response(&listener, "401 Unauthorized", unauthorized).await
+        });
+        let agent = TeamWorkflowAgent::new(team_at(vec![plan("drafter", "editor")], url));
+        // The failure is not turned into an output
+        let error = agent.run("Write a poem".to_owned()).await.unwrap_err();
+        assert!(matches!(error, AgentError::WorkflowError(_)));
+        assert!(error.to_string().contains("invalid_api_key"));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replan_connections() {
+        let (team, server) = team(
+            vec![plan("drafter", "editor"), plan("editor", "drafter")],
+            &["Draft", "Edited", "Notes", "Redrafted"],
+        )
+        .await;
+        let agent = TeamWorkflowAgent::new(team);
+        assert_eq!(
+            agent.run("Write a poem".to_owned()).await.unwrap(),
+            "Edited"
+        );
+        // The connection of the first plan is removed, or the agents would form a cycle
+        assert_eq!(
+            agent.run("Write a poem".to_owned()).await.unwrap(),
+            "Redrafted"
+        );
+
+        let requests = server.await.unwrap();
+        assert!(requests[3].contains("Notes"));
+        let team = agent.team.lock().await;
+        assert_eq!(
+            team.connections,
+            [("editor".to_owned(), "drafter".to_owned())]
+        );
+    }
+}